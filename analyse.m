@@ -27,7 +27,8 @@ hdf5write('atoms.h5', '/atoms', atoms);
 % 
 % Instead, use h5read:
 p = h5read('output.h5', '/photons');
-pos = [p.x0, p.x1, p.x2];
+% Files written by earlier versions have numbered fields, read as p.x0, p.x1, p.x2.
+pos = [p.px, p.py, p.pz];
 
 % Convert to units of um
 pos = pos * 1e6;
@@ -0,0 +1,135 @@
+//! Benchmarks of the hot paths of the simulation, to give a baseline against which performance changes can be measured.
+//!
+//! Run with `cargo bench`. To compare a change against the current code, first run
+//! `cargo bench -- --save-baseline before`, then apply the change and run `cargo bench -- --baseline before`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use atomecs::atom::{Atom, AtomicTransition, Force, Mass, Position, Velocity};
+use atomecs::ecs;
+use atomecs::initiate::NewlyCreated;
+use atomecs::integrator::Timestep;
+use atomecs::laser::gaussian::GaussianBeam;
+use atomecs::laser_cooling::force::EmissionForceOption;
+use atomecs::laser_cooling::photons_scattered::ScatteringFluctuationsOption;
+use atomecs::laser_cooling::CoolingLight;
+use imaging_diffusion::photons::list::{NullPhotonOutputter, PhotonEmission, PhotonOutputter, PhotonSink, RegisterPhotonsSystem};
+use imaging_diffusion::photons::PhotonHistogram;
+use nalgebra::Vector3;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Normal};
+use specs::prelude::*;
+
+const SEED: u64 = 0x5eed;
+/// Number of positions counted into the histogram per iteration.
+const N_POSITIONS: usize = 100_000;
+/// Number of atoms in the cloud for which photons are generated.
+const N_ATOMS: usize = 10_000;
+
+/// Positions drawn from a gaussian cloud of 50 um rms radius, spread over the cells of a 1 mm histogram.
+fn cloud_positions(n: usize) -> Vec<Vector3<f64>> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let normal = Normal::new(0.0, 50.0e-6).unwrap();
+    (0..n).map(|_| Vector3::from_fn(|_, _| normal.sample(&mut rng))).collect()
+}
+
+fn histogram_count(c: &mut Criterion) {
+    let positions = cloud_positions(N_POSITIONS);
+    let histogram = PhotonHistogram::new_cubic(1.0e-3, 100);
+    let mut group = c.benchmark_group("PhotonHistogram::count");
+    group.throughput(Throughput::Elements(N_POSITIONS as u64));
+    group.bench_function("dense", |b| b.iter(|| {
+        for &position in positions.iter() {
+            histogram.count(position);
+        }
+    }));
+    group.finish();
+}
+
+/// Creates a world with a cloud of atoms in a single imaging beam, and runs one frame of the atomecs simulation so
+/// that the number of photons scattered by each atom is known.
+fn scattering_cloud() -> World {
+    let mut world = World::new();
+    ecs::register_components(&mut world);
+    ecs::register_resources(&mut world);
+    let mut dispatcher = ecs::create_simulation_dispatcher_builder().build();
+    dispatcher.setup(&mut world);
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    // About one photon is scattered by each atom per frame, as in the simulation.
+    world.insert(Timestep { delta: 0.1e-6 });
+
+    world
+        .create_entity()
+        .with(GaussianBeam {
+            intersection: Vector3::zeros(),
+            e_radius: 0.01,
+            power: 0.01,
+            direction: Vector3::x(),
+            rayleigh_range: f64::INFINITY,
+            ellipticity: 0.0,
+        })
+        .with(CoolingLight::for_species(AtomicTransition::rubidium(), 0.0, 1))
+        .build();
+    for pos in cloud_positions(N_ATOMS) {
+        world
+            .create_entity()
+            .with(Position { pos })
+            .with(Atom)
+            .with(Force::new())
+            .with(Velocity { vel: Vector3::zeros() })
+            .with(NewlyCreated)
+            .with(AtomicTransition::rubidium())
+            .with(Mass { value: 87.0 })
+            .build();
+    }
+    dispatcher.dispatch(&mut world);
+    world.maintain();
+    world
+}
+
+fn register_photons(c: &mut Criterion) {
+    let mut world = scattering_cloud();
+    world.insert(NullPhotonOutputter::default());
+    let mut system = RegisterPhotonsSystem::<NullPhotonOutputter>::default();
+    System::setup(&mut system, &mut world);
+    let mut group = c.benchmark_group("RegisterPhotonsSystem");
+    group.throughput(Throughput::Elements(N_ATOMS as u64));
+    group.bench_function("10k atoms", |b| b.iter(|| system.run_now(&world)));
+    // A fresh system has no photon lists to reuse, so the difference from the steady state above is the cost of
+    // allocating the lists of the workers and the buffer each frame.
+    group.bench_function("10k atoms, first frame", |b| b.iter_batched(
+        RegisterPhotonsSystem::<NullPhotonOutputter>::default,
+        |mut system| system.run_now(&world),
+        BatchSize::SmallInput,
+    ));
+    group.finish();
+}
+
+fn append_photons(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("imaging_diffusion_bench_{}.h5", std::process::id()));
+    let outputter = PhotonOutputter::new_extended(path.to_str().unwrap().to_string()).expect("Could not create output file.");
+    let mut group = c.benchmark_group("PhotonOutputter::append_photons");
+    // Small batches are typical of a frame of a small cloud, where the per-call overhead dominates.
+    for &size in [10usize, 10_000].iter() {
+        let photons: Vec<PhotonEmission> = cloud_positions(size).into_iter().enumerate().map(|(i, position)| PhotonEmission {
+            position,
+            direction: Vector3::z(),
+            frequency: Some(3.8e14),
+            frame: i as u32,
+            source: 0,
+            atom: Some(i as u64),
+        }).collect();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_function(format!("{} photons", size), |b| b.iter(
+            || outputter.append_photons(&photons).expect("Could not write photons.")
+        ));
+    }
+    group.finish();
+    drop(outputter);
+    std::fs::remove_file(&path).ok();
+}
+
+criterion_group!(benches, histogram_count, register_photons, append_photons);
+criterion_main!(benches);
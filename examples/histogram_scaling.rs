@@ -0,0 +1,43 @@
+//! Measures how the time to count photons into a [PhotonHistogram] scales with the number of threads.
+//!
+//! The photons are drawn from a tightly peaked gaussian cloud, so that most counts land in a few central cells
+//! and the threads contend for the same counters. Run with `cargo run --release --example histogram_scaling`.
+
+use std::time::Instant;
+
+use imaging_diffusion::photons::PhotonHistogram;
+use nalgebra::Vector3;
+use rand_distr::{Distribution, Normal};
+use rayon::prelude::*;
+
+const N_PHOTONS: usize = 10_000_000;
+const REPEATS: usize = 5;
+
+fn main() {
+    // A cloud with 1 um rms radius, imaged onto 10 um cells, puts almost all photons in the central cells.
+    let normal = Normal::new(0.0, 1.0e-6).unwrap();
+    let mut rng = rand::thread_rng();
+    let positions: Vec<Vector3<f64>> = (0..N_PHOTONS)
+        .map(|_| Vector3::new(normal.sample(&mut rng), normal.sample(&mut rng), normal.sample(&mut rng)))
+        .collect();
+
+    let max_threads = rayon::current_num_threads();
+    let mut threads = 1;
+    let mut single_thread_time = None;
+    while threads <= max_threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let mut best = f64::INFINITY;
+        for _ in 0..REPEATS {
+            let histogram = PhotonHistogram::new_cubic(1.0e-3, 100);
+            let start = Instant::now();
+            pool.install(|| positions.par_iter().for_each(|&position| histogram.count(position)));
+            best = best.min(start.elapsed().as_secs_f64());
+        }
+        let reference = *single_thread_time.get_or_insert(best);
+        println!(
+            "{} threads: {:.1} ms, {:.1} Mcounts/s, speedup {:.2}",
+            threads, best * 1e3, N_PHOTONS as f64 / best / 1e6, reference / best
+        );
+        threads *= 2;
+    }
+}
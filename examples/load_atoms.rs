@@ -0,0 +1,78 @@
+//! Measures the time to load a large cloud of input atoms, which dominates the startup of the simulation.
+//!
+//! The atoms are written to a temporary h5 file, read back with [read_atoms_from_h5], and created in a world both one
+//! entity at a time, with `world.create_entity()`, and in bulk, with [create_atoms]. Run with
+//! `cargo run --release --example load_atoms`, optionally giving the number of atoms, which defaults to 10 million.
+
+use std::time::Instant;
+
+use atomecs::atom::{Atom, Force, Mass, Position, Velocity};
+use atomecs::ecs;
+use atomecs::initiate::NewlyCreated;
+use imaging_diffusion::photons::list::AtomIndex;
+use imaging_diffusion::simulation::{create_atoms, read_atoms_from_h5, InputAtom};
+use imaging_diffusion::species::Transition;
+use ndarray::Array1;
+use specs::prelude::*;
+
+const DEFAULT_ATOMS: usize = 10_000_000;
+
+fn new_world() -> World {
+    let mut world = World::new();
+    ecs::register_components(&mut world);
+    ecs::register_resources(&mut world);
+    world
+}
+
+/// Creates the atoms one entity at a time, as `create_atoms` did before entities were created in bulk.
+fn create_atoms_individually(world: &mut World, atoms: &[InputAtom], transition: Transition) {
+    world.register::<AtomIndex>();
+    for (index, atom) in atoms.iter().enumerate() {
+        world
+            .create_entity()
+            .with(Position { pos: atom.position })
+            .with(Atom)
+            .with(Force::new())
+            .with(Velocity { vel: atom.velocity })
+            .with(NewlyCreated)
+            .with(atom.species.atomic_transition(transition))
+            .with(Mass { value: atom.species.mass() })
+            .with(AtomIndex(index as u64))
+            .build();
+    }
+}
+
+fn main() -> Result<(), hdf5::Error> {
+    let n_atoms = std::env::args().nth(1).map_or(DEFAULT_ATOMS, |n| n.parse().expect("The number of atoms must be an integer."));
+    let transition = Transition::default();
+
+    let path = std::env::temp_dir().join(format!("imaging_diffusion_load_atoms_{}.h5", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    {
+        let file = hdf5::File::create(&path)?;
+        let group = file.create_group("atoms")?;
+        for (axis, name) in ["x", "y", "z", "vx", "vy", "vz"].iter().enumerate() {
+            let column = Array1::from_shape_fn(n_atoms, |i| 1.0e-9 * (i % 1000) as f64 + axis as f64);
+            group.new_dataset_builder().with_data(&column).create(*name)?;
+        }
+    }
+
+    let start = Instant::now();
+    let atoms = read_atoms_from_h5(&path, None, transition)?;
+    println!("Read {} atoms in {:.3} s.", atoms.len(), start.elapsed().as_secs_f64());
+    std::fs::remove_file(&path).ok();
+
+    let mut world = new_world();
+    let start = Instant::now();
+    create_atoms_individually(&mut world, &atoms, transition);
+    let individual = start.elapsed().as_secs_f64();
+    println!("Created {} atoms one at a time in {:.3} s.", atoms.len(), individual);
+    drop(world);
+
+    let mut world = new_world();
+    let start = Instant::now();
+    create_atoms(&mut world, &atoms, transition);
+    let bulk = start.elapsed().as_secs_f64();
+    println!("Created {} atoms in bulk in {:.3} s, a speedup of {:.2}.", atoms.len(), bulk, individual / bulk);
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Generation of synthetic atom clouds, as an alternative to loading atoms from an input file.
+
+use atomecs::atom::{Atom, Force, Mass, Position, Velocity};
+use atomecs::constant::{AMU, BOLTZCONST};
+use atomecs::initiate::NewlyCreated;
+use nalgebra::Vector3;
+use rand_distr::{Distribution, Normal};
+use specs::prelude::*;
+
+use crate::species::Transition;
+
+/// Creates `n` atoms in a thermal cloud, imaged on the given `transition`.
+///
+/// # Arguments
+///
+/// * `n`: number of atoms to create.
+///
+/// * `temperature`: temperature of the cloud, in units of K. Each velocity component is sampled from the
+/// Maxwell-Boltzmann distribution, a gaussian of width `sqrt(k_B T / m)`.
+///
+/// * `sigma_pos`: rms width of the gaussian cloud along each axis, in units of m.
+///
+/// * `mass`: mass of each atom, in atomic mass units.
+pub fn generate_thermal_cloud(
+    world: &mut World,
+    n: usize,
+    temperature: f64,
+    sigma_pos: f64,
+    mass: f64,
+    transition: Transition,
+) {
+    let mut rng = rand::thread_rng();
+    let position_distribution = Normal::new(0.0, sigma_pos).expect("Invalid cloud width.");
+    let velocity_distribution = Normal::new(0.0, (BOLTZCONST * temperature / (mass * AMU)).sqrt())
+        .expect("Invalid cloud temperature.");
+
+    for _ in 0..n {
+        let pos = Vector3::from_fn(|_, _| position_distribution.sample(&mut rng));
+        let vel = Vector3::from_fn(|_, _| velocity_distribution.sample(&mut rng));
+        world
+            .create_entity()
+            .with(Position { pos })
+            .with(Atom)
+            .with(Force::new())
+            .with(Velocity { vel })
+            .with(NewlyCreated)
+            .with(transition.atomic_transition())
+            .with(Mass { value: mass })
+            .build();
+    }
+    println!("Generated a thermal cloud of {:?} atoms.", n);
+}
+
+/// Multiplies the (vx, vy, vz) velocity of every atom by the factor for each axis.
+///
+/// This gives an anisotropic momentum distribution, whose temperature along each axis is scaled by the square of the
+/// factor, without editing the input file.
+pub fn scale_velocities(world: &mut World, scale: Vector3<f64>) {
+    let atoms = world.read_storage::<Atom>();
+    let mut velocities = world.write_storage::<Velocity>();
+    for (_, velocity) in (&atoms, &mut velocities).join() {
+        velocity.vel.component_mul_assign(&scale);
+    }
+}
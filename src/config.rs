@@ -0,0 +1,332 @@
+//! Configuration of the simulation parameters, loaded from a toml file.
+
+use std::error::Error;
+use std::fs;
+
+use atomecs::laser_cooling::force::{EmissionForceConfiguration, EmissionForceOption};
+use atomecs::laser_cooling::photons_scattered::ScatteringFluctuationsOption;
+use hdf5::types::VarLenUnicode;
+use nalgebra::Vector3;
+use ndarray::{arr1, Array2};
+use serde::Deserialize;
+
+use crate::photons::list::{PhotonPrecision, RegionOfInterest};
+use crate::photons::reabsorption::Reabsorption;
+use crate::pulse::PowerProfile;
+use crate::species::Transition;
+
+/// Parameters of the simulation.
+///
+/// Any parameters missing from the config file take their default values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SimulationConfig {
+    /// The imaging beams. Each beam is created as a separate entity.
+    pub beams: Vec<BeamConfig>,
+    /// The transition driven by the imaging beams. All atoms must be of a species which has this transition.
+    pub transition: Transition,
+    /// Uniform magnetic field, in units of G. The field Zeeman-shifts the σ⁺, σ⁻ and π transitions of the atoms.
+    pub magnetic_field: Vector3<f64>,
+    /// Duration of each timestep, in units of s.
+    pub timestep: f64,
+    /// Duration of the imaging exposure, in units of s.
+    pub exposure: f64,
+    /// Number of timesteps at the start of the exposure during which photons are not recorded, so that the cloud
+    /// can reach a steady state. The atoms still scatter photons and recoil during the warmup.
+    pub warmup_steps: u32,
+    /// Expected number of photons scattered by an atom in a frame, above which a warning is printed that the timestep
+    /// or beam power is too large.
+    pub max_scattered_per_frame: f64,
+    /// Whether atoms receive a recoil kick from the photons they emit.
+    pub emission_force: Switch,
+    /// Number of photons above which the emission force is calculated using a random walk approximation,
+    /// rather than explicitly sampling each photon. A lower threshold is faster for bright clouds, but the recoil of
+    /// atoms above it is a gaussian kick rather than a sum of discrete photon recoils.
+    pub emission_force_threshold: u64,
+    /// Whether the number of photons scattered each frame fluctuates about the mean, or is deterministic.
+    pub scattering_fluctuations: Switch,
+    /// Number of frames between progress reports. Set to 0 to disable progress reporting.
+    pub progress_interval: u32,
+    /// If true, the photon momentum emitted by each atom is accumulated and summarised at the end of the run.
+    pub recoil_diagnostic: bool,
+    /// If true, the number of photons scattered by each atom is accumulated, and its distribution summarised at the end of the run.
+    pub photon_statistics: bool,
+    /// If true, the mean and second moment of the emitted photon directions are accumulated and summarised at the end of the run.
+    pub emission_statistics: bool,
+    /// If true, the mean scattering rate and speed of the atoms are recorded every frame, to check the Doppler shift
+    /// of heated atoms off resonance.
+    pub scattering_rate_diagnostic: bool,
+    /// If true, the fraction of atoms which scatter more than `emission_force_threshold` photons is recorded every
+    /// frame, to show how often the emission force uses the random walk approximation.
+    pub explicit_threshold_diagnostic: bool,
+    /// Detunings to scan, in units of MHz. If not empty, a separate run is performed for each detuning,
+    /// with all beams set to that detuning, and the output file names are suffixed with the detuning.
+    pub detuning_scan: Vec<f64>,
+    /// Number of threads used to run the simulation, or 0 to use one thread per core.
+    ///
+    /// The members of an ensemble share a single pool of this many threads, so this caps the threads of the whole run.
+    pub threads: usize,
+    /// Number of independent members of the ensemble. If greater than 1, the members are run in parallel from the
+    /// same initial atoms, and the output file names are suffixed with the member index.
+    pub ensemble: u32,
+    /// If false, photons and initial atoms are not written to the output file.
+    ///
+    /// The emission and recoil of photons is still simulated, so this is useful when only the atomic motion is of
+    /// interest, or for benchmarking the simulation core.
+    pub write_photons: bool,
+    /// If false, the initial positions and velocities of the atoms are not written to the output file.
+    pub record_initial_atoms: bool,
+    /// If true, each photon is tagged with the index of the atom which emitted it, which is the row of the atom in
+    /// the input files. The photons are then written with the extended layout, which includes the atom index.
+    pub record_photon_atoms: bool,
+    /// If true, the photons are written with the extended layout, whose `source` column is the index of the beam in
+    /// [SimulationConfig::beams] which drove the scattering of each photon.
+    pub record_photon_beams: bool,
+    /// Precision of the photon positions and directions in the output file. `Single` halves the size of the file.
+    pub photon_precision: PhotonPrecision,
+    /// Indices of tracer atoms, whose position and velocity are written every frame to a 'trajectory_{index}' dataset.
+    /// The index of an atom is its row in the input files. Only a few atoms should be traced, to keep the output small.
+    pub tracer_atoms: Vec<u64>,
+    /// If set, each atom samples its photons from its own random number generator, seeded from this seed and the
+    /// index of the atom, so that the photons of each atom are reproducible regardless of the number of threads.
+    /// Each member of an ensemble adds its member index to the seed.
+    pub seed: Option<u64>,
+    /// If set, emitted photons may be reabsorbed by other atoms of the cloud, see [crate::photons::reabsorption].
+    pub reabsorption: Option<ReabsorptionConfig>,
+    /// If set, only photons emitted within this box are written to the output file.
+    pub region_of_interest: Option<RegionOfInterest>,
+    /// Probability (0-1) that an emitted photon is detected and written to the output file.
+    pub detection_efficiency: f64,
+    /// The thermal cloud generated when the program is run with `--generate`, instead of loading atoms from a file.
+    pub thermal_cloud: ThermalCloudConfig,
+    /// Factors by which the (vx, vy, vz) velocity of each initial atom is multiplied, whether loaded or generated.
+    /// The temperature along each axis is scaled by the square of its factor.
+    pub velocity_scale: Vector3<f64>,
+}
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            beams: vec![BeamConfig::default()],
+            transition: Transition::default(),
+            magnetic_field: Vector3::zeros(),
+            // a small timestep of 0.1 us keeps it so only ~0-1 photons are emitted each frame.
+            timestep: 0.1e-6,
+            exposure: 100.0e-6,
+            warmup_steps: 0,
+            max_scattered_per_frame: 2.0,
+            emission_force: Switch::On,
+            emission_force_threshold: 10,
+            scattering_fluctuations: Switch::On,
+            progress_interval: 100,
+            recoil_diagnostic: false,
+            photon_statistics: false,
+            emission_statistics: false,
+            scattering_rate_diagnostic: false,
+            explicit_threshold_diagnostic: false,
+            detuning_scan: Vec::new(),
+            ensemble: 1,
+            threads: 0,
+            write_photons: true,
+            record_initial_atoms: true,
+            record_photon_atoms: false,
+            record_photon_beams: false,
+            photon_precision: PhotonPrecision::Double,
+            tracer_atoms: Vec::new(),
+            seed: None,
+            reabsorption: None,
+            region_of_interest: None,
+            detection_efficiency: 1.0,
+            thermal_cloud: ThermalCloudConfig::default(),
+            velocity_scale: Vector3::repeat(1.0),
+        }
+    }
+}
+impl SimulationConfig {
+    /// Number of timesteps required to cover the exposure.
+    pub fn n_steps(&self) -> u32 {
+        (self.exposure / self.timestep).ceil() as u32
+    }
+
+    /// Writes the parameters of the run as attributes of the root group of an h5 file, so that the output is self-describing.
+    ///
+    /// Scalar parameters are stored as single-element attributes. The beam parameters are stored with one entry per beam,
+    /// e.g. `beam_power` has shape `[n_beams]` and `beam_direction` has shape `[n_beams, 3]`.
+    /// The `seed` attribute is only written if a seed is set. For a member of an ensemble, it is the seed of that member.
+    /// Likewise, the `region_of_interest_min` and `_max` and the `reabsorption_` attributes are only written if the
+    /// region or the reabsorption is set. A parameter added to the config which changes the output must be added here.
+    pub fn write_h5_attributes(&self, file: &hdf5::File) -> Result<(), hdf5::Error> {
+        let to_h5_error = |e: ndarray::ShapeError| hdf5::Error::from(e.to_string());
+        let string = |s: String| s.parse::<VarLenUnicode>().map_err(|e| hdf5::Error::from(e.to_string()));
+        let per_beam = |f: fn(&BeamConfig) -> f64| arr1(&self.beams.iter().map(f).collect::<Vec<f64>>());
+        let vectors = |f: fn(&BeamConfig) -> Vector3<f64>| {
+            Array2::from_shape_vec(
+                (self.beams.len(), 3),
+                self.beams.iter().flat_map(|b| f(b).iter().copied().collect::<Vec<f64>>()).collect(),
+            ).map_err(to_h5_error)
+        };
+
+        file.new_attr_builder().with_data(&per_beam(|b| b.power)).create("beam_power")?;
+        file.new_attr_builder().with_data(&per_beam(|b| b.e_radius)).create("beam_e_radius")?;
+        file.new_attr_builder().with_data(&per_beam(|b| b.detuning)).create("beam_detuning")?;
+        file.new_attr_builder().with_data(&vectors(|b| b.direction)?).create("beam_direction")?;
+        file.new_attr_builder().with_data(&vectors(|b| b.intersection)?).create("beam_intersection")?;
+        file.new_attr_builder().with_data(&arr1(&[self.timestep])).create("timestep")?;
+        file.new_attr_builder().with_data(&arr1(&[self.exposure])).create("exposure")?;
+        file.new_attr_builder().with_data(&arr1(&[self.warmup_steps])).create("warmup_steps")?;
+        file.new_attr_builder().with_data(&arr1(&[self.emission_force_threshold])).create("emission_force_threshold")?;
+        file.new_attr_builder().with_data(&arr1(self.magnetic_field.as_slice())).create("magnetic_field")?;
+        file.new_attr_builder().with_data(&arr1(self.velocity_scale.as_slice())).create("velocity_scale")?;
+        file.new_attr_builder().with_data(&arr1(&[string(format!("{:?}", self.transition))?])).create("transition")?;
+        file.new_attr_builder().with_data(&arr1(&[string(format!("{:?}", self.emission_force))?])).create("emission_force")?;
+        file.new_attr_builder().with_data(&arr1(&[string(format!("{:?}", self.scattering_fluctuations))?])).create("scattering_fluctuations")?;
+        file.new_attr_builder().with_data(&arr1(&[string(format!("{:?}", self.photon_precision))?])).create("photon_precision")?;
+        file.new_attr_builder().with_data(&arr1(&[self.detection_efficiency])).create("detection_efficiency")?;
+        if let Some(region) = &self.region_of_interest {
+            file.new_attr_builder().with_data(&arr1(region.min.as_slice())).create("region_of_interest_min")?;
+            file.new_attr_builder().with_data(&arr1(region.max.as_slice())).create("region_of_interest_max")?;
+        }
+        if let Some(reabsorption) = &self.reabsorption {
+            file.new_attr_builder().with_data(&arr1(&[reabsorption.cell_size])).create("reabsorption_cell_size")?;
+            file.new_attr_builder().with_data(&arr1(&[reabsorption.cross_section(self.transition)])).create("reabsorption_cross_section")?;
+            file.new_attr_builder().with_data(&arr1(&[reabsorption.max_scatterings])).create("reabsorption_max_scatterings")?;
+        }
+        if let Some(seed) = self.seed {
+            file.new_attr_builder().with_data(&arr1(&[seed])).create("seed")?;
+        }
+        Ok(())
+    }
+
+    /// The atomecs [EmissionForceOption] for this config.
+    pub fn emission_force_option(&self) -> EmissionForceOption {
+        match self.emission_force {
+            Switch::On => EmissionForceOption::On(EmissionForceConfiguration {
+                explicit_threshold: self.emission_force_threshold,
+            }),
+            Switch::Off => EmissionForceOption::Off,
+        }
+    }
+
+    /// The atomecs [ScatteringFluctuationsOption] for this config.
+    pub fn scattering_fluctuations_option(&self) -> ScatteringFluctuationsOption {
+        match self.scattering_fluctuations {
+            Switch::On => ScatteringFluctuationsOption::On,
+            Switch::Off => ScatteringFluctuationsOption::Off,
+        }
+    }
+}
+
+/// Enables or disables a physical effect of the simulation.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+/// Parameters of a gaussian imaging beam.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BeamConfig {
+    /// A point the beam passes through, in units of m.
+    pub intersection: Vector3<f64>,
+    /// Radius of the beam at which the intensity falls to 1/e of its peak, in units of m.
+    pub e_radius: f64,
+    /// Peak power of the beam, in units of W.
+    pub power: f64,
+    /// Temporal profile of the beam power.
+    pub profile: PowerProfile,
+    /// Propagation direction of the beam.
+    pub direction: Vector3<f64>,
+    /// Detuning of the beam from the atomic transition, in units of MHz.
+    pub detuning: f64,
+    /// Circular polarization of the beam, relative to its propagation direction.
+    pub polarization: Polarization,
+}
+impl Default for BeamConfig {
+    fn default() -> Self {
+        BeamConfig {
+            intersection: Vector3::new(0.0, 0.0, 0.0),
+            e_radius: 0.01,
+            power: 0.01,
+            profile: PowerProfile::Constant,
+            direction: Vector3::x(),
+            detuning: 0.0,
+            polarization: Polarization::SigmaPlus,
+        }
+    }
+}
+
+/// Circular polarization of a beam, relative to its propagation direction.
+///
+/// atomecs resolves the polarization into σ⁺, σ⁻ and π components about the local magnetic field,
+/// so π light is driven by a circularly polarized beam propagating perpendicular to the field.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum Polarization {
+    SigmaPlus,
+    SigmaMinus,
+}
+impl Polarization {
+    /// The polarization index used by atomecs [atomecs::laser_cooling::CoolingLight].
+    pub fn index(&self) -> i32 {
+        match self {
+            Polarization::SigmaPlus => 1,
+            Polarization::SigmaMinus => -1,
+        }
+    }
+}
+
+/// Parameters of the approximate model of reabsorption, see [crate::photons::reabsorption::Reabsorption].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReabsorptionConfig {
+    /// Size of the cells of the density grid, in units of m.
+    pub cell_size: f64,
+    /// Absorption cross section, in units of m². Defaults to the resonant cross section of the imaging transition.
+    pub cross_section: Option<f64>,
+    /// Number of times a photon may be reabsorbed before it is assumed to escape.
+    pub max_scatterings: u32,
+}
+impl Default for ReabsorptionConfig {
+    fn default() -> Self {
+        ReabsorptionConfig {
+            cell_size: 10.0e-6,
+            cross_section: None,
+            max_scatterings: 10,
+        }
+    }
+}
+impl ReabsorptionConfig {
+    /// The absorption cross section for atoms imaged on `transition`, in units of m².
+    pub fn cross_section(&self, transition: Transition) -> f64 {
+        self.cross_section.unwrap_or_else(|| Reabsorption::resonant_cross_section(&transition.atomic_transition()))
+    }
+}
+
+/// Parameters of a synthetic thermal cloud, see [crate::cloud::generate_thermal_cloud].
+///
+/// The atoms are the [crate::species::Transition::default_species] of the imaging transition.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThermalCloudConfig {
+    /// Number of atoms in the cloud.
+    pub atoms: usize,
+    /// Temperature of the cloud, in units of K.
+    pub temperature: f64,
+    /// Rms width of the cloud along each axis, in units of m.
+    pub sigma_position: f64,
+}
+impl Default for ThermalCloudConfig {
+    fn default() -> Self {
+        ThermalCloudConfig {
+            atoms: 1000,
+            temperature: 10.0e-6,
+            sigma_position: 100.0e-6,
+        }
+    }
+}
+
+/// Load a [SimulationConfig] from the toml file at the given path.
+pub fn load_config(path: &str) -> Result<SimulationConfig, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let config = toml::from_str(&contents)?;
+    Ok(config)
+}
@@ -1 +1,6 @@
-pub mod photons;
\ No newline at end of file
+pub mod cloud;
+pub mod config;
+pub mod photons;
+pub mod pulse;
+pub mod simulation;
+pub mod species;
\ No newline at end of file
@@ -1,31 +1,21 @@
 //! # Use `AtomECS` to simulate diffusion during imaging
 //! 
 //! This program uses [atomecs](https://github.com/TeamAtomECS/AtomECS) to simulate the diffusion of atoms
-//! as they scatter photons during imaging.
+//! as they scatter photons during imaging. It is a command line wrapper around [run_simulation].
 
 extern crate atomecs;
 extern crate specs;
 
-use std::time::Instant;
-
-use hdf5::{File, SliceOrIndex, Error, H5Type};
-use imaging_diffusion::photons::list::{RegisterPhotonsSystem, PhotonOutputter, RegisterInitialAtomsSystem};
-use lib::laser_cooling::force::{EmissionForceOption, EmissionForceConfiguration};
+use imaging_diffusion::config::{load_config, SimulationConfig};
+use imaging_diffusion::photons::list::{PhotonOutputter, PhotonOutputterOptions, NullPhotonOutputter};
+use imaging_diffusion::simulation::{read_atoms_from_h5_files, run_simulation, AtomInput, InputAtom, RunOptions};
+use ndarray::arr1;
 use serde::Deserialize;
-use specs::prelude::*;
+use rayon::ThreadPool;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-extern crate atomecs as lib;
 extern crate nalgebra;
-use lib::atom::{Atom, AtomicTransition, Force, Mass, Position, Velocity};
-use lib::ecs;
-use lib::initiate::NewlyCreated;
-use lib::integrator::Timestep;
-use lib::laser::gaussian::GaussianBeam;
-use lib::laser_cooling::photons_scattered::{ScatteringFluctuationsOption};
-use lib::laser_cooling::CoolingLight;
-use lib::output::file;
-use lib::output::file::Text;
-use nalgebra::Vector3;
 
 #[derive(Debug, Deserialize)]
 pub struct AtomRecord {
@@ -34,123 +24,209 @@ pub struct AtomRecord {
     pub z: f64
 }
 
+/// Set when the user interrupts the program, so that running simulations stop early and keep their output.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Command line arguments of the program.
+struct Arguments {
+    /// Path to a toml file containing the [SimulationConfig].
+    config: Option<String>,
+    /// The input h5 files of initial atom positions and velocities. The atoms of all files are simulated together.
+    inputs: Vec<AtomInput>,
+    /// If true, atoms are generated from the thermal cloud of the config, rather than loaded from the input file.
+    generate: bool,
+    /// Path to the output h5 file of photons.
+    output: String,
+    /// Path to the text file of atom positions.
+    pos_output: String,
+    /// If true, progress is not reported during the run.
+    quiet: bool,
+    /// Number of threads, which overrides the `threads` of the config if set.
+    threads: Option<usize>,
+}
+impl Arguments {
+    fn parse() -> Self {
+        let mut arguments = Arguments {
+            config: None,
+            inputs: Vec::new(),
+            generate: false,
+            output: "output.h5".to_string(),
+            pos_output: "pos.txt".to_string(),
+            quiet: false,
+            threads: None,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| panic!("Expected a value after '{}'.", arg));
+            match arg.as_str() {
+                "--config" => arguments.config = Some(value()),
+                "--input" => {
+                    let input = value();
+                    arguments.inputs.push(AtomInput::parse(&input).unwrap_or_else(|e| panic!("Invalid input '{}': {}", input, e)));
+                }
+                "--generate" => arguments.generate = true,
+                "--output" => arguments.output = value(),
+                "--pos-output" => arguments.pos_output = value(),
+                "--quiet" => arguments.quiet = true,
+                "--threads" => {
+                    let threads = value();
+                    arguments.threads = Some(threads.parse().unwrap_or_else(|_| panic!("Invalid number of threads '{}'.", threads)));
+                }
+                _ => panic!("Unrecognised argument '{}'.", arg),
+            }
+        }
+        if arguments.inputs.is_empty() {
+            arguments.inputs.push(AtomInput { path: "atoms.h5".to_string(), species: None });
+        }
+        arguments
+    }
+}
+
 fn main() {
-    
-    let now = Instant::now();
-
-    // Create the simulation world
-    let mut world = World::new();
-    ecs::register_components(&mut world);
-    ecs::register_resources(&mut world);
-    
-    // Create our dispatcher - which will run the different systems that comprise the simulation.
-    let mut builder =
-        ecs::create_simulation_dispatcher_builder();
-
-    // Add our extra systems, which do things like generate output.
-    //
-    // Our photon output system must run after the total scattered each frame has been calculated.
-    builder.add(
-        file::new::<Position, Text>("pos.txt".to_string(), 10),
-        "",
-        &[],
-    );
-
-    // Output atoms to an h5 file
-    builder.add(RegisterPhotonsSystem, "", &[]);
-    builder.add(RegisterInitialAtomsSystem, "", &[]);
-    world.insert(PhotonOutputter::new("output.h5".to_string()));
-
-    // // Having defined the dispatcher, we now build it and set up required resources in the world.
-    let mut dispatcher = builder.build();
-    dispatcher.setup(&mut world);
-
-    // Create atoms from an input h5 file.
-    // The input file format has a dataset called 'atoms' which has (x,y,z,vx,vy,vz) in SI units
-    load_atoms_from_h5(&mut world).expect("Unable to load initial atom position and velocity from 'atoms.h5' input file.");
-
-    // Create the imaging laser. We set it aligned to the origin, propagating along +x, and with zero detuning.
-    world
-        .create_entity()
-        .with(GaussianBeam {
-            intersection: Vector3::new(0.0, 0.0, 0.0),
-            e_radius: 0.01,
-            power: 0.01,
-            direction: Vector3::x(),
-            rayleigh_range: f64::INFINITY,
-            ellipticity: 0.0,
-        })
-        .with(CoolingLight::for_species(
-            AtomicTransition::rubidium(),
-            0.0,
-            1,
+
+    let arguments = Arguments::parse();
+
+    // The first Ctrl-C finishes the runs early, writing the output of the frames simulated so far.
+    // A second Ctrl-C exits immediately.
+    ctrlc::set_handler(|| {
+        if STOP_REQUESTED.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        println!("Interrupted, finishing early. Press Ctrl-C again to exit immediately.");
+    }).expect("Could not install the Ctrl-C handler.");
+    let mut config = match &arguments.config {
+        Some(path) => load_config(path).expect("Unable to load the config file."),
+        None => SimulationConfig::default(),
+    };
+    if let Some(threads) = arguments.threads {
+        config.threads = threads;
+    }
+    // A single pool runs the members of an ensemble, the dispatcher of every member, and the systems run outside of
+    // the dispatchers, so that the process uses at most `threads` threads in total.
+    let pool = if config.threads > 0 {
+        Some(Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(config.threads)
+                .build()
+                .expect("Could not configure the number of threads."),
         ))
-        .build();
-
-    // Enable scattering fluctuations and emission forces
-    world.insert(EmissionForceOption::On(EmissionForceConfiguration {
-        explicit_threshold: 10,
-    }));
-    world.insert(ScatteringFluctuationsOption::On);
-
-    // Define timestep - we use a small timestep of 0.1 us here to keep it so only ~0-1 photons are emitted each frame.
-    let dt = 0.1e-6;
-    world.insert(Timestep { delta: dt });
-
-    println!("Initialisation took {} ms.", now.elapsed().as_millis());
-
-    // Run the simulation for a number of steps to generate the output.
-    let exposure_us = 100.0;
-    let n_steps = (exposure_us * 1.0e-6 / dt).ceil() as u32;
-    for _i in 0..n_steps {
-        dispatcher.dispatch(&mut world);
-        world.maintain();
+    } else {
+        None
+    };
+    match &pool {
+        Some(pool) => pool.install(|| run_all(&arguments, &config, Some(pool))),
+        None => run_all(&arguments, &config, None),
+    }
+}
+
+/// Runs the ensemble of the config, or of each point of its detuning scan, with the threads of the `pool` if given.
+fn run_all(arguments: &Arguments, config: &SimulationConfig, pool: Option<&Arc<ThreadPool>>) {
+    // Create atoms from the input h5 files.
+    // The input file format has a dataset called 'atoms' which has (x,y,z,vx,vy,vz) in SI units,
+    // and an optional dataset called 'species' which has the mass number of each atom.
+    // The atoms are read once, and created afresh in the world of each run.
+    let input_atoms = if arguments.generate {
+        None
+    } else {
+        Some(
+            read_atoms_from_h5_files(&arguments.inputs, config.transition)
+                .unwrap_or_else(|e| panic!("Unable to load initial atom positions and velocities: {}", e))
+        )
+    };
+    let input = input_atoms.as_deref();
+
+    if config.detuning_scan.is_empty() {
+        run_ensemble(config, input, &arguments.output, &arguments.pos_output, arguments.quiet, pool);
+        return;
     }
 
-    println!("Simulation completed in {} ms.", now.elapsed().as_millis());
+    // Perform a fresh run for each detuning in the scan, with all beams set to that detuning.
+    for &detuning in config.detuning_scan.iter() {
+        if STOP_REQUESTED.load(Ordering::Relaxed) {
+            break;
+        }
+        println!("Running scan point with detuning {} MHz.", detuning);
+        let mut point = config.clone();
+        for beam in point.beams.iter_mut() {
+            beam.detuning = detuning;
+        }
+        let suffix = format!("detuning_{}", detuning);
+        run_ensemble(
+            &point,
+            input,
+            &with_suffix(&arguments.output, &suffix),
+            &with_suffix(&arguments.pos_output, &suffix),
+            arguments.quiet,
+            pool,
+        );
+    }
 }
 
-const READ_BATCH_SIZE: usize = 1000;
-
-#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
-#[repr(C)]
-pub struct InputAtomPositionRecord {
-    x: f64,
-    y: f64,
-    z: f64,
-    vx: f64,
-    vy: f64,
-    vz: f64
+/// Inserts `_suffix` into a file name, before the extension.
+fn with_suffix(file_name: &str, suffix: &str) -> String {
+    match file_name.rfind('.') {
+        Some(i) => format!("{}_{}{}", &file_name[..i], suffix, &file_name[i..]),
+        None => format!("{}_{}", file_name, suffix),
+    }
 }
 
-fn load_atoms_from_h5(world: &mut World) -> Result<(), Error> {
-    let file = File::open("atoms.h5")?;
-    let ds = file.dataset("atoms")?;
-    let mut n_created = 0;
-    
-    for i in (0..ds.size()).step_by(READ_BATCH_SIZE) {
-        let n_to_read = READ_BATCH_SIZE.min(ds.size()-i);
-        let atoms = ds.read_slice_1d::<InputAtomPositionRecord, SliceOrIndex>(SliceOrIndex::SliceCount{ start: i, step: 1, block: 1, count: n_to_read })?;
-        n_created += atoms.len();
-
-        for atom in atoms {
-            world
-                .create_entity()
-                .with(Position {
-                    pos: Vector3::new(atom.x, atom.y, atom.z),
-                })
-                .with(Atom)
-                .with(Force::new())
-                .with(Velocity {
-                    vel: Vector3::new(atom.vx, atom.vy, atom.vz),
-                })
-                .with(NewlyCreated)
-                .with(AtomicTransition::rubidium())
-                .with(Mass { value: 87.0 })
-                .build();
-        }        
+/// Runs each member of the ensemble, in parallel, writing outputs with a `member_<i>` suffix.
+///
+/// Each member builds its own [World], dispatcher and resources on its own rayon task, so members share no
+/// mutable state; only the input atoms are shared, immutably. The dispatchers of all members run on the same `pool`,
+/// so the members share its threads rather than each using `threads` threads. The random number generators are per-thread,
+/// so members sample independent photons and recoils. If a seed is configured, each member adds its index to the
+/// seed, so that the members remain independent, and the output of each member records its index and seed. If the
+/// ensemble has a single member, the simulation is run once and the outputs are not suffixed.
+fn run_ensemble(config: &SimulationConfig, input: Option<&[InputAtom]>, output: &str, pos_output: &str, quiet: bool, pool: Option<&Arc<ThreadPool>>) {
+    use rayon::prelude::*;
+
+    if config.ensemble <= 1 {
+        run(config, input, output, pos_output, quiet, None, pool);
+        return;
     }
+    (0..config.ensemble).into_par_iter().for_each(|member| {
+        let suffix = format!("member_{}", member);
+        let config = SimulationConfig { seed: config.seed.map(|seed| seed.wrapping_add(member as u64)), ..config.clone() };
+        run(&config, input, &with_suffix(output, &suffix), &with_suffix(pos_output, &suffix), quiet, Some(member), pool);
+    });
+}
 
-    println!("Loaded {:?} atoms from the input h5 file.", n_created);
-    Ok(())
-}
\ No newline at end of file
+/// Runs the simulation for the given config, creating the `input` atoms and writing photons to `output`.
+///
+/// If `input` is `None`, the atoms are generated from the thermal cloud of the config instead.
+///
+/// If `write_photons` is disabled in the config, the output file is not created and photons are discarded.
+/// For a `member` of an ensemble, its index is written as the `ensemble_member` attribute of the output.
+fn run(config: &SimulationConfig, input: Option<&[InputAtom]>, output: &str, pos_output: &str, quiet: bool, member: Option<usize>, pool: Option<&Arc<ThreadPool>>) {
+    let options = RunOptions {
+        atoms: input,
+        pos_output: Some(pos_output.to_string()),
+        quiet,
+        stop: Some(&STOP_REQUESTED),
+        pool: pool.cloned(),
+    };
+    let result = if config.write_photons {
+        // The atom and beam indices of each photon are only stored by the extended layout, which also has the
+        // frequency and frame of each photon.
+        let extended = config.record_photon_atoms || config.record_photon_beams;
+        let outputter = PhotonOutputter::builder(output)
+            .frequency(extended)
+            .frame(extended)
+            .source(extended)
+            .atom(config.record_photon_atoms)
+            .options(PhotonOutputterOptions { precision: config.photon_precision, ..Default::default() })
+            .build()
+            .unwrap_or_else(|e| panic!("Could not create '{}' output file: {}", output, e));
+        config.write_h5_attributes(&outputter.file)
+            .and_then(|_| match member {
+                Some(member) => outputter.file.new_attr_builder().with_data(&arr1(&[member as u64])).create("ensemble_member").map(|_| ()),
+                None => Ok(()),
+            })
+            .unwrap_or_else(|e| panic!("Could not write run parameters to '{}': {}", output, e));
+        run_simulation(config, &options, outputter).map(|_| ())
+    } else {
+        run_simulation(config, &options, NullPhotonOutputter::default()).map(|_| ())
+    };
+    result.unwrap_or_else(|e| panic!("{}", e));
+}
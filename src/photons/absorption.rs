@@ -0,0 +1,210 @@
+//! An absorption image, formed by the shadow that scattering atoms cast in a probe beam.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use atomecs::atom::Position;
+use atomecs::constant::{C, HBAR};
+use atomecs::integrator::Timestep;
+use atomecs::laser::gaussian::GaussianBeam;
+use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
+use atomecs::laser_cooling::CoolingLight;
+use nalgebra::Vector3;
+use ndarray::{arr1, Array2};
+use specs::prelude::*;
+
+use super::camera::pixel_index;
+use super::histogram::add_f64;
+
+/// A 2D map of the photons removed from a probe beam, viewed along the beam direction.
+///
+/// Each photon scattered by an atom is removed from the probe, so the scattered photons are deposited at the
+/// projection of the atom position onto the image plane. The photons incident on each pixel are accumulated
+/// from the probe beams, so that the optical density `-ln(1 - removed / incident)` can be calculated.
+///
+/// The image plane is defined by a `center` and two orthogonal unit vectors, `horizontal` and `vertical`,
+/// whose cross product gives the direction of the probe beam.
+pub struct AbsorptionImage {
+    pub center: Vector3<f64>,
+    pub horizontal: Vector3<f64>,
+    pub vertical: Vector3<f64>,
+    /// Size of a pixel in units of m.
+    pub pixel_size: f64,
+    /// Number of pixels along (horizontal, vertical).
+    pixel_number: [usize; 2],
+    /// Photons removed from the probe in each pixel, stored as f64 bits.
+    removed: Vec<AtomicU64>,
+    /// Photons incident on each pixel, stored as f64 bits.
+    incident: Vec<AtomicU64>,
+}
+impl AbsorptionImage {
+    /// Create a new [AbsorptionImage].
+    ///
+    /// # Arguments
+    ///
+    /// * `center`: position of the image center, in units of m.
+    ///
+    /// * `horizontal`: direction along which the column index increases.
+    ///
+    /// * `vertical`: direction along which the row index increases. Must be orthogonal to `horizontal`.
+    ///
+    /// * `pixel_size`: size of each pixel, in units of m.
+    ///
+    /// * `pixel_number`: number of pixels along the horizontal and vertical directions.
+    pub fn new(
+        center: Vector3<f64>,
+        horizontal: Vector3<f64>,
+        vertical: Vector3<f64>,
+        pixel_size: f64,
+        pixel_number: [usize; 2],
+    ) -> Self {
+        assert!(
+            horizontal.dot(&vertical).abs() < 1e-9 * horizontal.norm() * vertical.norm(),
+            "Image basis vectors must be orthogonal."
+        );
+        let length = pixel_number[0] * pixel_number[1];
+        AbsorptionImage {
+            center,
+            horizontal: horizontal.normalize(),
+            vertical: vertical.normalize(),
+            pixel_size,
+            pixel_number,
+            removed: (0..length).map(|_| AtomicU64::new(0.0f64.to_bits())).collect(),
+            incident: (0..length).map(|_| AtomicU64::new(0.0f64.to_bits())).collect(),
+        }
+    }
+
+    /// Direction of the probe beam, `horizontal` x `vertical`.
+    pub fn normal(&self) -> Vector3<f64> {
+        self.horizontal.cross(&self.vertical)
+    }
+
+    /// Deposits a number of photons removed from the probe at the projection of `position`.
+    pub fn deposit(&self, position: Vector3<f64>, photons: f64) {
+        if let Some(index) = self.get_index(position) {
+            add_f64(&self.removed[index], photons);
+        }
+    }
+
+    /// Position of the center of each pixel, in row-major order.
+    pub fn pixel_centers(&self) -> Vec<Vector3<f64>> {
+        let mut centers = Vec::with_capacity(self.removed.len());
+        for row in 0..self.pixel_number[1] {
+            for column in 0..self.pixel_number[0] {
+                let h = (column as f64 - (self.pixel_number[0] / 2) as f64 + 0.5) * self.pixel_size;
+                let v = (row as f64 - (self.pixel_number[1] / 2) as f64 + 0.5) * self.pixel_size;
+                centers.push(self.center + h * self.horizontal + v * self.vertical);
+            }
+        }
+        centers
+    }
+
+    /// Get the pixel index for a given position.
+    ///
+    /// Pixels are stored in row-major order.
+    fn get_index(&self, position: Vector3<f64>) -> Option<usize> {
+        if !position.iter().all(|x| x.is_finite()) {
+            return None;
+        }
+        let delta = position - self.center;
+        let projected = [delta.dot(&self.horizontal), delta.dot(&self.vertical)];
+        pixel_index(projected, self.pixel_size, self.pixel_number)
+    }
+
+    /// Photons removed from the probe in each pixel, in row-major order.
+    pub fn removed_photons(&self) -> Vec<f64> {
+        self.removed.iter().map(|v| f64::from_bits(v.load(Ordering::Relaxed))).collect()
+    }
+
+    /// Photons incident on each pixel, in row-major order.
+    pub fn incident_photons(&self) -> Vec<f64> {
+        self.incident.iter().map(|v| f64::from_bits(v.load(Ordering::Relaxed))).collect()
+    }
+
+    /// Optical density `-ln(1 - removed / incident)` of each pixel, in row-major order.
+    ///
+    /// Pixels which received no probe light have zero optical density. Pixels where all of the incident
+    /// photons were removed have infinite optical density.
+    pub fn optical_density(&self) -> Vec<f64> {
+        self.removed_photons()
+            .iter()
+            .zip(self.incident_photons())
+            .map(|(&removed, incident)| {
+                if incident > 0.0 {
+                    -(1.0 - (removed / incident).min(1.0)).ln()
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the image to an h5 file.
+    ///
+    /// The datasets 'optical_density', 'removed_photons' and 'incident_photons' are indexed as `[row][column]`.
+    /// The 'optical_density' dataset has attributes:
+    ///
+    /// * `pixel_size`: size of a pixel, in units of m.
+    ///
+    /// * `pixel_number`: number of pixels along (horizontal, vertical).
+    ///
+    /// * `center`: position of the image center, in units of m.
+    pub fn write_to_h5(&self, file_name: String) -> Result<(), hdf5::Error> {
+        let shape = (self.pixel_number[1], self.pixel_number[0]);
+        let to_h5_error = |e: ndarray::ShapeError| hdf5::Error::from(e.to_string());
+
+        let file = hdf5::File::create(file_name)?;
+        let od = Array2::from_shape_vec(shape, self.optical_density()).map_err(to_h5_error)?;
+        let dataset = file.new_dataset_builder().with_data(&od).create("optical_density")?;
+        let removed = Array2::from_shape_vec(shape, self.removed_photons()).map_err(to_h5_error)?;
+        file.new_dataset_builder().with_data(&removed).create("removed_photons")?;
+        let incident = Array2::from_shape_vec(shape, self.incident_photons()).map_err(to_h5_error)?;
+        file.new_dataset_builder().with_data(&incident).create("incident_photons")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.pixel_size])).create("pixel_size")?;
+        dataset.new_attr_builder().with_data(&arr1(&self.pixel_number.map(|n| n as u64))).create("pixel_number")?;
+        dataset.new_attr_builder().with_data(&arr1(self.center.as_slice())).create("center")?;
+        Ok(())
+    }
+}
+
+/// Accumulates the [AbsorptionImage] each frame.
+///
+/// The photons scattered by each atom are deposited as removed photons, and the photons delivered to each pixel
+/// by every gaussian beam propagating along the image normal are deposited as incident photons.
+/// Photons scattered from all beams are counted, so other beams should be off, or weak, while imaging.
+pub struct AbsorptionImageSystem;
+impl<'a> System<'a> for AbsorptionImageSystem {
+    type SystemData = (
+        ReadExpect<'a, AbsorptionImage>,
+        ReadStorage<'a, ActualPhotonsScatteredVector>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, GaussianBeam>,
+        ReadStorage<'a, CoolingLight>,
+        ReadExpect<'a, Timestep>,
+    );
+    fn run(&mut self, (image, totals, positions, beams, lights, timestep): Self::SystemData) {
+        use rayon::prelude::*;
+
+        (&totals, &positions).par_join().for_each(|(total, position)| {
+            let scattered: f64 = total.contents.iter().map(|a| a.scattered).sum();
+            image.deposit(position.pos, scattered);
+        });
+
+        let normal = image.normal();
+        let pixel_area = image.pixel_size * image.pixel_size;
+        let centers = image.pixel_centers();
+        for (beam, light) in (&beams, &lights).join() {
+            let direction = beam.direction.normalize();
+            if direction.dot(&normal) < 1.0 - 1e-6 {
+                continue;
+            }
+            let photon_energy = 2.0 * std::f64::consts::PI * HBAR * C / light.wavelength;
+            let peak = beam.power / (std::f64::consts::PI * beam.e_radius * beam.e_radius);
+            for (cell, center) in image.incident.iter().zip(centers.iter()) {
+                let offset = center - beam.intersection;
+                let r_squared = (offset - offset.dot(&direction) * direction).norm_squared();
+                let intensity = peak * (-r_squared / (beam.e_radius * beam.e_radius)).exp();
+                add_f64(cell, intensity * pixel_area * timestep.delta / photon_energy);
+            }
+        }
+    }
+}
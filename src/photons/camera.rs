@@ -0,0 +1,690 @@
+//! A two-dimensional histogram of photons projected onto the image plane of a camera.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use atomecs::atom::Position;
+use atomecs::integrator::Timestep;
+use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
+use nalgebra::Vector3;
+use ndarray::{arr1, Array2};
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, Normal};
+use specs::prelude::*;
+
+use super::emission::EmissionPattern;
+use super::histogram::add_f64;
+use super::list::AtomRng;
+use super::{BackgroundRate, DetectionEfficiency, FrameNumber, PhotonCountingMode, WarmupSteps};
+
+/// The numerical aperture of the imaging lens.
+///
+/// When this resource is present, the [CameraHistogramSystem] only counts photons emitted into the collection cone
+/// of the lens, and each photon is ray-traced along its emission direction onto the image plane.
+pub struct NumericalAperture(pub f64);
+impl NumericalAperture {
+    /// Cosine of the half-angle of the collection cone.
+    pub fn cos_half_angle(&self) -> f64 {
+        (1.0 - self.0 * self.0).max(0.0).sqrt()
+    }
+}
+
+/// Geometry of the imaging lens.
+///
+/// If not present, the optical axis is taken to be the normal of the camera image plane.
+pub struct LensGeometry {
+    /// Direction of the optical axis, pointing from the atoms toward the lens.
+    pub optical_axis: Vector3<f64>,
+}
+
+/// The point spread function of the imaging system, a gaussian of rms width `sigma` in the sensor plane.
+///
+/// When this resource is present, the [CameraHistogramSystem] displaces each photon by an offset drawn from the
+/// gaussian before counting it, so the blur is applied stochastically per photon rather than by convolving the
+/// final image. The width is scaled back to the object plane by the [CameraHistogram::magnification].
+///
+/// Atoms away from the focal plane are additionally blurred by defocus. The defocus blur grows linearly with the
+/// distance `dz` of the emitting atom from the focal plane, along the camera normal, and is added in quadrature:
+/// `σ_object(dz)² = (sigma / |M|)² + (defocus · dz)²`.
+#[derive(Clone, Copy, Debug)]
+pub struct PointSpreadFunction {
+    /// Rms width of the point spread function in the sensor plane, in units of m.
+    pub sigma: f64,
+    /// Position of the focal plane along the camera normal, relative to the [CameraHistogram::center], in units of m.
+    pub focal_plane: f64,
+    /// Rms width of the defocus blur in the object plane, per unit distance from the focal plane.
+    ///
+    /// For a geometric blur disk of radius NA·|dz|, the rms width along each axis is NA·|dz|/2, so this is ~NA/2.
+    /// Zero disables defocus.
+    pub defocus: f64,
+}
+impl PointSpreadFunction {
+    /// Create an in-focus [PointSpreadFunction], with rms width `sigma` in the sensor plane and no defocus.
+    pub fn new(sigma: f64) -> Self {
+        PointSpreadFunction { sigma, focal_plane: 0.0, defocus: 0.0 }
+    }
+
+    /// Add a defocus blur of `defocus` per unit distance from a focal plane at `focal_plane`, see [PointSpreadFunction::defocus].
+    pub fn with_defocus(self, focal_plane: f64, defocus: f64) -> Self {
+        PointSpreadFunction { focal_plane, defocus, ..self }
+    }
+
+    /// Rms width of the blur in the object plane of `camera`, for a photon emitted at `position`, in units of m.
+    pub fn object_sigma(&self, camera: &CameraHistogram, position: &Vector3<f64>) -> f64 {
+        let in_focus = self.sigma / camera.magnification.abs();
+        let dz = (position - camera.center).dot(&camera.normal()) - self.focal_plane;
+        let defocus = self.defocus * dz;
+        (in_focus * in_focus + defocus * defocus).sqrt()
+    }
+
+    /// Samples a displacement within the image plane of `camera` for a photon emitted at `position`, in object plane units of m.
+    pub fn sample_offset<R: Rng + ?Sized>(&self, camera: &CameraHistogram, position: &Vector3<f64>, rng: &mut R) -> Vector3<f64> {
+        let normal = Normal::new(0.0, self.object_sigma(camera, position))
+            .expect("Invalid point spread function width.");
+        normal.sample(rng) * camera.horizontal + normal.sample(rng) * camera.vertical
+    }
+}
+
+/// The fall-off of collection efficiency toward the edge of the field of view.
+///
+/// When this resource is present, the [CameraHistogramSystem] scales the weight of each photon by the efficiency at
+/// its distance `r` from the [CameraHistogram::center] in the image plane, in object plane units.
+#[derive(Clone, Debug)]
+pub enum Vignetting {
+    /// The cos⁴θ law of natural vignetting, where θ is the field angle, `tan θ = r / distance`.
+    Cos4 {
+        /// Distance from the object plane to the entrance pupil of the lens, in units of m.
+        distance: f64,
+    },
+    /// Efficiency interpolated linearly from a table of `(r, efficiency)` points, sorted by increasing `r` in units of m.
+    ///
+    /// The efficiency is constant beyond the first and last points.
+    Table(Vec<(f64, f64)>),
+}
+impl Vignetting {
+    /// Relative collection efficiency (0-1) at a distance `r` from the center of the field of view, in units of m.
+    pub fn efficiency(&self, r: f64) -> f64 {
+        match self {
+            Vignetting::Cos4 { distance } => {
+                let cos_squared = distance * distance / (distance * distance + r * r);
+                cos_squared * cos_squared
+            }
+            Vignetting::Table(points) => {
+                let upper = points.iter().position(|&(radius, _)| radius > r);
+                match upper {
+                    None => points.last().map_or(1.0, |&(_, e)| e),
+                    Some(0) => points[0].1,
+                    Some(i) => {
+                        let (r0, e0) = points[i - 1];
+                        let (r1, e1) = points[i];
+                        e0 + (e1 - e0) * (r - r0) / (r1 - r0)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The full-well capacity of the pixels of a [CameraHistogram], above which they saturate.
+///
+/// Saturation is applied to the accumulated weights when the image is read, see [CameraHistogram::saturated_weights].
+/// The photons of a pixel accumulate linearly until it is full, so this is the same as clipping each photon as it
+/// arrives.
+#[derive(Clone, Copy, Debug)]
+pub struct Saturation {
+    /// Maximum weight a pixel can hold, in photons.
+    pub full_well: f64,
+    /// Whether the overflow of a full pixel spills into its neighbours, see [Blooming].
+    pub blooming: Blooming,
+}
+impl Saturation {
+    /// Pixels clip at the `full_well` capacity, in photons, and the overflow is lost.
+    pub fn new(full_well: f64) -> Self {
+        assert!(full_well > 0.0, "The full-well capacity must be positive.");
+        Saturation { full_well, blooming: Blooming::Off }
+    }
+
+    /// Set how the overflow of a full pixel spreads into its neighbours.
+    pub fn with_blooming(self, blooming: Blooming) -> Self {
+        Saturation { blooming, ..self }
+    }
+
+    /// Applies the saturation to the weights of an image with `columns` pixels per row, in row-major order.
+    pub fn apply(&self, weights: &mut [f64], columns: usize) {
+        match self.blooming {
+            Blooming::Off => weights.iter_mut().for_each(|w| *w = w.min(self.full_well)),
+            Blooming::Column => {
+                if columns == 0 {
+                    return;
+                }
+                let rows = weights.len() / columns;
+                for column in 0..columns {
+                    let mut pixels: Vec<f64> = (0..rows).map(|row| weights[row * columns + column]).collect();
+                    self.bloom(&mut pixels);
+                    for (row, &w) in pixels.iter().enumerate() {
+                        weights[row * columns + column] = w;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Spills the overflow of each full pixel of a column equally up and down, into the nearest pixels which are
+    /// not yet full. Overflow which reaches the end of the column is lost.
+    fn bloom(&self, column: &mut [f64]) {
+        for i in 0..column.len() {
+            if column[i] <= self.full_well {
+                continue;
+            }
+            let overflow = column[i] - self.full_well;
+            column[i] = self.full_well;
+            self.spill(column, (0..i).rev(), overflow / 2.0);
+            self.spill(column, i + 1..column.len(), overflow / 2.0);
+        }
+    }
+
+    fn spill<I: Iterator<Item = usize>>(&self, column: &mut [f64], pixels: I, mut overflow: f64) {
+        for j in pixels {
+            if overflow <= 0.0 {
+                return;
+            }
+            let taken = (self.full_well - column[j]).max(0.0).min(overflow);
+            column[j] += taken;
+            overflow -= taken;
+        }
+    }
+}
+
+/// How the overflow of a saturated pixel spreads, see [Saturation].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Blooming {
+    /// The overflow is lost, so saturated pixels simply clip at the full-well capacity.
+    Off,
+    /// The overflow spills along the column of the pixel, i.e. the vertical readout direction of a CCD, filling the
+    /// nearest pixels above and below. This gives the vertical streaks seen from bright regions of real images.
+    Column,
+}
+
+/// A 2D histogram of photon emission positions, projected onto the image plane of a camera.
+///
+/// Each pixel accumulates a (possibly fractional) weight, which is the number of photons counted unless the
+/// [CollectionModel::SolidAngleWeight] model is used.
+///
+/// The image plane is defined by a `center` and two orthogonal unit vectors, `horizontal` and `vertical`,
+/// which give the directions of increasing column and row index respectively.
+///
+/// All lengths are in the object (atom) plane. For a real sensor, see [CameraHistogram::for_sensor], which maps
+/// the pixel pitch of the sensor back to the object plane through the magnification of the imaging system.
+pub struct CameraHistogram {
+    pub center: Vector3<f64>,
+    pub horizontal: Vector3<f64>,
+    pub vertical: Vector3<f64>,
+    /// Size of a pixel in the object plane, in units of m.
+    pub pixel_size: f64,
+    /// Magnification from the object plane to the sensor. 1 unless created with [CameraHistogram::for_sensor].
+    pub magnification: f64,
+    /// Number of pixels along (horizontal, vertical).
+    pixel_number: [usize; 2],
+    /// f64 weights, stored as bits in an [AtomicU64] so they can be updated atomically.
+    cells: Vec<AtomicU64>,
+    /// Number of background counts added to the pixels, see [BackgroundRate].
+    background: AtomicU64,
+    /// The full-well capacity of the pixels, if they saturate.
+    saturation: Option<Saturation>,
+}
+impl CameraHistogram {
+    /// Create a new [CameraHistogram].
+    ///
+    /// # Arguments
+    ///
+    /// * `center`: position of the image center, in units of m.
+    ///
+    /// * `horizontal`: direction along which the column index increases.
+    ///
+    /// * `vertical`: direction along which the row index increases. Must be orthogonal to `horizontal`.
+    ///
+    /// * `pixel_size`: size of each pixel, in units of m.
+    ///
+    /// * `pixel_number`: number of pixels along the horizontal and vertical directions.
+    pub fn new(
+        center: Vector3<f64>,
+        horizontal: Vector3<f64>,
+        vertical: Vector3<f64>,
+        pixel_size: f64,
+        pixel_number: [usize; 2],
+    ) -> Self {
+        assert!(
+            horizontal.dot(&vertical).abs() < 1e-9 * horizontal.norm() * vertical.norm(),
+            "Camera basis vectors must be orthogonal."
+        );
+        let cells = (0..(pixel_number[0] * pixel_number[1]))
+            .map(|_| AtomicU64::new(0.0f64.to_bits()))
+            .collect();
+        CameraHistogram {
+            center,
+            horizontal: horizontal.normalize(),
+            vertical: vertical.normalize(),
+            pixel_size,
+            magnification: 1.0,
+            pixel_number,
+            cells,
+            background: AtomicU64::new(0),
+            saturation: None,
+        }
+    }
+
+    /// Set the full-well capacity of the pixels, above which they saturate, see [Saturation].
+    pub fn with_saturation(mut self, saturation: Saturation) -> Self {
+        self.saturation = Some(saturation);
+        self
+    }
+
+    /// The full-well capacity of the pixels, if they saturate.
+    pub fn saturation(&self) -> Option<Saturation> {
+        self.saturation
+    }
+
+    /// Create a [CameraHistogram] which matches the pixels of a real sensor.
+    ///
+    /// # Arguments
+    ///
+    /// * `center`: position in the object plane which is imaged onto the center of the sensor, in units of m.
+    ///
+    /// * `horizontal`: direction in the object plane along which the column index increases, for positive magnification.
+    ///
+    /// * `vertical`: direction in the object plane along which the row index increases, for positive magnification.
+    ///
+    /// * `pixel_pitch`: distance between the centers of adjacent sensor pixels, in units of m.
+    ///
+    /// * `sensor`: number of pixels of the sensor along the horizontal and vertical directions.
+    ///
+    /// * `magnification`: magnification of the imaging system. A negative magnification gives an inverted image.
+    pub fn for_sensor(
+        center: Vector3<f64>,
+        horizontal: Vector3<f64>,
+        vertical: Vector3<f64>,
+        pixel_pitch: f64,
+        sensor: [usize; 2],
+        magnification: f64,
+    ) -> Self {
+        assert!(magnification != 0.0, "Magnification must be nonzero.");
+        // An inverted image reverses both axes, which leaves the image plane normal unchanged.
+        let sign = magnification.signum();
+        let mut camera = Self::new(center, sign * horizontal, sign * vertical, pixel_pitch / magnification.abs(), sensor);
+        camera.magnification = magnification;
+        camera
+    }
+
+    /// Number of pixels along (horizontal, vertical).
+    pub fn pixel_number(&self) -> [usize; 2] {
+        self.pixel_number
+    }
+
+    /// The weight registered by each pixel, after [Saturation], as an array indexed by `[row][column]` with the
+    /// dimensions of the sensor.
+    pub fn image(&self) -> Array2<f64> {
+        Array2::from_shape_vec((self.pixel_number[1], self.pixel_number[0]), self.saturated_weights())
+            .expect("Camera pixel count does not match the sensor dimensions.")
+    }
+
+    /// The weight accumulated in each pixel, before [Saturation], in row-major order.
+    pub fn weights(&self) -> Vec<f64> {
+        self.cells.iter().map(|v| f64::from_bits(v.load(Ordering::Relaxed))).collect()
+    }
+
+    /// The weight registered by each pixel, in row-major order. If the pixels saturate, the weights are clipped at
+    /// the full-well capacity, and the overflow spread by any [Blooming]; otherwise they are the [CameraHistogram::weights].
+    pub fn saturated_weights(&self) -> Vec<f64> {
+        let mut weights = self.weights();
+        if let Some(saturation) = &self.saturation {
+            saturation.apply(&mut weights, self.pixel_number[0]);
+        }
+        weights
+    }
+
+    /// Number of pixels whose accumulated weight exceeds the full-well capacity, or 0 if the pixels do not saturate.
+    pub fn saturated_pixels(&self) -> usize {
+        match &self.saturation {
+            Some(saturation) => self.weights().iter().filter(|&&w| w > saturation.full_well).count(),
+            None => 0,
+        }
+    }
+
+    /// Adds `number` background counts, each to a pixel chosen uniformly at random.
+    ///
+    /// The counts are tallied in [CameraHistogram::background].
+    pub fn add_background<R: Rng + ?Sized>(&self, number: u64, rng: &mut R) {
+        if self.cells.is_empty() {
+            return;
+        }
+        for _ in 0..number {
+            add_f64(&self.cells[rng.gen_range(0..self.cells.len())], 1.0);
+        }
+        self.background.fetch_add(number, Ordering::Relaxed);
+    }
+
+    /// Number of background counts added to the pixels, see [BackgroundRate].
+    ///
+    /// The background is uniform, so the mean background of each pixel is this divided by the number of pixels.
+    pub fn background(&self) -> u64 {
+        self.background.load(Ordering::Relaxed)
+    }
+
+    /// Counts a given position into the histogram, after projecting it onto the image plane.
+    pub fn count(&self, position: Vector3<f64>) {
+        self.count_weighted(position, 1.0);
+    }
+
+    /// Adds a fractional weight at a given position into the histogram, after projecting it onto the image plane.
+    ///
+    /// Positions which are not finite, or which project outside the sensor, are not counted.
+    pub fn count_weighted(&self, position: Vector3<f64>, weight: f64) {
+        if let Some(index) = self.get_index(position) {
+            add_f64(&self.cells[index], weight);
+        }
+    }
+
+    /// Project a position onto the image plane, giving (horizontal, vertical) coordinates in units of m.
+    pub fn project(&self, position: Vector3<f64>) -> [f64; 2] {
+        let delta = position - self.center;
+        [delta.dot(&self.horizontal), delta.dot(&self.vertical)]
+    }
+
+    /// Unit normal of the image plane, `horizontal` x `vertical`.
+    pub fn normal(&self) -> Vector3<f64> {
+        self.horizontal.cross(&self.vertical)
+    }
+
+    /// Counts a photon emitted from `position` along `direction` into the pixel where its ray crosses the image plane.
+    ///
+    /// Photons travelling parallel to the image plane are not counted.
+    pub fn count_ray(&self, position: Vector3<f64>, direction: Vector3<f64>) {
+        if let Some(crossing) = self.ray_crossing(position, direction) {
+            self.count(crossing);
+        }
+    }
+
+    /// The point at which the ray of a photon emitted from `position` along `direction` crosses the image plane.
+    ///
+    /// The image plane is the object plane imaged by the lens, normally through the middle of the cloud, so atoms on
+    /// the lens side of it are traced back along the ray to the plane, as the lens images them. Returns `None` for
+    /// photons travelling parallel to the image plane.
+    pub fn ray_crossing(&self, position: Vector3<f64>, direction: Vector3<f64>) -> Option<Vector3<f64>> {
+        let normal = self.normal();
+        let denominator = direction.dot(&normal);
+        if denominator == 0.0 {
+            return None;
+        }
+        let t = (self.center - position).dot(&normal) / denominator;
+        Some(position + t * direction)
+    }
+
+    /// Get the pixel index for a given position.
+    ///
+    /// Pixels are stored in row-major order.
+    fn get_index(&self, position: Vector3<f64>) -> Option<usize> {
+        if !position.iter().all(|x| x.is_finite()) {
+            return None;
+        }
+        pixel_index(self.project(position), self.pixel_size, self.pixel_number)
+    }
+
+    /// Writes the image to a csv file, with one line per row of pixels.
+    pub fn write_to_file(&self, file_name: String) {
+        let mut writer = Self::create_writer(&file_name);
+        for row in self.saturated_weights().chunks(self.pixel_number[0]) {
+            let line: Vec<String> = row
+                .iter()
+                .map(|v| v.to_string())
+                .collect();
+            writeln!(writer, "{}", line.join(",")).expect("Could not write output.");
+        }
+    }
+
+    /// Writes the image to a plain-text (P2) pgm file, which can be opened directly by most image viewers.
+    ///
+    /// Weights are rescaled so that the brightest pixel has the maximum gray value.
+    pub fn write_to_pgm(&self, file_name: String) {
+        const MAX_GRAY: u64 = 65535;
+        let mut writer = Self::create_writer(&file_name);
+        let counts = self.saturated_weights();
+        let max = counts.iter().copied().fold(0.0, f64::max);
+        let max = if max > 0.0 { max } else { 1.0 };
+        writeln!(writer, "P2\n{} {}\n{}", self.pixel_number[0], self.pixel_number[1], MAX_GRAY)
+            .expect("Could not write output.");
+        for row in counts.chunks(self.pixel_number[0]) {
+            let line: Vec<String> = row
+                .iter()
+                .map(|&c| ((c / max) * MAX_GRAY as f64).round().to_string())
+                .collect();
+            writeln!(writer, "{}", line.join(" ")).expect("Could not write output.");
+        }
+    }
+
+    fn create_writer(file_name: &str) -> BufWriter<File> {
+        let path = Path::new(file_name);
+        let display = path.display();
+        let file = match File::create(&path) {
+            Err(why) => panic!("couldn't open {}: {}", display, why),
+            Ok(file) => file,
+        };
+        BufWriter::new(file)
+    }
+}
+
+/// Converts the photons counted by a [CameraHistogram] into the 16 bit digital counts of a camera frame.
+///
+/// The weight of each pixel is converted to electrons by the conversion gain, gaussian read noise is added, and the
+/// electrons are digitised to analog-to-digital units (ADU), clipped to the range of a 16 bit converter. The shot
+/// noise of the photons is already present in the counts, so is not added again.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraReadout {
+    /// Conversion gain, in electrons per photon, e.g. the quantum efficiency, or the EM gain of an EMCCD.
+    pub gain: f64,
+    /// Rms read noise, in electrons.
+    pub read_noise: f64,
+    /// Number of electrons per ADU.
+    pub electrons_per_adu: f64,
+    /// Offset added to every pixel, in ADU, so that negative read noise is not clipped at zero.
+    pub offset: f64,
+}
+impl CameraReadout {
+    /// Maximum value of the 16 bit analog-to-digital converter.
+    pub const MAX_ADU: u16 = u16::MAX;
+
+    /// Create a new [CameraReadout] with the conversion gain, in electrons per photon, and the rms read noise, in
+    /// electrons. Each electron is one ADU, with no offset, unless set by [CameraReadout::with_adc].
+    pub fn new(gain: f64, read_noise: f64) -> Self {
+        assert!(gain >= 0.0 && read_noise >= 0.0, "The gain and read noise must not be negative.");
+        CameraReadout { gain, read_noise, electrons_per_adu: 1.0, offset: 0.0 }
+    }
+
+    /// Set the number of electrons per ADU, and the offset of each pixel in ADU.
+    pub fn with_adc(self, electrons_per_adu: f64, offset: f64) -> Self {
+        assert!(electrons_per_adu > 0.0, "The number of electrons per ADU must be positive.");
+        CameraReadout { electrons_per_adu, offset, ..self }
+    }
+
+    /// Reads out the camera, returning the digital counts of each pixel as an array indexed by `[row][column]`,
+    /// like [CameraHistogram::image].
+    pub fn read_out<R: Rng + ?Sized>(&self, camera: &CameraHistogram, rng: &mut R) -> Array2<u16> {
+        let noise = Normal::new(0.0, self.read_noise).expect("Invalid read noise.");
+        camera.image().mapv(|photons| {
+            let electrons = photons * self.gain + noise.sample(rng);
+            (electrons / self.electrons_per_adu + self.offset).round().clamp(0.0, Self::MAX_ADU as f64) as u16
+        })
+    }
+
+    /// Reads out the camera, see [CameraReadout::read_out], and writes the frame to an h5 file.
+    ///
+    /// The frame is stored as a 2D u16 dataset 'frame' indexed as `[row][column]`, with the `gain`, `read_noise`,
+    /// `electrons_per_adu` and `offset` of the readout as attributes.
+    pub fn write_to_h5<R: Rng + ?Sized>(&self, camera: &CameraHistogram, file_name: String, rng: &mut R) -> Result<(), hdf5::Error> {
+        let frame = self.read_out(camera, rng);
+        let file = hdf5::File::create(file_name)?;
+        let dataset = file.new_dataset_builder().with_data(&frame).create("frame")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.gain])).create("gain")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.read_noise])).create("read_noise")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.electrons_per_adu])).create("electrons_per_adu")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.offset])).create("offset")?;
+        Ok(())
+    }
+}
+
+/// How the [CameraHistogramSystem] accounts for the collection of photons by the lens, when there is a [NumericalAperture].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CollectionModel {
+    /// Sample a direction for each photon, and count it where it crosses the image plane if it lies within the
+    /// collection cone; otherwise, reject it.
+    Rejection,
+    /// Sample a direction for each photon uniformly within the collection cone, and count it where it crosses the
+    /// image plane, with a weight equal to the probability that the [EmissionPattern] emits a photon into the cone
+    /// about that direction, `2π(1 - cos θ) p(n)`. For isotropic emission, this is the fraction of 4π subtended by
+    /// the cone, `(1 - cos θ)/2`.
+    ///
+    /// Every photon is counted, so this has lower variance than [CollectionModel::Rejection], with the same mean.
+    SolidAngleWeight,
+}
+impl Default for CollectionModel {
+    fn default() -> Self {
+        CollectionModel::Rejection
+    }
+}
+
+/// Counts photons scattered by each atom into the [CameraHistogram].
+///
+/// Without a [NumericalAperture] resource, every photon is counted at the projection of its emission position.
+/// With a [NumericalAperture], only photons emitted within the collection cone about the optical axis are counted,
+/// at the point where they cross the image plane.
+/// With a [DetectionEfficiency], each photon is only counted with the given probability.
+/// The photons of atoms with an [AtomRng] are sampled from it, see [super::list::seed_atoms], so that the photons
+/// counted, and those lost to the detection efficiency, are reproducible.
+/// The [CollectionModel] resource selects how the collection cone is applied.
+/// With a [PointSpreadFunction], each counted photon is displaced by a random offset drawn from the blur at the
+/// depth of the emitting atom.
+/// With a [Vignetting], the weight of each photon is scaled by the collection efficiency where it lands.
+/// With a [BackgroundRate], background counts are added to the pixels each frame, without vignetting.
+/// The system does nothing during the [WarmupSteps].
+pub struct CameraHistogramSystem;
+impl<'a> System<'a> for CameraHistogramSystem {
+    type SystemData = (
+        ReadExpect<'a, CameraHistogram>,
+        ReadStorage<'a, ActualPhotonsScatteredVector>,
+        ReadStorage<'a, Position>,
+        Read<'a, PhotonCountingMode>,
+        Read<'a, EmissionPattern>,
+        Option<Read<'a, NumericalAperture>>,
+        Option<Read<'a, LensGeometry>>,
+        Option<Read<'a, DetectionEfficiency>>,
+        Read<'a, CollectionModel>,
+        Option<Read<'a, PointSpreadFunction>>,
+        Option<Read<'a, Vignetting>>,
+        Read<'a, FrameNumber>,
+        Read<'a, WarmupSteps>,
+        Option<Read<'a, BackgroundRate>>,
+        ReadExpect<'a, Timestep>,
+        WriteStorage<'a, AtomRng>,
+    );
+    fn run(&mut self, (camera, totals, positions, mode, pattern, aperture, lens, efficiency, model, psf, vignetting, frame, warmup, background, timestep, mut rngs): Self::SystemData) {
+        use rayon::prelude::*;
+
+        if warmup.is_warming_up(*frame) {
+            return;
+        }
+
+        let efficiency = efficiency.as_deref().copied();
+        let psf = psf.as_deref().copied();
+        let vignetting = vignetting.as_deref();
+
+        let collection = aperture.map(|na| {
+            let axis = match lens {
+                Some(lens) => lens.optical_axis.normalize(),
+                None => camera.normal(),
+            };
+            (axis, na.cos_half_angle())
+        });
+
+        (&totals, &positions, (&mut rngs).maybe()).par_join().for_each(|(total, position, atom_rng)| {
+            let mut thread_rng = rand::thread_rng();
+            let mut rng: &mut dyn RngCore = match atom_rng {
+                Some(atom_rng) => &mut atom_rng.0,
+                None => &mut thread_rng,
+            };
+            let number: u32 = total.contents.iter().map(|a| mode.sample(a.scattered, &mut rng)).sum();
+            for _ in 0..number {
+                if let Some(efficiency) = efficiency {
+                    if !efficiency.detects(&mut rng) {
+                        continue;
+                    }
+                }
+                let landing = match collection {
+                    None => Some((position.pos, 1.0)),
+                    Some((axis, cos_half_angle)) if *model == CollectionModel::SolidAngleWeight => {
+                        let direction = sample_in_cone(&axis, cos_half_angle, &mut rng);
+                        let weight = 2.0 * std::f64::consts::PI * (1.0 - cos_half_angle) * pattern.density(&direction);
+                        camera.ray_crossing(position.pos, direction).map(|crossing| (crossing, weight))
+                    }
+                    Some((axis, cos_half_angle)) => {
+                        let direction = pattern.sample(&mut rng);
+                        if direction.dot(&axis) >= cos_half_angle {
+                            camera.ray_crossing(position.pos, direction).map(|crossing| (crossing, 1.0))
+                        } else {
+                            None
+                        }
+                    }
+                };
+                if let Some((point, weight)) = landing {
+                    let point = point + psf.map_or(Vector3::zeros(), |psf| psf.sample_offset(&camera, &position.pos, &mut rng));
+                    let weight = match vignetting {
+                        Some(vignetting) => {
+                            let [h, v] = camera.project(point);
+                            weight * vignetting.efficiency((h * h + v * v).sqrt())
+                        }
+                        None => weight,
+                    };
+                    camera.count_weighted(point, weight);
+                }
+            }
+        });
+
+        if let Some(background) = background {
+            let mut rng = rand::thread_rng();
+            let number = background.sample(camera.cells.len(), timestep.delta, &mut rng);
+            camera.add_background(number, &mut rng);
+        }
+    }
+}
+
+/// Samples a unit vector uniformly over the solid angle of the cone of `cos_half_angle` about the unit vector `axis`.
+fn sample_in_cone<R: Rng + ?Sized>(axis: &Vector3<f64>, cos_half_angle: f64, rng: &mut R) -> Vector3<f64> {
+    let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - cos_half_angle);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * rng.gen::<f64>();
+    // An orthonormal basis about the axis, from the coordinate axis least aligned with it.
+    let other = if axis.x.abs() < 0.9 { Vector3::x() } else { Vector3::y() };
+    let u = axis.cross(&other).normalize();
+    let v = axis.cross(&u);
+    cos_theta * axis + sin_theta * (phi.cos() * u + phi.sin() * v)
+}
+
+/// Index of the pixel containing a position `projected` onto a sensor of `pixel_number` square pixels of side
+/// `pixel_size`, centered on the optical axis. Pixels are stored in row-major order. Returns `None` if the position
+/// is off the sensor.
+///
+/// The bounds are checked on the floating point pixel coordinate before it is cast, so that distant positions, e.g.
+/// from near-grazing rays, cannot overflow the index.
+pub(crate) fn pixel_index(projected: [f64; 2], pixel_size: f64, pixel_number: [usize; 2]) -> Option<usize> {
+    let mut indices = [0usize; 2];
+    for axis in 0..2 {
+        let n = pixel_number[axis];
+        let i = (projected[axis] / pixel_size).floor() + (n / 2) as f64;
+        if !(i >= 0.0 && i < n as f64) {
+            return None;
+        }
+        indices[axis] = i as usize;
+    }
+    Some(indices[1] * pixel_number[0] + indices[0])
+}
@@ -0,0 +1,218 @@
+//! A histogram of where photons are produced, binned in cylindrical coordinates about an axis.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use atomecs::atom::Position;
+use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
+use nalgebra::Vector3;
+use ndarray::{arr1, Array2};
+use specs::prelude::*;
+
+use super::{FrameNumber, PhotonCountingMode, WarmupSteps};
+
+/// A 2D histogram of photon emission positions, binned by radial distance `r` from an axis and axial position `z` along it.
+///
+/// For clouds which are cylindrically symmetric about the axis, e.g. the axis of the imaging beam, each bin collects
+/// the photons of a whole ring, so the statistics per bin are far better than for a cartesian [super::PhotonHistogram].
+///
+/// The radial bins cover `0 <= r < radius`, and the axial bins cover `-length/2 <= z < length/2` about the `origin`.
+pub struct CylindricalHistogram {
+    /// Position of the center of the histogram on the axis, in units of m.
+    pub origin: Vector3<f64>,
+    /// Unit vector along the axis of symmetry.
+    pub axis: Vector3<f64>,
+    /// Radial size of a cell, in units of m.
+    pub radial_cell_size: f64,
+    /// Axial size of a cell, in units of m.
+    pub axial_cell_size: f64,
+    /// Number of cells along (r, z).
+    cell_number: [usize; 2],
+    /// Counts of each cell, with z varying fastest.
+    cells: Vec<AtomicU64>,
+    /// Number of photons which were outside the histogram, or at a non-finite position.
+    outside: AtomicU64,
+}
+impl CylindricalHistogram {
+    /// Create a new [CylindricalHistogram].
+    ///
+    /// # Arguments
+    ///
+    /// * `origin`: position of the center of the histogram on the axis, in units of m.
+    ///
+    /// * `axis`: direction of the axis of symmetry.
+    ///
+    /// * `radius`: outer radius of the histogram, in units of m.
+    ///
+    /// * `length`: length of the histogram along the axis, in units of m.
+    ///
+    /// * `cell_number`: number of cells along the radial and axial directions.
+    pub fn new(
+        origin: Vector3<f64>,
+        axis: Vector3<f64>,
+        radius: f64,
+        length: f64,
+        cell_number: [usize; 2],
+    ) -> Self {
+        assert!(axis.norm() > 0.0, "The histogram axis must be nonzero.");
+        CylindricalHistogram {
+            origin,
+            axis: axis.normalize(),
+            radial_cell_size: radius / cell_number[0] as f64,
+            axial_cell_size: length / cell_number[1] as f64,
+            cell_number,
+            cells: (0..(cell_number[0] * cell_number[1])).map(|_| AtomicU64::new(0)).collect(),
+            outside: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of cells along (r, z).
+    pub fn cell_number(&self) -> [usize; 2] {
+        self.cell_number
+    }
+
+    /// Converts a position into cylindrical (r, z) coordinates about the axis, in units of m.
+    pub fn cylindrical(&self, position: Vector3<f64>) -> [f64; 2] {
+        let delta = position - self.origin;
+        let z = delta.dot(&self.axis);
+        [(delta - z * self.axis).norm(), z]
+    }
+
+    /// Counts a given position into the histogram.
+    ///
+    /// Positions outside the histogram, or which are not finite, are tallied in [CylindricalHistogram::outside].
+    pub fn count(&self, position: Vector3<f64>) {
+        match self.get_index(position) {
+            Some(index) => { self.cells[index].fetch_add(1, Ordering::Relaxed); }
+            None => { self.outside.fetch_add(1, Ordering::Relaxed); }
+        }
+    }
+
+    /// Number of counts which were outside the histogram, or at a non-finite position.
+    pub fn outside(&self) -> u64 {
+        self.outside.load(Ordering::Relaxed)
+    }
+
+    /// Get the cell index for a given position.
+    ///
+    /// Cells are stored with z varying fastest.
+    fn get_index(&self, position: Vector3<f64>) -> Option<usize> {
+        if !position.iter().all(|x| x.is_finite()) {
+            return None;
+        }
+        let [r, z] = self.cylindrical(position);
+        let ir = (r / self.radial_cell_size).floor() as i64;
+        let nz = self.cell_number[1] as i64;
+        let iz = (z / self.axial_cell_size).floor() as i64 + nz / 2;
+        if ir >= self.cell_number[0] as i64 || iz < 0 || iz >= nz {
+            return None;
+        }
+        Some(ir as usize * self.cell_number[1] + iz as usize)
+    }
+
+    /// Get the counts in each cell of the histogram, with z varying fastest.
+    pub fn counts(&self) -> Vec<u64> {
+        self.cells.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Volume of each cell, in units of m³, in the same order as [CylindricalHistogram::counts].
+    ///
+    /// Dividing the counts by the volumes gives the density of emitted photons, e.g. for a radial density profile.
+    pub fn cell_volumes(&self) -> Vec<f64> {
+        let dr = self.radial_cell_size;
+        (0..self.cell_number[0])
+            .flat_map(|ir| {
+                let inner = ir as f64 * dr;
+                let outer = inner + dr;
+                let volume = std::f64::consts::PI * (outer * outer - inner * inner) * self.axial_cell_size;
+                std::iter::repeat(volume).take(self.cell_number[1])
+            })
+            .collect()
+    }
+
+    /// Writes the counts to a csv file, with one line per radial cell and one column per axial cell.
+    pub fn write_to_file(&self, file_name: String) {
+        let path = Path::new(&file_name);
+        let display = path.display();
+        let file = match File::create(&path) {
+            Err(why) => panic!("couldn't open {}: {}", display, why),
+            Ok(file) => file,
+        };
+        let mut writer = BufWriter::new(file);
+        for row in self.counts().chunks(self.cell_number[1]) {
+            let line: Vec<String> = row.iter().map(|c| c.to_string()).collect();
+            writeln!(writer, "{}", line.join(",")).expect("Could not write output.");
+        }
+    }
+
+    /// Writes the histogram to an h5 file.
+    ///
+    /// The counts are stored in a 2D dataset 'histogram' indexed as `[r][z]`, with attributes:
+    ///
+    /// * `radial_cell_size`: radial size of a cell, in units of m. Cell `i` covers `i*dr <= r < (i+1)*dr`.
+    ///
+    /// * `axial_cell_size`: axial size of a cell, in units of m.
+    ///
+    /// * `cell_number`: number of cells along (r, z).
+    ///
+    /// * `z_min`: axial position of the lower edge of the first axial cell, relative to the origin, in units of m.
+    ///
+    /// * `origin`: position of the center of the histogram on the axis, in units of m.
+    ///
+    /// * `axis`: unit vector along the axis.
+    ///
+    /// * `outside`: number of counts which were outside the histogram.
+    pub fn write_to_h5(&self, file_name: String) -> Result<(), hdf5::Error> {
+        let to_h5_error = |e: ndarray::ShapeError| hdf5::Error::from(e.to_string());
+
+        let file = hdf5::File::create(file_name)?;
+        let counts = Array2::from_shape_vec((self.cell_number[0], self.cell_number[1]), self.counts()).map_err(to_h5_error)?;
+        let dataset = file.new_dataset_builder().with_data(&counts).create("histogram")?;
+        let z_min = -((self.cell_number[1] / 2) as f64) * self.axial_cell_size;
+        dataset.new_attr_builder().with_data(&arr1(&[self.radial_cell_size])).create("radial_cell_size")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.axial_cell_size])).create("axial_cell_size")?;
+        dataset.new_attr_builder().with_data(&arr1(&self.cell_number.map(|n| n as u64))).create("cell_number")?;
+        dataset.new_attr_builder().with_data(&arr1(&[z_min])).create("z_min")?;
+        dataset.new_attr_builder().with_data(&arr1(self.origin.as_slice())).create("origin")?;
+        dataset.new_attr_builder().with_data(&arr1(self.axis.as_slice())).create("axis")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.outside()])).create("outside")?;
+        Ok(())
+    }
+}
+
+/// Counts the photons scattered by each atom into the [CylindricalHistogram].
+///
+/// The system does nothing during the [WarmupSteps].
+pub struct CylindricalHistogramSystem;
+impl<'a> System<'a> for CylindricalHistogramSystem {
+    type SystemData = (
+        ReadExpect<'a, CylindricalHistogram>,
+        ReadStorage<'a, ActualPhotonsScatteredVector>,
+        ReadStorage<'a, Position>,
+        Read<'a, PhotonCountingMode>,
+        Read<'a, FrameNumber>,
+        Read<'a, WarmupSteps>,
+    );
+    fn run(&mut self, (histogram, totals, positions, mode, frame, warmup): Self::SystemData) {
+        use rayon::prelude::*;
+
+        if warmup.is_warming_up(*frame) {
+            return;
+        }
+
+        (&totals, &positions).par_join().for_each(|(total, position)| {
+            let mut rng = rand::thread_rng();
+            let number: u32 = total.contents.iter().map(|a| mode.sample(a.scattered, &mut rng)).sum();
+            if number > 0 {
+                let index = histogram.get_index(position.pos);
+                match index {
+                    Some(index) => { histogram.cells[index].fetch_add(number as u64, Ordering::Relaxed); }
+                    None => { histogram.outside.fetch_add(number as u64, Ordering::Relaxed); }
+                }
+            }
+        });
+    }
+}
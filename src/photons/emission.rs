@@ -0,0 +1,182 @@
+//! Angular distributions used to sample the direction of emitted photons.
+
+use std::marker::PhantomData;
+
+use nalgebra::{Matrix3, Vector3};
+use ndarray::{arr1, Array2};
+use rand::Rng;
+use rand_distr::{Distribution, UnitSphere};
+use specs::prelude::*;
+
+use super::list::{OutputError, PhotonOutput, PhotonOutputter};
+
+/// The angular distribution of photons emitted by the atoms.
+///
+/// Non-isotropic patterns are sampled by rejection from a uniform distribution over the unit sphere.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EmissionPattern {
+    /// Photons are emitted uniformly in all directions.
+    Isotropic,
+    /// Emission from a linearly polarized dipole oriented along `axis`, distributed as sin²θ about the axis.
+    DipoleLinear { axis: Vector3<f64> },
+    /// Emission from a circularly polarized dipole rotating about `axis`, distributed as (1+cos²θ)/2 about the axis.
+    DipoleCircular { axis: Vector3<f64> },
+}
+impl Default for EmissionPattern {
+    fn default() -> Self {
+        EmissionPattern::Isotropic
+    }
+}
+impl EmissionPattern {
+    /// Sample a unit vector emission direction from this pattern.
+    ///
+    /// Downstream ray tracing and projection assume that directions have unit norm, so the sampled vector is
+    /// renormalised, and a degenerate (zero or non-finite) draw is discarded and resampled rather than emitted.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Vector3<f64> {
+        loop {
+            let v: [f64; 3] = UnitSphere.sample(rng);
+            let norm = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+            if !(norm.is_finite() && norm > 0.5) {
+                continue;
+            }
+            let direction = Vector3::new(v[0], v[1], v[2]) / norm;
+            let acceptance = match self {
+                EmissionPattern::Isotropic => return direction,
+                EmissionPattern::DipoleLinear { axis } => {
+                    let cos_theta = direction.dot(&axis.normalize());
+                    1.0 - cos_theta * cos_theta
+                }
+                EmissionPattern::DipoleCircular { axis } => {
+                    let cos_theta = direction.dot(&axis.normalize());
+                    (1.0 + cos_theta * cos_theta) / 2.0
+                }
+            };
+            if rng.gen::<f64>() < acceptance {
+                return direction;
+            }
+        }
+    }
+
+    /// The probability per unit solid angle, in units of sr⁻¹, that a photon is emitted along the unit vector `direction`.
+    pub fn density(&self, direction: &Vector3<f64>) -> f64 {
+        use std::f64::consts::PI;
+        match self {
+            EmissionPattern::Isotropic => 1.0 / (4.0 * PI),
+            EmissionPattern::DipoleLinear { axis } => {
+                let cos_theta = direction.dot(&axis.normalize());
+                3.0 / (8.0 * PI) * (1.0 - cos_theta * cos_theta)
+            }
+            EmissionPattern::DipoleCircular { axis } => {
+                let cos_theta = direction.dot(&axis.normalize());
+                3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta)
+            }
+        }
+    }
+
+    /// The expected second moment tensor `<n nᵀ>` of the emission direction `n`.
+    ///
+    /// The first moment `<n>` is zero for all patterns.
+    pub fn expected_second_moment(&self) -> Matrix3<f64> {
+        match self {
+            EmissionPattern::Isotropic => Matrix3::identity() / 3.0,
+            EmissionPattern::DipoleLinear { axis } => {
+                let a = axis.normalize();
+                Matrix3::identity() * 0.4 - a * a.transpose() * 0.2
+            }
+            EmissionPattern::DipoleCircular { axis } => {
+                let a = axis.normalize();
+                Matrix3::identity() * 0.3 + a * a.transpose() * 0.1
+            }
+        }
+    }
+}
+
+/// Sums of the directions of emitted photons, used to verify the sampled [EmissionPattern].
+///
+/// When this resource is present, the [RegisterPhotonsSystem](super::list::RegisterPhotonsSystem) adds the direction
+/// of every photon it emits, including photons which are not output.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct EmissionStatistics {
+    /// Number of photons included.
+    pub photons: u64,
+    /// Sum of the emission directions.
+    pub direction_sum: Vector3<f64>,
+    /// Sum of the outer products `n nᵀ` of the emission directions.
+    pub outer_product_sum: Matrix3<f64>,
+}
+impl EmissionStatistics {
+    /// Add the direction of an emitted photon.
+    pub fn add(&mut self, direction: &Vector3<f64>) {
+        self.photons += 1;
+        self.direction_sum += direction;
+        self.outer_product_sum += direction * direction.transpose();
+    }
+
+    /// Add the sums accumulated separately, e.g. by another thread.
+    pub fn merge(&mut self, other: &EmissionStatistics) {
+        self.photons += other.photons;
+        self.direction_sum += other.direction_sum;
+        self.outer_product_sum += other.outer_product_sum;
+    }
+
+    /// Mean emission direction.
+    pub fn mean(&self) -> Vector3<f64> {
+        self.direction_sum / self.photons.max(1) as f64
+    }
+
+    /// Second moment tensor `<n nᵀ>` of the emission directions.
+    pub fn second_moment(&self) -> Matrix3<f64> {
+        self.outer_product_sum / self.photons.max(1) as f64
+    }
+}
+
+/// Prints the [EmissionStatistics], and the second moment expected for the [EmissionPattern].
+///
+/// The mean direction is written to the 'emission_direction_mean' dataset of the [PhotonOutput] `T`, and the second
+/// moment to the 'emission_direction_second_moment' dataset, which has `photons` and `expected` attributes.
+pub struct EmissionStatisticsSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for EmissionStatisticsSystem<T> {
+    fn default() -> Self {
+        EmissionStatisticsSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for EmissionStatisticsSystem<T> {
+    type SystemData = (
+        Option<Read<'a, EmissionStatistics>>,
+        Read<'a, EmissionPattern>,
+        Option<Read<'a, T>>,
+        Write<'a, OutputError>,
+    );
+    fn run(&mut self, (statistics, pattern, output, mut error): Self::SystemData) {
+        let statistics = match statistics {
+            Some(statistics) if statistics.photons > 0 => statistics,
+            _ => return,
+        };
+        let mean = statistics.mean();
+        let second_moment = statistics.second_moment();
+        let expected = pattern.expected_second_moment();
+
+        println!(
+            "Emission statistics of {} photons: mean direction ({:.4}, {:.4}, {:.4}), expected 0.",
+            statistics.photons, mean[0], mean[1], mean[2]
+        );
+        println!("  Second moment:{}  Expected:{}", second_moment, expected);
+
+        if let Some(output) = output {
+            // nalgebra matrices are column-major, so the tensors are written row by row.
+            let to_array = |m: &Matrix3<f64>| Array2::from_shape_fn((3, 3), |(i, j)| m[(i, j)]);
+            error.record(output.write_diagnostic("emission_direction_mean", arr1(mean.as_slice()).view()));
+            error.record(
+                output.write_diagnostic("emission_direction_second_moment", to_array(&second_moment).view()).and_then(|dataset| {
+                    if let Some(dataset) = dataset {
+                        dataset.new_attr_builder().with_data(&arr1(&[statistics.photons])).create("photons")?;
+                        dataset.new_attr_builder().with_data(&to_array(&expected)).create("expected")?;
+                    }
+                    Ok(())
+                })
+            );
+        }
+    }
+}
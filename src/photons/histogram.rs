@@ -0,0 +1,971 @@
+//! A spatial histogram of where photons are produced.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use atomecs::atom::Position;
+use atomecs::integrator::Timestep;
+use dashmap::DashMap;
+use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
+use nalgebra::Vector3;
+use ndarray::{arr1, Array3, Array4};
+use rand::Rng;
+use specs::prelude::*;
+
+use super::emission::EmissionPattern;
+use super::list::{OutputError, PhotonEmission, PhotonSink};
+use super::{BackgroundRate, FrameNumber, PhotonCountingMode, WarmupSteps};
+
+/// Width of the counters used to store each cell of a [PhotonHistogram].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CounterType {
+    /// 32 bit counters, which halve the memory of the histogram but overflow above ~4.3e9 counts per cell.
+    U32,
+    /// 64 bit counters, which will not overflow for any achievable photon budget (~1.8e19).
+    U64,
+}
+impl Default for CounterType {
+    fn default() -> Self {
+        CounterType::U64
+    }
+}
+
+/// How the counts of a [PhotonHistogram] are stored in memory.
+///
+/// The dense backend allocates a counter for every cell up front: 8 bytes per cell for [CounterType::U64]
+/// (~1 GB for 512³ cells), or 4 bytes for [CounterType::U32]. Counting is a single atomic add.
+///
+/// The sparse backend only stores cells which have received photons, in a sharded concurrent hash map.
+/// Each occupied cell costs roughly 32 bytes including hash map overhead, and counting requires hashing
+/// and taking a shard lock, which is several times slower than the dense backend.
+/// As a rule of thumb, the sparse backend uses less memory when fewer than ~1/4 (u64) or ~1/8 (u32)
+/// of the cells are occupied, e.g. for thin or filamentary clouds in a large domain.
+///
+/// The weighted backend is dense, but accumulates a (fractional) f64 weight in each cell rather than an integer count,
+/// see [PhotonHistogram::count_weighted]. It uses 8 bytes per cell.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HistogramBackend {
+    Dense,
+    Sparse,
+    Weighted,
+}
+impl Default for HistogramBackend {
+    fn default() -> Self {
+        HistogramBackend::Dense
+    }
+}
+
+/// How a [PhotonHistogram] counts positions which lie outside its domain.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DomainPolicy {
+    /// Positions outside the domain are not counted, and are tallied in [PhotonHistogram::outside]. The histogram is
+    /// then an image of the cloud within a field of view.
+    Clip,
+    /// Positions outside the domain are counted into the nearest boundary cell, along each axis where they lie
+    /// outside. The boundary cells then hold every photon beyond them, like an image whose edge pixels collect
+    /// all of the light which would fall off the sensor, so the total count is conserved.
+    ClampToEdge,
+    /// Positions are wrapped back into the domain by the domain size along each axis, as for a periodic system.
+    /// This is appropriate for analyses of a cloud which is periodic in space, e.g. a lattice, where the histogram
+    /// covers one unit cell.
+    Wrap,
+}
+impl Default for DomainPolicy {
+    fn default() -> Self {
+        DomainPolicy::Clip
+    }
+}
+
+/// What [PhotonHistogram::downsampled] does when the number of cells along an axis is not a multiple of the factor.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DownsampleRemainder {
+    /// Discard the leftover cells at the upper end of the axis. Their counts are added to [PhotonHistogram::outside].
+    Truncate,
+    /// Return an error.
+    Error,
+}
+
+/// Storage for the counts in each cell of the histogram.
+///
+/// All atomic operations use [Ordering::Relaxed]. Each cell is an independent, monotonic counter: no other memory
+/// is published through it, so no ordering between cells is required, and atomic read-modify-write operations
+/// on a single cell never lose increments regardless of ordering. The counts are only read once all counting
+/// threads have been joined (e.g. at the end of a dispatch), which already synchronises the final values.
+/// Relaxed ordering avoids the full fences of [Ordering::SeqCst], which are costly when many threads update
+/// the few bright cells at the center of a peaked cloud.
+enum Counters {
+    U32(Vec<AtomicU32>),
+    U64(Vec<AtomicU64>),
+    Sparse { cells: DashMap<usize, u64>, length: usize },
+    /// f64 weights, stored as bits in an [AtomicU64] so they can be updated atomically.
+    Weighted(Vec<AtomicU64>),
+}
+impl Counters {
+    fn new(backend: HistogramBackend, counter_type: CounterType, length: usize) -> Self {
+        match (backend, counter_type) {
+            (HistogramBackend::Sparse, _) => Counters::Sparse { cells: DashMap::new(), length },
+            (HistogramBackend::Weighted, _) => Counters::Weighted((0..length).map(|_| AtomicU64::new(0.0f64.to_bits())).collect()),
+            (HistogramBackend::Dense, CounterType::U32) => Counters::U32((0..length).map(|_| AtomicU32::new(0)).collect()),
+            (HistogramBackend::Dense, CounterType::U64) => Counters::U64((0..length).map(|_| AtomicU64::new(0)).collect()),
+        }
+    }
+
+    fn add(&self, index: usize, n: u64) {
+        match self {
+            Counters::U32(cells) => { cells[index].fetch_add(n as u32, Ordering::Relaxed); }
+            Counters::U64(cells) => { cells[index].fetch_add(n, Ordering::Relaxed); }
+            Counters::Sparse { cells, .. } => { *cells.entry(index).or_insert(0) += n; }
+            Counters::Weighted(cells) => add_f64(&cells[index], n as f64),
+        }
+    }
+
+    /// Adds a fractional weight to the cell. Integer counters add the weight rounded to the nearest integer.
+    fn add_weight(&self, index: usize, weight: f64) {
+        match self {
+            Counters::Weighted(cells) => add_f64(&cells[index], weight),
+            _ => self.add(index, weight.round() as u64),
+        }
+    }
+
+    fn load(&self, index: usize) -> u64 {
+        match self {
+            Counters::U32(cells) => cells[index].load(Ordering::Relaxed) as u64,
+            Counters::U64(cells) => cells[index].load(Ordering::Relaxed),
+            Counters::Sparse { cells, .. } => cells.get(&index).map(|v| *v).unwrap_or(0),
+            Counters::Weighted(_) => self.load_weight(index).round() as u64,
+        }
+    }
+
+    fn load_weight(&self, index: usize) -> f64 {
+        match self {
+            Counters::Weighted(cells) => f64::from_bits(cells[index].load(Ordering::Relaxed)),
+            _ => self.load(index) as f64,
+        }
+    }
+
+    fn reset(&self) {
+        match self {
+            Counters::U32(cells) => cells.iter().for_each(|c| c.store(0, Ordering::Relaxed)),
+            Counters::U64(cells) => cells.iter().for_each(|c| c.store(0, Ordering::Relaxed)),
+            Counters::Sparse { cells, .. } => cells.clear(),
+            Counters::Weighted(cells) => cells.iter().for_each(|c| c.store(0.0f64.to_bits(), Ordering::Relaxed)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Counters::U32(cells) => cells.len(),
+            Counters::U64(cells) => cells.len(),
+            Counters::Sparse { length, .. } => *length,
+            Counters::Weighted(cells) => cells.len(),
+        }
+    }
+}
+
+/// Atomically adds `value` to an f64 stored as bits in `cell`.
+pub(crate) fn add_f64(cell: &AtomicU64, value: f64) {
+    let mut current = cell.load(Ordering::Relaxed);
+    loop {
+        let new = (f64::from_bits(current) + value).to_bits();
+        match cell.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// Determines how the [PhotonHistogramSystem] deposits the photons scattered by each atom.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HistogramDeposition {
+    /// Deposit an integer number of photons, sampled according to the [PhotonCountingMode].
+    Sampled,
+    /// Deposit the expected number of scattered photons as a fractional weight, giving a noise-free image.
+    ///
+    /// This should be used with the [HistogramBackend::Weighted] backend, otherwise the weights are rounded.
+    Weighted,
+}
+impl Default for HistogramDeposition {
+    fn default() -> Self {
+        HistogramDeposition::Sampled
+    }
+}
+
+/// This system constructs a spatial histogram of where photons are produced.
+/// 
+/// Atomic counters are used so that elements in the histogram can be updated from parallel threads -
+/// only a non-mutable borrow is required for the [PhotonHistogram] itself.
+///
+/// By default 64 bit counters are used, because a single bright cell can exceed the ~4.3e9 limit of a u32 during a long exposure.
+/// 32 bit counters can be selected with [PhotonHistogramBuilder::counter_type] to halve the memory of the histogram.
+pub struct PhotonHistogram {
+    /// Size of a histogram cell along each axis, in units of m.
+    pub cell_size: Vector3<f64>,
+    /// Position of the lower corner of cell `n/2` along each axis, where `n` is the number of cells, in units of m.
+    /// This is the center of the domain along axes with an even number of cells, and half a cell below the center
+    /// along axes with an odd number. See [PhotonHistogram::lower_corner] for the corner of the domain.
+    pub origin: Vector3<f64>,
+    cell_number: [usize; 3],
+    cells: Counters,
+    /// Number of photons dropped because they were emitted at a non-finite position.
+    dropped: AtomicU64,
+    /// Number of photons which fell outside the histogram domain.
+    outside: AtomicU64,
+    /// Number of background counts added to the cells, see [BackgroundRate].
+    background: AtomicU64,
+    domain_policy: DomainPolicy,
+    /// Sum of the directions of the photons counted into each cell, if enabled with [PhotonHistogramBuilder::flux].
+    /// Each component is an f64 stored as bits in an [AtomicU64].
+    flux: Option<Vec<[AtomicU64; 3]>>,
+}
+impl PhotonHistogram {
+    /// Create a new [PhotonHistogram]. 
+    /// 
+    /// # Arguments
+    /// 
+    /// * `domain_size`: size of the histogram domain along each axis in units of m.
+    /// 
+    /// * `cell_number`: number of cells along each axis of the histogram.
+    pub fn new(
+        domain_size: Vector3<f64>,
+        cell_number: [usize; 3]
+    ) -> Self
+    {
+        Self::create(domain_size, cell_number, Vector3::zeros(), HistogramBackend::default(), CounterType::default())
+    }
+
+    /// Create a new cubic [PhotonHistogram], with the same size and number of cells along each axis.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `domain_size`: size of the histogram domain in units of m.
+    /// 
+    /// * `cell_number`: number of cells along one dimension of the histogram.
+    pub fn new_cubic(
+        domain_size: f64,
+        cell_number: usize
+    ) -> Self
+    {
+        Self::new(Vector3::repeat(domain_size), [cell_number; 3])
+    }
+
+    /// Create a [PhotonHistogram] whose domain is sized to contain the given positions, e.g. the initial atom positions.
+    ///
+    /// # Arguments
+    ///
+    /// * `positions`: positions which the domain must contain, in units of m. Non-finite positions are ignored.
+    ///
+    /// * `margin`: distance added to each side of the domain, beyond the extent of the positions, in units of m.
+    ///   The upper edge of the domain is excluded, so this should be positive for the outermost positions to be counted.
+    ///
+    /// * `growth`: factor by which the extent of the positions is scaled about its center, before the margin is added.
+    ///   Atoms diffuse outward during imaging, so a factor greater than 1 keeps late photons inside the domain.
+    ///
+    /// * `cell_number`: number of cells along each axis of the histogram.
+    ///
+    /// Returns an error if there are no finite positions, or the resulting domain has zero size along an axis.
+    pub fn auto_size(
+        positions: &[Vector3<f64>],
+        margin: f64,
+        growth: f64,
+        cell_number: [usize; 3]
+    ) -> Result<Self, String>
+    {
+        let mut finite = positions.iter().filter(|p| p.iter().all(|x| x.is_finite()));
+        let first = finite.next().ok_or("Cannot size a histogram domain without any finite positions.")?;
+        let (min, max) = finite.fold((*first, *first), |(min, max), p| (min.inf(p), max.sup(p)));
+        let center = (min + max) / 2.0;
+        let domain = (max - min) * growth + Vector3::repeat(2.0 * margin);
+        // The domain is centered on the origin only for an even number of cells, so place the lower
+        // corner explicitly, see [PhotonHistogram::lower_corner].
+        let lower = center - domain / 2.0;
+        let origin = Vector3::from_fn(|axis, _| lower[axis] + (cell_number[axis] / 2) as f64 * domain[axis] / cell_number[axis].max(1) as f64);
+        PhotonHistogram::builder()
+            .domain(domain)
+            .cells(cell_number)
+            .origin(origin)
+            .build()
+    }
+
+    /// Create a [PhotonHistogramBuilder], to configure a new [PhotonHistogram].
+    pub fn builder() -> PhotonHistogramBuilder {
+        PhotonHistogramBuilder::default()
+    }
+
+    fn create(
+        domain_size: Vector3<f64>,
+        cell_number: [usize; 3],
+        origin: Vector3<f64>,
+        backend: HistogramBackend,
+        counter_type: CounterType
+    ) -> Self
+    {
+        PhotonHistogram {
+            origin,
+            cell_size: Vector3::new(
+                domain_size[0] / cell_number[0] as f64,
+                domain_size[1] / cell_number[1] as f64,
+                domain_size[2] / cell_number[2] as f64,
+            ),
+            cells: Counters::new(backend, counter_type, cell_number[0]*cell_number[1]*cell_number[2]),
+            cell_number,
+            dropped: AtomicU64::new(0),
+            outside: AtomicU64::new(0),
+            background: AtomicU64::new(0),
+            domain_policy: DomainPolicy::default(),
+            flux: None,
+        }
+    }
+
+    fn new_flux(length: usize) -> Vec<[AtomicU64; 3]> {
+        // The bits of 0.0 are zero, so the default counters are empty.
+        (0..length).map(|_| Default::default()).collect()
+    }
+
+    /// How positions outside the domain are counted, see [DomainPolicy].
+    pub fn domain_policy(&self) -> DomainPolicy {
+        self.domain_policy
+    }
+
+    /// Counts a given position into the histogram.
+    ///
+    /// Non-finite positions are not counted, and are instead tallied in [PhotonHistogram::dropped].
+    /// Positions outside the domain are counted according to the [DomainPolicy]; with [DomainPolicy::Clip],
+    /// they are tallied in [PhotonHistogram::outside].
+    pub fn count(&self, position: Vector3<f64>) {
+        if !self.check_finite(&position) {
+            return;
+        }
+        match self.get_index(position) {
+            Some(index) => self.cells.add(index, 1),
+            None => { self.outside.fetch_add(1, Ordering::Relaxed); }
+        }
+    }
+
+    /// Counts a photon emitted at the given position along the unit `direction` into the histogram.
+    ///
+    /// The position is counted as for [PhotonHistogram::count]. If the flux is enabled, see
+    /// [PhotonHistogramBuilder::flux], the direction is also added to the flux of the cell.
+    pub fn count_directed(&self, position: Vector3<f64>, direction: Vector3<f64>) {
+        if !self.check_finite(&position) {
+            return;
+        }
+        match self.get_index(position) {
+            Some(index) => {
+                self.cells.add(index, 1);
+                if let Some(flux) = &self.flux {
+                    for axis in 0..3 {
+                        add_f64(&flux[index][axis], direction[axis]);
+                    }
+                }
+            }
+            None => { self.outside.fetch_add(1, Ordering::Relaxed); }
+        }
+    }
+
+    /// Returns true if the histogram accumulates the flux of photon directions, see [PhotonHistogramBuilder::flux].
+    pub fn has_flux(&self) -> bool {
+        self.flux.is_some()
+    }
+
+    /// Get the sum of the directions of the photons counted into each cell, with x varying fastest, then y, then z,
+    /// or `None` if the flux is not enabled.
+    pub fn flux(&self) -> Option<Vec<Vector3<f64>>> {
+        self.flux.as_ref().map(|flux| flux.iter()
+            .map(|cell| Vector3::from_fn(|axis, _| f64::from_bits(cell[axis].load(Ordering::Relaxed))))
+            .collect())
+    }
+
+    /// Get the mean direction of the photons counted into each cell, the [PhotonHistogram::flux] divided by the weight
+    /// of the cell, or `None` if the flux is not enabled. Empty cells have a mean direction of zero.
+    ///
+    /// The length of the mean direction is 0 for isotropic emission, and 1 if every photon of the cell is emitted the
+    /// same way, so it shows the net photon flux through each cell.
+    pub fn mean_directions(&self) -> Option<Vec<Vector3<f64>>> {
+        self.flux().map(|flux| flux.into_iter().zip(self.weights())
+            .map(|(sum, weight)| if weight > 0.0 { sum / weight } else { Vector3::zeros() })
+            .collect())
+    }
+
+    /// Adds a fractional weight at the given position into the histogram.
+    ///
+    /// The weight is only stored exactly by the [HistogramBackend::Weighted] backend; other backends round it to an integer.
+    /// Non-finite positions are not counted, and are instead tallied in [PhotonHistogram::dropped].
+    /// Positions outside the domain are counted according to the [DomainPolicy]; with [DomainPolicy::Clip],
+    /// they are tallied in [PhotonHistogram::outside], once per call.
+    pub fn count_weighted(&self, position: Vector3<f64>, weight: f64) {
+        if !self.check_finite(&position) {
+            return;
+        }
+        match self.get_index(position) {
+            Some(index) => self.cells.add_weight(index, weight),
+            None => { self.outside.fetch_add(1, Ordering::Relaxed); }
+        }
+    }
+
+    /// Adds `number` background counts, each to a cell chosen uniformly at random.
+    ///
+    /// The counts are tallied in [PhotonHistogram::background].
+    pub fn add_background<R: Rng + ?Sized>(&self, number: u64, rng: &mut R) {
+        let length = self.cells.len();
+        if length == 0 {
+            return;
+        }
+        for _ in 0..number {
+            self.cells.add(rng.gen_range(0..length), 1);
+        }
+        self.background.fetch_add(number, Ordering::Relaxed);
+    }
+
+    /// Sets the counts of every cell to zero.
+    ///
+    /// The tallies of [PhotonHistogram::dropped], [PhotonHistogram::outside] and [PhotonHistogram::background] are kept. This must not be called
+    /// while other threads are counting into the histogram.
+    pub fn reset(&self) {
+        self.cells.reset();
+        if let Some(flux) = &self.flux {
+            flux.iter().flatten().for_each(|c| c.store(0.0f64.to_bits(), Ordering::Relaxed));
+        }
+    }
+
+    /// Number of counts dropped because they were at a non-finite position, e.g. from an atom with a NaN position.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of counts which fell outside the histogram domain, and so were not counted.
+    ///
+    /// A large number indicates that the domain is too small to contain the cloud, see [PhotonHistogram::auto_size].
+    pub fn outside(&self) -> u64 {
+        self.outside.load(Ordering::Relaxed)
+    }
+
+    /// Number of background counts added to the cells, see [BackgroundRate].
+    ///
+    /// The background is uniform, so the mean background of each cell is this divided by the number of cells.
+    pub fn background(&self) -> u64 {
+        self.background.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the position is finite, otherwise tallies it as dropped.
+    ///
+    /// Casting a NaN to an integer gives zero, so without this check a non-finite position would silently
+    /// be counted into the central cell.
+    fn check_finite(&self, position: &Vector3<f64>) -> bool {
+        let finite = position.iter().all(|x| x.is_finite());
+        if !finite {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        finite
+    }
+
+    /// Get the cell index for a given position, or `None` if it lies outside the domain and the [DomainPolicy] is
+    /// [DomainPolicy::Clip].
+    ///
+    /// Cells are stored with x varying fastest, then y, then z.
+    fn get_index(&self, position: Vector3<f64>) -> Option<usize> {
+        let position = position - self.origin;
+        let mut indices = [0usize; 3];
+        for axis in 0..3 {
+            let n = self.cell_number[axis] as i64;
+            // floor, rather than truncate, so that bins are uniform across zero.
+            let i = (position[axis] / self.cell_size[axis]).floor() as i64 + n / 2;
+            let i = match self.domain_policy {
+                DomainPolicy::Clip if i < 0 || i >= n => return None,
+                DomainPolicy::Clip => i,
+                DomainPolicy::ClampToEdge => i.clamp(0, n - 1),
+                DomainPolicy::Wrap => i.rem_euclid(n),
+            };
+            indices[axis] = i as usize;
+        }
+        Some(
+            indices[2] * self.cell_number[1] * self.cell_number[0]
+            + indices[1] * self.cell_number[0]
+            + indices[0]
+        )
+    }
+
+    /// Get the counts in each cell of the histogram, with x varying fastest, then y, then z.
+    pub fn counts(&self) -> Vec<u64> {
+        (0..self.cells.len()).map(|i| self.cells.load(i)).collect()
+    }
+
+    /// Get the weight in each cell of the histogram, with x varying fastest, then y, then z.
+    ///
+    /// For integer backends this is equal to the counts.
+    pub fn weights(&self) -> Vec<f64> {
+        (0..self.cells.len()).map(|i| self.cells.load_weight(i)).collect()
+    }
+
+    /// Writes the counts of each cell to a text file, separated by commas, with x varying fastest, then y, then z.
+    ///
+    /// If the flux is enabled, see [PhotonHistogramBuilder::flux], each cell is instead written on its own line as
+    /// `count,dx,dy,dz`, where `(dx,dy,dz)` is the [PhotonHistogram::mean_directions] of the cell.
+    pub fn write_to_file(&self, file_name: String) {
+        let path = Path::new(&file_name);
+        let display = path.display();
+        let file = match File::create(&path) {
+            Err(why) => panic!("couldn't open {}: {}", display, why),
+            Ok(file) => file,
+        };
+        let mut writer = BufWriter::new(file);
+        match self.mean_directions() {
+            Some(directions) => {
+                for (v, d) in self.counts().into_iter().zip(directions) {
+                    writeln!(writer, "{:?},{:e},{:e},{:e}", v, d[0], d[1], d[2]).expect("Could not write output.");
+                }
+            }
+            None => {
+                for v in self.counts() {
+                    write!(writer, "{:?},", v).expect("Could not write output.");
+                }
+            }
+        }
+    }
+
+    /// Writes the histogram to a NumPy `.npy` file, which can be read with `numpy.load`.
+    ///
+    /// The array has shape `(z, y, x)`, the same as the 'histogram' dataset of [PhotonHistogram::write_to_h5], and
+    /// dtype `<u8` (u64 counts), or `<f8` for the [HistogramBackend::Weighted] backend.
+    /// The scales of the histogram are written to a companion json file, with the extension replaced by `.json`,
+    /// which has the `cell_size`, `cell_number`, `domain_size` and `lower_corner` of the h5 attributes.
+    pub fn write_to_npy(&self, file_name: String) -> std::io::Result<()> {
+        let (descr, data): (&str, Vec<u8>) = match self.cells {
+            Counters::Weighted(_) => ("<f8", self.weights().iter().flat_map(|w| w.to_le_bytes()).collect()),
+            _ => ("<u8", self.counts().iter().flat_map(|c| c.to_le_bytes()).collect()),
+        };
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}, {}), }}",
+            descr, self.cell_number[2], self.cell_number[1], self.cell_number[0]
+        );
+        // The magic string, version and header length take 10 bytes, and the header is padded with spaces and
+        // terminated by a newline so that the data starts on a 64 byte boundary.
+        let total = 10 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - total % 64) % 64));
+        header.push('\n');
+
+        let mut writer = BufWriter::new(File::create(&file_name)?);
+        writer.write_all(b"\x93NUMPY\x01\x00")?;
+        writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+        writer.write_all(&data)?;
+        writer.flush()?;
+
+        let json_name = Path::new(&file_name).with_extension("json");
+        let vector = |v: Vector3<f64>| format!("[{:e}, {:e}, {:e}]", v[0], v[1], v[2]);
+        let mut json = BufWriter::new(File::create(json_name)?);
+        writeln!(json, "{{")?;
+        writeln!(json, "  \"cell_size\": {},", vector(self.cell_size))?;
+        writeln!(json, "  \"cell_number\": [{}, {}, {}],", self.cell_number[0], self.cell_number[1], self.cell_number[2])?;
+        writeln!(json, "  \"domain_size\": {},", vector(self.domain_size()))?;
+        writeln!(json, "  \"lower_corner\": {}", vector(self.lower_corner()))?;
+        writeln!(json, "}}")?;
+        json.flush()
+    }
+
+    /// Sums the histogram over the other two axes, to give the line-integrated profile along `axis`, 0, 1 or 2 for x, y or z.
+    ///
+    /// Returns the position of the center of each slice of cells along the axis, in units of m, and the total weight of
+    /// the slice, which is its number of counts for the integer backends.
+    pub fn profile(&self, axis: usize) -> Vec<(f64, f64)> {
+        assert!(axis < 3, "The axis of the profile must be 0, 1 or 2, not {}.", axis);
+        let [nx, ny, _] = self.cell_number;
+        let mut sums = vec![0.0; self.cell_number[axis]];
+        for (index, weight) in self.weights().into_iter().enumerate() {
+            let cell = [index % nx, (index / nx) % ny, index / (nx * ny)];
+            sums[cell[axis]] += weight;
+        }
+        let lower = self.lower_corner()[axis];
+        sums.into_iter().enumerate()
+            .map(|(i, sum)| (lower + (i as f64 + 0.5) * self.cell_size[axis], sum))
+            .collect()
+    }
+
+    /// Writes the [PhotonHistogram::profile] along `axis` to a text file, with a `position,count` line for each slice.
+    ///
+    /// The positions are in units of m. This gives a quick check of the width and diffusion of the cloud along an axis.
+    pub fn write_profile(&self, file_name: String, axis: usize) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&file_name)?);
+        writeln!(writer, "position,count")?;
+        for (position, count) in self.profile(axis) {
+            writeln!(writer, "{:e},{}", position, count)?;
+        }
+        writer.flush()
+    }
+
+    /// Create a coarser copy of the histogram, in which each cell sums a block of `factor` cells along each axis.
+    ///
+    /// The lower corner of the domain is unchanged. Dense and sparse histograms are down-binned into 64 bit counters,
+    /// so that the sums do not overflow, and weighted histograms keep their weights.
+    /// Returns an error if a factor is zero or larger than the number of cells, or if the number of cells is not a
+    /// multiple of the factor and `remainder` is [DownsampleRemainder::Error].
+    pub fn downsampled(&self, factor: [usize; 3], remainder: DownsampleRemainder) -> Result<PhotonHistogram, String> {
+        let mut cell_number = [0usize; 3];
+        for axis in 0..3 {
+            if factor[axis] == 0 || factor[axis] > self.cell_number[axis] {
+                return Err(format!(
+                    "The down-binning factor {} along axis {} must be between 1 and the number of cells, {}.",
+                    factor[axis], axis, self.cell_number[axis]
+                ));
+            }
+            if remainder == DownsampleRemainder::Error && self.cell_number[axis] % factor[axis] != 0 {
+                return Err(format!(
+                    "The {} cells along axis {} are not a multiple of the down-binning factor {}.",
+                    self.cell_number[axis], axis, factor[axis]
+                ));
+            }
+            cell_number[axis] = self.cell_number[axis] / factor[axis];
+        }
+        let cell_size = Vector3::from_fn(|axis, _| self.cell_size[axis] * factor[axis] as f64);
+        let domain_size = Vector3::from_fn(|axis, _| cell_size[axis] * cell_number[axis] as f64);
+        let lower = self.lower_corner();
+        let origin = Vector3::from_fn(|axis, _| lower[axis] + (cell_number[axis] / 2) as f64 * cell_size[axis]);
+        let backend = match self.cells {
+            Counters::Sparse { .. } => HistogramBackend::Sparse,
+            Counters::Weighted(_) => HistogramBackend::Weighted,
+            _ => HistogramBackend::Dense,
+        };
+        let mut coarse = Self::create(domain_size, cell_number, origin, backend, CounterType::U64);
+        coarse.domain_policy = self.domain_policy;
+        if self.flux.is_some() {
+            coarse.flux = Some(Self::new_flux(coarse.cells.len()));
+        }
+        let flux = self.flux();
+
+        let [nx, ny, _] = self.cell_number;
+        let mut truncated = 0;
+        for index in 0..self.cells.len() {
+            let count = self.cells.load(index);
+            let weight = self.cells.load_weight(index);
+            if weight == 0.0 {
+                continue;
+            }
+            let fine = [index % nx, (index / nx) % ny, index / (nx * ny)];
+            let [x, y, z] = [fine[0] / factor[0], fine[1] / factor[1], fine[2] / factor[2]];
+            if x >= cell_number[0] || y >= cell_number[1] || z >= cell_number[2] {
+                truncated += count;
+                continue;
+            }
+            let coarse_index = z * cell_number[1] * cell_number[0] + y * cell_number[0] + x;
+            match self.cells {
+                Counters::Weighted(_) => coarse.cells.add_weight(coarse_index, weight),
+                _ => coarse.cells.add(coarse_index, count),
+            }
+            if let (Some(fine), Some(coarse)) = (&flux, &coarse.flux) {
+                for axis in 0..3 {
+                    add_f64(&coarse[coarse_index][axis], fine[index][axis]);
+                }
+            }
+        }
+        coarse.dropped.store(self.dropped(), Ordering::Relaxed);
+        coarse.outside.store(self.outside() + truncated, Ordering::Relaxed);
+        coarse.background.store(self.background(), Ordering::Relaxed);
+        Ok(coarse)
+    }
+
+    /// Writes a [PhotonHistogram::downsampled] copy of the histogram to an h5 file, see [PhotonHistogram::write_to_h5].
+    ///
+    /// This reduces the noise of a histogram accumulated at high resolution, without repeating the simulation.
+    pub fn write_downsampled(&self, file_name: String, factor: [usize; 3], remainder: DownsampleRemainder) -> Result<(), hdf5::Error> {
+        self.downsampled(factor, remainder).map_err(hdf5::Error::from)?.write_to_h5(file_name)
+    }
+
+    /// Size of the histogram domain along each axis, in units of m.
+    pub fn domain_size(&self) -> Vector3<f64> {
+        Vector3::new(
+            self.cell_number[0] as f64 * self.cell_size[0],
+            self.cell_number[1] as f64 * self.cell_size[1],
+            self.cell_number[2] as f64 * self.cell_size[2],
+        )
+    }
+
+    /// Position of the lower corner of the first cell of the histogram, in units of m.
+    pub fn lower_corner(&self) -> Vector3<f64> {
+        self.origin - Vector3::new(
+            ((self.cell_number[0] / 2) as f64) * self.cell_size[0],
+            ((self.cell_number[1] / 2) as f64) * self.cell_size[1],
+            ((self.cell_number[2] / 2) as f64) * self.cell_size[2],
+        )
+    }
+
+    /// Writes the histogram to an h5 file.
+    ///
+    /// The counts are stored in a 3D dataset 'histogram' indexed as `[z][y][x]`, with attributes:
+    ///
+    /// * `cell_size`: size of a cell along (x,y,z), in units of m.
+    ///
+    /// * `cell_number`: number of cells along (x,y,z).
+    ///
+    /// * `domain_size`: size of the histogram domain along (x,y,z), in units of m.
+    ///
+    /// * `lower_corner`: position of the lower corner of the first cell, in units of m, see [PhotonHistogram::lower_corner].
+    ///
+    /// * `outside`: number of counts which fell outside the domain.
+    ///
+    /// * `background`: number of background counts included in the histogram, see [BackgroundRate].
+    ///
+    /// The [HistogramBackend::Weighted] backend stores f64 weights; other backends store u64 counts.
+    /// If the flux is enabled, the [PhotonHistogram::mean_directions] are stored in a 4D dataset 'mean_direction'
+    /// indexed as `[z][y][x][axis]`.
+    pub fn write_to_h5(&self, file_name: String) -> Result<(), hdf5::Error> {
+        let shape = (self.cell_number[2], self.cell_number[1], self.cell_number[0]);
+        let to_h5_error = |e: ndarray::ShapeError| hdf5::Error::from(e.to_string());
+
+        let file = hdf5::File::create(file_name)?;
+        let dataset = match self.cells {
+            Counters::Weighted(_) => {
+                let weights = Array3::from_shape_vec(shape, self.weights()).map_err(to_h5_error)?;
+                file.new_dataset_builder().with_data(&weights).create("histogram")?
+            }
+            _ => {
+                let counts = Array3::from_shape_vec(shape, self.counts()).map_err(to_h5_error)?;
+                file.new_dataset_builder().with_data(&counts).create("histogram")?
+            }
+        };
+        let lower_corner = self.lower_corner();
+        dataset.new_attr_builder().with_data(&arr1(self.cell_size.as_slice())).create("cell_size")?;
+        dataset.new_attr_builder().with_data(&arr1(&self.cell_number.map(|n| n as u64))).create("cell_number")?;
+        dataset.new_attr_builder().with_data(&arr1(self.domain_size().as_slice())).create("domain_size")?;
+        dataset.new_attr_builder().with_data(&arr1(lower_corner.as_slice())).create("lower_corner")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.outside()])).create("outside")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.background()])).create("background")?;
+        if let Some(directions) = self.mean_directions() {
+            let components = directions.iter().flat_map(|d| d.iter().copied().collect::<Vec<f64>>()).collect();
+            let directions = Array4::from_shape_vec((shape.0, shape.1, shape.2, 3), components).map_err(to_h5_error)?;
+            file.new_dataset_builder().with_data(&directions).create("mean_direction")?;
+        }
+        Ok(())
+    }
+}
+
+/// Configures and validates the construction of a [PhotonHistogram].
+///
+/// ```ignore
+/// let histogram = PhotonHistogram::builder()
+///     .domain(Vector3::new(1e-3, 1e-3, 1e-3))
+///     .cells([128, 128, 32])
+///     .origin(Vector3::new(0.0, 0.0, 1e-4))
+///     .counter_type(CounterType::U32)
+///     .build()?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PhotonHistogramBuilder {
+    domain: Option<Vector3<f64>>,
+    cells: Option<[usize; 3]>,
+    origin: Vector3<f64>,
+    backend: HistogramBackend,
+    counter_type: CounterType,
+    domain_policy: DomainPolicy,
+    flux: bool,
+}
+impl PhotonHistogramBuilder {
+    /// Size of the histogram domain along each axis, in units of m.
+    pub fn domain(mut self, domain: Vector3<f64>) -> Self {
+        self.domain = Some(domain);
+        self
+    }
+
+    /// Number of cells along each axis of the histogram.
+    pub fn cells(mut self, cells: [usize; 3]) -> Self {
+        self.cells = Some(cells);
+        self
+    }
+
+    /// Position of the lower corner of cell `n/2` along each axis, in units of m, see [PhotonHistogram::origin].
+    /// This is the center of the domain for an even number of cells. Defaults to the coordinate origin.
+    pub fn origin(mut self, origin: Vector3<f64>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// How the counts are stored in memory, see [HistogramBackend]. Defaults to [HistogramBackend::Dense].
+    pub fn backend(mut self, backend: HistogramBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Width of the counter used for each cell of the dense backend.
+    pub fn counter_type(mut self, counter_type: CounterType) -> Self {
+        self.counter_type = counter_type;
+        self
+    }
+
+    /// How positions outside the domain are counted. Defaults to [DomainPolicy::Clip].
+    pub fn domain_policy(mut self, domain_policy: DomainPolicy) -> Self {
+        self.domain_policy = domain_policy;
+        self
+    }
+
+    /// Whether each cell also accumulates the sum of the directions of its photons, see [PhotonHistogram::flux].
+    /// This triples the memory of a dense histogram. Defaults to false.
+    pub fn flux(mut self, flux: bool) -> Self {
+        self.flux = flux;
+        self
+    }
+
+    /// Validate the configuration and build the [PhotonHistogram].
+    pub fn build(self) -> Result<PhotonHistogram, String> {
+        let domain = self.domain.ok_or("The histogram domain must be set.")?;
+        let cells = self.cells.ok_or("The number of histogram cells must be set.")?;
+        if domain.iter().any(|&d| !(d.is_finite() && d > 0.0)) {
+            return Err(format!("The histogram domain must be positive and finite along each axis, but was {:?}.", domain));
+        }
+        if cells.iter().any(|&n| n == 0) {
+            return Err(format!("The number of histogram cells must be nonzero along each axis, but was {:?}.", cells));
+        }
+        if cells.iter().any(|&n| n > i32::MAX as usize) {
+            return Err(format!("The number of histogram cells along each axis must not exceed {}.", i32::MAX));
+        }
+        if self.origin.iter().any(|x| !x.is_finite()) {
+            return Err(format!("The histogram origin must be finite, but was {:?}.", self.origin));
+        }
+        if self.flux && self.backend == HistogramBackend::Sparse {
+            return Err("The flux is stored densely, so it cannot be combined with the sparse histogram backend.".to_string());
+        }
+        let mut histogram = PhotonHistogram::create(domain, cells, self.origin, self.backend, self.counter_type);
+        histogram.domain_policy = self.domain_policy;
+        if self.flux {
+            histogram.flux = Some(PhotonHistogram::new_flux(histogram.cells.len()));
+        }
+        Ok(histogram)
+    }
+}
+
+/// Whether each [HistogramSnapshots] snapshot contains the photons since the start of the run, or since the last snapshot.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SnapshotMode {
+    /// The histogram accumulates over the whole run, so each snapshot is the integrated image so far.
+    Cumulative,
+    /// The histogram is reset after each snapshot, so each snapshot is the image of one interval.
+    Interval,
+}
+
+/// Periodically writes the [PhotonHistogram] during the run, e.g. to make a movie of the cloud diffusing.
+///
+/// When this resource is present, the [PhotonHistogramSystem] writes the histogram every `interval` frames, with
+/// [PhotonHistogram::write_to_h5], to a file named `{prefix}_frame{n}.h5`, where `n` is the number of frames completed.
+#[derive(Clone, Debug)]
+pub struct HistogramSnapshots {
+    /// Number of frames between snapshots.
+    pub interval: u32,
+    pub mode: SnapshotMode,
+    /// Prefix of the snapshot file names, which may include a directory.
+    pub prefix: String,
+}
+impl HistogramSnapshots {
+    /// File name of the snapshot taken after `frames` frames.
+    pub fn file_name(&self, frames: u32) -> String {
+        format!("{}_frame{}.h5", self.prefix, frames)
+    }
+}
+
+/// A [PhotonSink] which counts the positions of the photons appended to it into a shared [PhotonHistogram], e.g. for a
+/// quick look at the image alongside the photon list with a [PhotonBroadcast](super::list::PhotonBroadcast).
+///
+/// Unlike the [PhotonHistogramSystem], which samples its own photons, the histogram receives exactly the photons written
+/// to the other sinks. The directions are counted into the flux, if enabled. No [BackgroundRate] is added.
+pub struct HistogramSink {
+    histogram: Arc<PhotonHistogram>,
+    photons_written: AtomicU64,
+}
+impl HistogramSink {
+    /// Create a [HistogramSink] which counts photons into the given histogram.
+    pub fn new(histogram: Arc<PhotonHistogram>) -> Self {
+        HistogramSink { histogram, photons_written: AtomicU64::new(0) }
+    }
+
+    /// The histogram the photons are counted into.
+    pub fn histogram(&self) -> &Arc<PhotonHistogram> {
+        &self.histogram
+    }
+}
+impl PhotonSink for HistogramSink {
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), hdf5::Error> {
+        for photon in photons {
+            self.histogram.count_directed(photon.position, photon.direction);
+        }
+        self.photons_written.fetch_add(photons.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn photons_written(&self) -> u64 {
+        self.photons_written.load(Ordering::SeqCst)
+    }
+
+    fn records_frequency(&self) -> bool {
+        false
+    }
+}
+
+/// Counts the photons scattered by each atom into the [PhotonHistogram].
+///
+/// If there is a [HistogramSnapshots] resource, the histogram is also written periodically.
+/// If there is a [BackgroundRate] resource, background counts are added each frame.
+/// If the histogram accumulates the flux, see [PhotonHistogramBuilder::flux], a direction is sampled for each photon
+/// from the [EmissionPattern]. The [HistogramDeposition::Weighted] deposition adds no flux.
+/// The system does nothing during the [WarmupSteps].
+pub struct PhotonHistogramSystem;
+impl<'a> System<'a> for PhotonHistogramSystem {
+    type SystemData = (
+        ReadExpect<'a, PhotonHistogram>,
+        ReadStorage<'a, ActualPhotonsScatteredVector>,
+        ReadStorage<'a, Position>,
+        Read<'a, PhotonCountingMode>,
+        Read<'a, HistogramDeposition>,
+        Option<Read<'a, HistogramSnapshots>>,
+        Read<'a, FrameNumber>,
+        Write<'a, OutputError>,
+        Read<'a, WarmupSteps>,
+        Option<Read<'a, BackgroundRate>>,
+        ReadExpect<'a, Timestep>,
+        Read<'a, EmissionPattern>,
+    );
+    fn run(&mut self, (histogram, totals, positions, mode, deposition, snapshots, frame, mut error, warmup, background, timestep, pattern): Self::SystemData) {
+        use rayon::prelude::*;
+
+        if warmup.is_warming_up(*frame) {
+            return;
+        }
+
+        if *deposition == HistogramDeposition::Weighted {
+            (&totals, &positions).par_join().for_each(|(total, position)| {
+                let expected: f64 = total.contents.iter().map(|a| a.scattered).sum();
+                histogram.count_weighted(position.pos, expected);
+            });
+        } else {
+            // Generate photons scattered by each atom in the system.
+            (&totals, &positions).par_join().for_each(|(total, position)| {
+                let mut rng = rand::thread_rng();
+                let number: u32 = total.contents.iter().map(|a| mode.sample(a.scattered, &mut rng)).sum();
+                for _ in 0..number {
+                    // sow positions into the histogram
+                    if histogram.has_flux() {
+                        histogram.count_directed(position.pos, pattern.sample(&mut rng));
+                    } else {
+                        histogram.count(position.pos);
+                    }
+                }
+            });
+        }
+
+        if let Some(background) = background {
+            let mut rng = rand::thread_rng();
+            let number = background.sample(histogram.cells.len(), timestep.delta, &mut rng);
+            histogram.add_background(number, &mut rng);
+        }
+
+        if let Some(snapshots) = snapshots {
+            // The frame number is incremented after each dispatch, so this frame is not yet included.
+            let frames = frame.0 + 1;
+            if snapshots.interval > 0 && frames % snapshots.interval == 0 {
+                error.record(histogram.write_to_h5(snapshots.file_name(frames)));
+                if snapshots.mode == SnapshotMode::Interval {
+                    histogram.reset();
+                }
+            }
+        }
+    }
+}
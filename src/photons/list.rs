@@ -1,36 +1,340 @@
 //! Yet another implementation of a way to gather photons.
 //! This one builds a vec of photon records in parallel each timestep, and stores the result in memory.
 
-use hdf5::{File, H5Type, SimpleExtents, SliceOrIndex};
-use atomecs::{atom::{Position, Velocity, Atom}, laser_cooling::photons_scattered::ActualPhotonsScatteredVector, initiate::NewlyCreated};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hdf5::{Dataset, Error, File, H5Type, SimpleExtents, SliceOrIndex};
+use hdf5::types::{FloatSize, TypeDescriptor, VarLenUnicode};
+use atomecs::{atom::{Position, Velocity, Atom, AtomicTransition, Mass}, laser_cooling::photons_scattered::ActualPhotonsScatteredVector, initiate::NewlyCreated};
+use atomecs::constant::{AMU, C, HBAR};
 use nalgebra::Vector3;
-use rand_distr::{UnitSphere, Distribution};
+use serde::Deserialize;
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
 use specs::prelude::*;
-use ndarray::arr1;
+use ndarray::{arr1, ArrayView, Dimension};
+
+use super::{DetectionEfficiency, DroppedPhotons, FrameNumber, MaxScatteredPerFrame, PhotonCounter, PhotonCountingMode, WarmupSteps};
+use super::emission::{EmissionPattern, EmissionStatistics};
+use super::reabsorption::{Absorption, DensityGrid, GridAtom, Reabsorption};
+use super::recoil::EmissionRecoil;
+use super::tracer::TrajectoryRecord;
+
+/// Maximum number of photon records converted and written to the h5 file at once.
+pub const WRITE_BATCH_SIZE: usize = 100_000;
+
+/// Number of photon records per chunk of the photons dataset, unless set by [PhotonOutputterOptions::chunk].
+///
+/// A chunk of 4096 records (~200 kB for the basic layout) fits in the default chunk cache.
+pub const DEFAULT_PHOTON_CHUNK: usize = 4096;
+
+/// Value of the 'origin' attribute which identifies h5 files created by the [PhotonOutputter].
+pub const OUTPUT_ORIGIN: &str = "imaging_diffusion";
 
 /// Represents emission of a photon
 #[derive(Clone, Copy)]
 pub struct PhotonEmission {
     pub position: Vector3<f64>,
-    pub direction: Vector3<f64>
+    pub direction: Vector3<f64>,
+    /// Frequency of the emitted photon in Hz, including the Doppler shift from the emitting atom's motion and the natural linewidth.
+    pub frequency: Option<f64>,
+    /// Index of the frame in which the photon was emitted.
+    pub frame: u32,
+    /// Index of the entry of the emitting atom's `ActualPhotonsScatteredVector` from which the photon was scattered.
+    ///
+    /// atomecs stores the photons scattered from each cooling light separately, so this is the index of the beam
+    /// which drove the scattering. Beams may be tuned to different transitions, e.g. on a multi-line species.
+    /// The cooling lights are indexed in the order their entities were created, which for
+    /// [crate::simulation::create_beams] is the order of the beams in the config.
+    ///
+    /// The attribution is exact within the rate equation model of atomecs: the scattering rate from each beam is
+    /// calculated from its own intensity and detuning at the atom, with saturation shared between all beams. The beams
+    /// are treated as incoherent, so interference between overlapping beams, and coherent processes which transfer
+    /// photons from one beam to another, are neglected.
+    pub source: u32,
+    /// Index of the emitting atom, if [RecordPhotonAtoms] is enabled. See [AtomIndex].
+    pub atom: Option<u64>,
+}
+impl PhotonEmission {
+    /// Doppler-shifted frequency of a photon emitted along `direction` by an atom moving with `velocity`.
+    pub fn doppler_shifted_frequency(transition: &AtomicTransition, velocity: &Vector3<f64>, direction: &Vector3<f64>) -> f64 {
+        transition.frequency * (1.0 + velocity.dot(direction) / C)
+    }
+
+    /// Samples the frequency of a photon emitted along `direction` by an atom moving with `velocity`.
+    ///
+    /// The frequency is drawn from a Lorentzian with a full width at half maximum equal to the natural linewidth
+    /// of the transition, centered on the Doppler-shifted transition frequency. The Lorentzian is sampled
+    /// by inverting its cumulative distribution, `f = f_0 + (Γ/2) tan(π(u - 1/2))` for `u` uniform in [0,1).
+    pub fn sample_frequency<R: Rng + ?Sized>(transition: &AtomicTransition, velocity: &Vector3<f64>, direction: &Vector3<f64>, rng: &mut R) -> f64 {
+        let center = Self::doppler_shifted_frequency(transition, velocity, direction);
+        let u: f64 = rng.gen();
+        center + transition.linewidth / 2.0 * (std::f64::consts::PI * (u - 0.5)).tan()
+    }
 }
 
+/// A photon in the photons dataset: the position of emission `(px, py, pz)` in units of m, and the unit direction
+/// of emission `(dx, dy, dz)`.
+///
+/// The field names are stored in the compound datatype of the dataset, so they are shown by tools such as `h5dump`.
 #[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
 #[repr(C)]
-struct PhotonRecord(f64, f64, f64, f64, f64, f64);
+struct PhotonRecord {
+    px: f64,
+    py: f64,
+    pz: f64,
+    dx: f64,
+    dy: f64,
+    dz: f64,
+}
 impl PhotonRecord {
     fn new(p: &PhotonEmission) -> Self {
         PhotonRecord {
-            0: p.position[0], 
-            1: p.position[1],
-            2: p.position[2],
-            3: p.direction[0],
-            4: p.direction[1],
-            5: p.direction[2],
+            px: p.position[0],
+            py: p.position[1],
+            pz: p.position[2],
+            dx: p.direction[0],
+            dy: p.direction[1],
+            dz: p.direction[2],
+        }
+    }
+
+    fn emission(&self) -> PhotonEmission {
+        PhotonEmission {
+            position: Vector3::new(self.px, self.py, self.pz),
+            direction: Vector3::new(self.dx, self.dy, self.dz),
+            frequency: None,
+            frame: 0,
+            source: 0,
+            atom: None,
         }
     }
 }
 
+/// A [PhotonRecord] which additionally stores the photon `frequency`, the `frame` of emission, the [PhotonEmission::source]
+/// and the [PhotonEmission::atom]. Photons without an atom index have `u64::MAX` in the atom column.
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+struct ExtendedPhotonRecord {
+    px: f64,
+    py: f64,
+    pz: f64,
+    dx: f64,
+    dy: f64,
+    dz: f64,
+    frequency: f64,
+    frame: u32,
+    source: u32,
+    atom: u64,
+}
+impl ExtendedPhotonRecord {
+    fn new(p: &PhotonEmission) -> Self {
+        ExtendedPhotonRecord {
+            px: p.position[0],
+            py: p.position[1],
+            pz: p.position[2],
+            dx: p.direction[0],
+            dy: p.direction[1],
+            dz: p.direction[2],
+            frequency: p.frequency.unwrap_or(f64::NAN),
+            frame: p.frame,
+            source: p.source,
+            atom: p.atom.unwrap_or(u64::MAX),
+        }
+    }
+
+    fn emission(&self) -> PhotonEmission {
+        PhotonEmission {
+            position: Vector3::new(self.px, self.py, self.pz),
+            direction: Vector3::new(self.dx, self.dy, self.dz),
+            frequency: Some(self.frequency).filter(|f| !f.is_nan()),
+            frame: self.frame,
+            source: self.source,
+            atom: Some(self.atom).filter(|&atom| atom != u64::MAX),
+        }
+    }
+}
+
+/// The precision of the floating point position and direction columns of the photons dataset.
+///
+/// Single precision halves the size of the basic layout, from 48 to 24 bytes per photon, and reduces the extended
+/// layout from 72 to 48 bytes per photon. An f32 resolves positions to ~1e-7 of their magnitude, e.g. 0.1 nm for a
+/// 1 mm field of view, which is far below the resolution of any image. The frequency is always stored as f64, as the
+/// Doppler shifts of interest (~MHz) are below the resolution of an f32 at optical frequencies.
+///
+/// The precision is written as the `precision` attribute of the photons dataset, either "f64" or "f32".
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum PhotonPrecision {
+    Double,
+    Single,
+}
+impl Default for PhotonPrecision {
+    fn default() -> Self {
+        PhotonPrecision::Double
+    }
+}
+impl PhotonPrecision {
+    /// Value of the `precision` attribute of the photons dataset.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PhotonPrecision::Double => "f64",
+            PhotonPrecision::Single => "f32",
+        }
+    }
+}
+
+/// A [PhotonRecord] with single precision position and direction, see [PhotonPrecision].
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+struct SinglePhotonRecord {
+    px: f32,
+    py: f32,
+    pz: f32,
+    dx: f32,
+    dy: f32,
+    dz: f32,
+}
+impl SinglePhotonRecord {
+    fn new(p: &PhotonEmission) -> Self {
+        SinglePhotonRecord {
+            px: p.position[0] as f32,
+            py: p.position[1] as f32,
+            pz: p.position[2] as f32,
+            dx: p.direction[0] as f32,
+            dy: p.direction[1] as f32,
+            dz: p.direction[2] as f32,
+        }
+    }
+
+    fn emission(&self) -> PhotonEmission {
+        PhotonRecord {
+            px: self.px as f64, py: self.py as f64, pz: self.pz as f64,
+            dx: self.dx as f64, dy: self.dy as f64, dz: self.dz as f64,
+        }.emission()
+    }
+}
+
+/// An [ExtendedPhotonRecord] with single precision position and direction, see [PhotonPrecision].
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+struct SingleExtendedPhotonRecord {
+    px: f32,
+    py: f32,
+    pz: f32,
+    dx: f32,
+    dy: f32,
+    dz: f32,
+    frequency: f64,
+    frame: u32,
+    source: u32,
+    atom: u64,
+}
+impl SingleExtendedPhotonRecord {
+    fn new(p: &PhotonEmission) -> Self {
+        let record = ExtendedPhotonRecord::new(p);
+        SingleExtendedPhotonRecord {
+            px: record.px as f32,
+            py: record.py as f32,
+            pz: record.pz as f32,
+            dx: record.dx as f32,
+            dy: record.dy as f32,
+            dz: record.dz as f32,
+            frequency: record.frequency,
+            frame: record.frame,
+            source: record.source,
+            atom: record.atom,
+        }
+    }
+
+    fn emission(&self) -> PhotonEmission {
+        ExtendedPhotonRecord {
+            px: self.px as f64, py: self.py as f64, pz: self.pz as f64,
+            dx: self.dx as f64, dy: self.dy as f64, dz: self.dz as f64,
+            frequency: self.frequency, frame: self.frame, source: self.source, atom: self.atom,
+        }.emission()
+    }
+}
+
+/// The [PhotonRecord] written by earlier versions, whose compound fields are numbered `0`-`5` rather than named.
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+struct LegacyPhotonRecord(f64, f64, f64, f64, f64, f64);
+impl LegacyPhotonRecord {
+    fn emission(&self) -> PhotonEmission {
+        PhotonRecord { px: self.0, py: self.1, pz: self.2, dx: self.3, dy: self.4, dz: self.5 }.emission()
+    }
+}
+
+/// The [ExtendedPhotonRecord] written by earlier versions, whose compound fields are numbered `0`-`9` rather than named.
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+struct LegacyExtendedPhotonRecord(f64, f64, f64, f64, f64, f64, f64, u32, u32, u64);
+impl LegacyExtendedPhotonRecord {
+    fn emission(&self) -> PhotonEmission {
+        ExtendedPhotonRecord {
+            px: self.0, py: self.1, pz: self.2, dx: self.3, dy: self.4, dz: self.5,
+            frequency: self.6, frame: self.7, source: self.8, atom: self.9,
+        }.emission()
+    }
+}
+
+/// The layout of a photons dataset written by a [PhotonOutputter].
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct PhotonLayout {
+    /// True for the [ExtendedPhotonRecord] layout.
+    extended: bool,
+    /// True for the numbered fields written by earlier versions.
+    legacy: bool,
+    /// Precision of the position and direction fields.
+    precision: PhotonPrecision,
+}
+impl PhotonLayout {
+    fn of(dataset: &hdf5::Dataset) -> Result<Self, Error> {
+        let compound = match dataset.dtype()?.to_descriptor()? {
+            TypeDescriptor::Compound(compound) => compound,
+            descriptor => return Err(Error::from(format!(
+                "The '{}' dataset has an unrecognised type {:?}.", dataset.name(), descriptor
+            ))),
+        };
+        let extended = match compound.fields.len() {
+            6 => false,
+            10 => true,
+            n => return Err(Error::from(format!(
+                "The '{}' dataset has {} fields, but photon records have 6 or 10.", dataset.name(), n
+            ))),
+        };
+        let legacy = compound.fields.first().map_or(false, |field| field.name == "0");
+        let precision = match compound.fields.first().map(|field| &field.ty) {
+            Some(TypeDescriptor::Float(FloatSize::U4)) => PhotonPrecision::Single,
+            _ => PhotonPrecision::Double,
+        };
+        Ok(PhotonLayout { extended, legacy, precision })
+    }
+}
+
+/// Reads the photons of a dataset written by a [PhotonOutputter], e.g. 'photons' or 'photons_run2'.
+///
+/// Both the basic and extended layouts are accepted, in either [PhotonPrecision], with either named fields or the
+/// numbered fields of files written by earlier versions. The basic layout does not store the frequency, frame, source or atom of the photons, so
+/// these are `None`, 0, 0 and `None`.
+pub fn read_photons_h5(filename: &str, dataset: &str) -> Result<Vec<PhotonEmission>, Error> {
+    let file = File::open(filename)?;
+    let dataset = file.dataset(dataset)?;
+    let layout = PhotonLayout::of(&dataset)?;
+    let photons = match (layout.extended, layout.legacy, layout.precision) {
+        (false, false, PhotonPrecision::Double) => dataset.read_raw::<PhotonRecord>()?.iter().map(PhotonRecord::emission).collect(),
+        (true, false, PhotonPrecision::Double) => dataset.read_raw::<ExtendedPhotonRecord>()?.iter().map(ExtendedPhotonRecord::emission).collect(),
+        (false, false, PhotonPrecision::Single) => dataset.read_raw::<SinglePhotonRecord>()?.iter().map(SinglePhotonRecord::emission).collect(),
+        (true, false, PhotonPrecision::Single) => dataset.read_raw::<SingleExtendedPhotonRecord>()?.iter().map(SingleExtendedPhotonRecord::emission).collect(),
+        // Earlier versions only wrote double precision.
+        (false, true, _) => dataset.read_raw::<LegacyPhotonRecord>()?.iter().map(LegacyPhotonRecord::emission).collect(),
+        (true, true, _) => dataset.read_raw::<LegacyExtendedPhotonRecord>()?.iter().map(LegacyExtendedPhotonRecord::emission).collect(),
+    };
+    Ok(photons)
+}
+
 #[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
 #[repr(C)]
 pub struct InitialAtomPositionRecord(pub f64, pub f64, pub f64, pub f64, pub f64, pub f64);
@@ -40,90 +344,973 @@ impl InitialAtomPositionRecord {
     }
 }
 
+/// A destination for the photons generated by the simulation.
+pub trait PhotonSink: Send + Sync + 'static {
+    /// Appends photons emitted in a frame to the sink.
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), Error>;
+
+    /// Total number of photons appended to the sink so far.
+    fn photons_written(&self) -> u64;
+
+    /// Whether the sink uses the [PhotonEmission::frequency] of the photons appended to it. Sampling the frequency
+    /// costs a random draw per photon, so it is left as `None` for sinks which discard it. Defaults to true.
+    fn records_frequency(&self) -> bool {
+        true
+    }
+}
+
+/// A shared sink, so that it can be inspected while, or after, it is used by a [PhotonBroadcast].
+impl<S: PhotonSink> PhotonSink for Arc<S> {
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), Error> {
+        (**self).append_photons(photons)
+    }
+
+    fn photons_written(&self) -> u64 {
+        (**self).photons_written()
+    }
+
+    fn records_frequency(&self) -> bool {
+        (**self).records_frequency()
+    }
+}
+
+/// A [PhotonSink] which appends the photons of each frame to several sinks, e.g. both a [PhotonOutputter] for
+/// archival and a histogram for a quick look.
+///
+/// The photons are sampled once by the [PhotonBroadcastSystem], so every sink receives the same photons. Wrap a
+/// sink in an [Arc] to keep a handle to it.
+#[derive(Default)]
+pub struct PhotonBroadcast {
+    sinks: Vec<Box<dyn PhotonSink>>,
+    photons_written: AtomicU64,
+}
+impl PhotonBroadcast {
+    /// Create a [PhotonBroadcast] with no sinks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sink, which receives every photon appended from now on.
+    pub fn with_sink<S: PhotonSink>(mut self, sink: S) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Number of sinks the photons are appended to.
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    /// Returns true if there are no sinks.
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+impl PhotonSink for PhotonBroadcast {
+    /// Appends the photons to each sink in turn.
+    ///
+    /// The photons are appended to every sink even if one fails, so that a failing sink does not starve the others.
+    /// Returns the first error.
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), Error> {
+        let mut result = Ok(());
+        for sink in self.sinks.iter() {
+            let appended = sink.append_photons(photons);
+            if result.is_ok() {
+                result = appended;
+            }
+        }
+        self.photons_written.fetch_add(photons.len() as u64, Ordering::SeqCst);
+        result
+    }
+
+    fn photons_written(&self) -> u64 {
+        self.photons_written.load(Ordering::SeqCst)
+    }
+
+    fn records_frequency(&self) -> bool {
+        self.sinks.iter().any(|sink| sink.records_frequency())
+    }
+}
+
+/// A [PhotonSink] which also records the initial and final states of the atoms.
+pub trait PhotonOutput: PhotonSink {
+    /// Writes the initial positions and velocities of newly created atoms to the output.
+    fn write_initial_atom_positions(&self, records: Vec<InitialAtomPositionRecord>) -> Result<(), Error>;
+
+    /// Writes the final positions and velocities of atoms to the output, at the end of the simulation.
+    fn write_final_atom_positions(&self, records: Vec<InitialAtomPositionRecord>) -> Result<(), Error>;
+
+    /// Appends records to the trajectory of the tracer atom with the given index, see [super::tracer::TracerAtom].
+    fn append_trajectory(&self, atom: u64, records: &[TrajectoryRecord]) -> Result<(), Error>;
+
+    /// Writes `data` to the diagnostic dataset `name`, e.g. a summary written at the end of the run. Returns the
+    /// dataset, so that attributes can be added to it, or `None` if the output discards diagnostics.
+    fn write_diagnostic<'d, D: H5Type, Dim: Dimension>(&self, name: &str, data: ArrayView<'d, D, Dim>) -> Result<Option<Dataset>, Error>;
+}
+impl<S: PhotonOutput> PhotonOutput for Arc<S> {
+    fn write_initial_atom_positions(&self, records: Vec<InitialAtomPositionRecord>) -> Result<(), Error> {
+        (**self).write_initial_atom_positions(records)
+    }
+
+    fn write_final_atom_positions(&self, records: Vec<InitialAtomPositionRecord>) -> Result<(), Error> {
+        (**self).write_final_atom_positions(records)
+    }
+
+    fn append_trajectory(&self, atom: u64, records: &[TrajectoryRecord]) -> Result<(), Error> {
+        (**self).append_trajectory(atom, records)
+    }
+
+    fn write_diagnostic<'d, D: H5Type, Dim: Dimension>(&self, name: &str, data: ArrayView<'d, D, Dim>) -> Result<Option<Dataset>, Error> {
+        (**self).write_diagnostic(name, data)
+    }
+}
+
+/// Options controlling how the [PhotonOutputter] creates the photons dataset.
+///
+/// The photons dataset is chunked, and each call to [PhotonSink::append_photons] resizes it and writes the new
+/// photons in batches of [WRITE_BATCH_SIZE]. A write which only partly covers a chunk requires that chunk to be read
+/// back, so chunks should be small compared to the photons appended per frame, or the chunk cache should be large
+/// enough to hold the partly-filled final chunk between frames. Larger chunks reduce the per-chunk overhead and
+/// compress better, but use more memory in the cache.
+#[derive(Clone, Debug)]
+pub struct PhotonOutputterOptions {
+    /// If true, the photons dataset uses the extended layout, see [PhotonOutputter::new_extended].
+    pub extended: bool,
+    /// Precision of the position and direction columns of the photons dataset.
+    pub precision: PhotonPrecision,
+    /// Level (0-9) of gzip compression applied to the photons dataset, or `None` for no compression.
+    ///
+    /// Photon positions are repeated for every photon emitted by an atom in a frame, which compresses well,
+    /// but the directions are effectively random floats and compress poorly. Compression costs CPU time on
+    /// every append; low levels (1-3) give most of the size reduction for the least time.
+    pub compression: Option<u8>,
+    /// Number of photon records per chunk, or `None` for [DEFAULT_PHOTON_CHUNK].
+    pub chunk: Option<usize>,
+    /// Number of slots in the chunk cache hash table. This should be a prime number, and around 100 times the
+    /// number of chunks which fit in the cache.
+    pub cache_slots: usize,
+    /// Size of the chunk cache in bytes, or `None` for a cache of `cache_slots` photon records.
+    pub cache_bytes: Option<usize>,
+    /// Preemption policy (0-1) of the chunk cache. 1 evicts fully read or written chunks first, which suits the
+    /// append-only writes of the photons dataset.
+    pub cache_preemption: f64,
+}
+impl Default for PhotonOutputterOptions {
+    fn default() -> Self {
+        PhotonOutputterOptions {
+            extended: false,
+            precision: PhotonPrecision::Double,
+            compression: None,
+            chunk: None,
+            cache_slots: 10_000,
+            cache_bytes: None,
+            cache_preemption: 1.0,
+        }
+    }
+}
+
+/// The optional columns of the photons dataset, beyond the position and direction of each photon.
+///
+/// If any column is enabled, the photons are written with the extended layout, see [PhotonOutputter::new_extended].
+/// The extended layout stores every column, so disabled columns hold fill values: NaN for the frequency, 0 for the
+/// frame and source, and `u64::MAX` for the atom. The enabled columns are listed in the `fields` attribute of the
+/// photons dataset.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct PhotonFields {
+    /// The [PhotonEmission::frequency].
+    pub frequency: bool,
+    /// The [PhotonEmission::frame].
+    pub frame: bool,
+    /// The [PhotonEmission::source], i.e. the index of the beam.
+    pub source: bool,
+    /// The [PhotonEmission::atom], which requires [RecordPhotonAtoms].
+    pub atom: bool,
+}
+impl PhotonFields {
+    /// All of the optional columns, as written by [PhotonOutputter::new_extended].
+    pub fn all() -> Self {
+        PhotonFields { frequency: true, frame: true, source: true, atom: true }
+    }
+
+    /// True if any of the optional columns are enabled, which requires the extended layout.
+    pub fn any(&self) -> bool {
+        self.frequency || self.frame || self.source || self.atom
+    }
+
+    /// Names of the enabled columns, as written to the `fields` attribute.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = vec!["px", "py", "pz", "dx", "dy", "dz"];
+        let optional = [("frequency", self.frequency), ("frame", self.frame), ("source", self.source), ("atom", self.atom)];
+        names.extend(optional.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name));
+        names
+    }
+
+    /// Returns an error if the photon lacks a value for an enabled column.
+    fn check(&self, photon: &PhotonEmission) -> Result<(), Error> {
+        if self.frequency && photon.frequency.is_none() {
+            return Err(Error::from("The frequency column is enabled, but a photon has no frequency.".to_string()));
+        }
+        if self.atom && photon.atom.is_none() {
+            return Err(Error::from(
+                "The atom column is enabled, but a photon has no atom index. Enable RecordPhotonAtoms.".to_string()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Configures which columns the [PhotonOutputter] writes, and how the photons dataset is stored.
+///
+/// ```ignore
+/// let outputter = PhotonOutputter::builder("output.h5")
+///     .frame(true)
+///     .source(true)
+///     .build()?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct PhotonOutputterBuilder {
+    filename: String,
+    fields: PhotonFields,
+    options: PhotonOutputterOptions,
+}
+impl PhotonOutputterBuilder {
+    /// Whether to write the [PhotonEmission::frequency] of each photon.
+    pub fn frequency(mut self, enabled: bool) -> Self {
+        self.fields.frequency = enabled;
+        self
+    }
+
+    /// Whether to write the [PhotonEmission::frame] of each photon.
+    pub fn frame(mut self, enabled: bool) -> Self {
+        self.fields.frame = enabled;
+        self
+    }
+
+    /// Whether to write the [PhotonEmission::source] of each photon.
+    pub fn source(mut self, enabled: bool) -> Self {
+        self.fields.source = enabled;
+        self
+    }
+
+    /// Whether to write the [PhotonEmission::atom] of each photon.
+    pub fn atom(mut self, enabled: bool) -> Self {
+        self.fields.atom = enabled;
+        self
+    }
+
+    /// Set the storage options of the photons dataset. The [PhotonOutputterOptions::extended] flag is ignored, as the
+    /// layout is chosen from the enabled columns.
+    pub fn options(mut self, options: PhotonOutputterOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Create the output file.
+    pub fn build(self) -> Result<PhotonOutputter, Error> {
+        let options = PhotonOutputterOptions { extended: self.fields.any(), ..self.options };
+        let mut outputter = PhotonOutputter::with_options(self.filename, options)?;
+        outputter.set_fields(self.fields)?;
+        Ok(outputter)
+    }
+}
+
 /// Provides methods for writing photon and atom data to an h5 file.
+///
+/// A file may hold several runs, see [PhotonOutputter::open_append]. The datasets of the first run are named
+/// 'photons', 'atoms', etc, and those of later runs have a suffix, e.g. 'photons_run2'.
 pub struct PhotonOutputter {
-    pub file: File
+    pub file: File,
+    /// If true, the photons dataset uses the extended layout, with additional columns for the photon frequency, frame, source and atom.
+    pub extended: bool,
+    /// Precision of the position and direction columns of the photons dataset.
+    pub precision: PhotonPrecision,
+    /// The optional columns declared with the [PhotonOutputterBuilder], see [PhotonFields]. Photons are checked against
+    /// these before they are written. `None` if the outputter was created without a builder, in which case photons
+    /// are not checked and every column of the layout is written.
+    pub fields: Option<PhotonFields>,
+    /// Index of the run written by this outputter, starting from 1.
+    pub run: u32,
+    /// The photons dataset of this run, opened once so that each append does not look it up in the file again.
+    photons: Dataset,
+    photons_written: AtomicU64
 }
 impl PhotonOutputter {
-    pub fn new(filename: String) -> Self {
-        let file = File::create(filename).expect("Could not create file.");
+    /// Create a new [PhotonOutputter] which writes photons as (x,y,z,dx,dy,dz) records.
+    pub fn new(filename: String) -> Result<Self, Error> {
+        Self::with_options(filename, PhotonOutputterOptions::default())
+    }
+
+    /// Create a new [PhotonOutputter] which writes photons as (x,y,z,dx,dy,dz,f,frame,source,atom) records,
+    /// where f is the photon frequency, frame is the index of the frame in which the photon was emitted,
+    /// source is the [PhotonEmission::source] of the photon, and atom is the [PhotonEmission::atom].
+    pub fn new_extended(filename: String) -> Result<Self, Error> {
+        Self::with_options(filename, PhotonOutputterOptions { extended: true, ..Default::default() })
+    }
+
+    /// Create a [PhotonOutputterBuilder], to choose the columns written for each photon.
+    pub fn builder(filename: &str) -> PhotonOutputterBuilder {
+        PhotonOutputterBuilder { filename: filename.to_string(), fields: PhotonFields::default(), options: PhotonOutputterOptions::default() }
+    }
+
+    /// Create a new [PhotonOutputter] with the given options.
+    pub fn with_options(filename: String, options: PhotonOutputterOptions) -> Result<Self, Error> {
+        let file = File::create(filename)?;
+        let origin: VarLenUnicode = OUTPUT_ORIGIN.parse().map_err(|e| Error::from(format!("{:?}", e)))?;
+        file.new_attr_builder().with_data(&arr1(&[origin])).create("origin")?;
+        Self::create_layout(file, &options, 1)
+    }
+
+    /// Open an existing output file, and append a new run to it.
+    ///
+    /// The photons of the new run use the same layout and precision as those already in the file. Returns an error if the file
+    /// was not created by a [PhotonOutputter], or was written by an earlier version with numbered photon fields.
+    pub fn open_append(filename: String) -> Result<Self, Error> {
+        let file = File::open_rw(&filename)?;
+        let origin = file.attr("origin").and_then(|attr| attr.read_raw::<VarLenUnicode>()).map_err(
+            |_| Error::from(format!("{} has no 'origin' attribute, so was not created by imaging_diffusion.", filename))
+        )?;
+        if origin.first().map(|o| o.as_str()) != Some(OUTPUT_ORIGIN) {
+            return Err(Error::from(format!("{} has origin {:?}, but expected '{}'.", filename, origin, OUTPUT_ORIGIN)));
+        }
+        let layout = PhotonLayout::of(&file.dataset("photons")?)?;
+        if layout.legacy {
+            // Mixing the layouts in one file would give readers a different schema for each run.
+            return Err(Error::from(format!(
+                "{} was written by an earlier version with numbered photon fields, so cannot be appended to. \
+                 It can still be read with read_photons_h5.", filename
+            )));
+        }
+        let extended = layout.extended;
+        // Files created without a builder have no declared fields.
+        let fields = match file.dataset("photons")?.attr("fields") {
+            Ok(attr) => {
+                let names = attr.read_raw::<VarLenUnicode>()?;
+                let has = |name: &str| names.iter().any(|n| n.as_str() == name);
+                Some(PhotonFields { frequency: has("frequency"), frame: has("frame"), source: has("source"), atom: has("atom") })
+            }
+            Err(_) => None,
+        };
+        let run = 1 + file.member_names()?.iter()
+            .filter(|name| name.as_str() == "photons" || name.starts_with("photons_run"))
+            .count() as u32;
+        let options = PhotonOutputterOptions { extended, precision: layout.precision, ..Default::default() };
+        let mut outputter = Self::create_layout(file, &options, run)?;
+        if let Some(fields) = fields {
+            outputter.set_fields(fields)?;
+        }
+        Ok(outputter)
+    }
+
+    /// Sets the optional columns which hold values, and lists them in the `fields` attribute of the photons dataset.
+    fn set_fields(&mut self, fields: PhotonFields) -> Result<(), Error> {
+        let names = fields.names().iter()
+            .map(|name| name.parse::<VarLenUnicode>().map_err(|e| Error::from(format!("{:?}", e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.photons.new_attr_builder().with_data(&arr1(&names)).create("fields")?;
+        self.fields = Some(fields);
+        Ok(())
+    }
+
+    /// The photons dataset of the run written by this outputter.
+    pub fn photons_dataset(&self) -> &Dataset {
+        &self.photons
+    }
+
+    /// Name of the dataset `base` for the run written by this outputter.
+    pub fn dataset_name(&self, base: &str) -> String {
+        Self::run_dataset_name(base, self.run)
+    }
+
+    fn run_dataset_name(base: &str, run: u32) -> String {
+        if run == 1 {
+            base.to_string()
+        } else {
+            format!("{}_run{}", base, run)
+        }
+    }
+
+    /// Creates the datasets of a run, with the photon record type for the layout and precision of the options.
+    fn create_layout(file: File, options: &PhotonOutputterOptions, run: u32) -> Result<Self, Error> {
+        match (options.extended, options.precision) {
+            (false, PhotonPrecision::Double) => Self::create_datasets::<PhotonRecord>(file, options, run),
+            (true, PhotonPrecision::Double) => Self::create_datasets::<ExtendedPhotonRecord>(file, options, run),
+            (false, PhotonPrecision::Single) => Self::create_datasets::<SinglePhotonRecord>(file, options, run),
+            (true, PhotonPrecision::Single) => Self::create_datasets::<SingleExtendedPhotonRecord>(file, options, run),
+        }
+    }
+
+    fn create_datasets<T: H5Type>(file: File, options: &PhotonOutputterOptions, run: u32) -> Result<Self, Error> {
+        let mut builder = file.new_dataset_builder();
+        if let Some(level) = options.compression {
+            builder = builder.deflate(level);
+        }
+        builder = builder.chunk(options.chunk.unwrap_or(DEFAULT_PHOTON_CHUNK));
+        let cache_bytes = options.cache_bytes.unwrap_or(options.cache_slots * std::mem::size_of::<T>());
+        // The dataset starts empty, so that its length is the number of photons appended.
+        let se = SimpleExtents::new(&[(0,None)]);
+        let photons = builder.chunk_cache(options.cache_slots, cache_bytes, options.cache_preemption).empty::<T>().shape(se)
+            .create(Self::run_dataset_name("photons", run).as_str())?;
+        photons.new_attr_builder().with_data(&arr1(&[run])).create("run")?;
+        let precision: VarLenUnicode = options.precision.name().parse().map_err(|e| Error::from(format!("{:?}", e)))?;
+        photons.new_attr_builder().with_data(&arr1(&[precision])).create("precision")?;
         let builder = file.new_dataset_builder();
-        let se = SimpleExtents::new(&[(1,None)]);
-        builder.chunk_cache(10_000, 10_000*48, 1.0).empty::<PhotonRecord>().shape(se).create("photons").expect("Could not create dataset");
-        //file.new_attr_builder().with_data(VarLenAscii::from_ascii("atomecs")).create("origin").expect("Unable to create attribute");
-        PhotonOutputter { file }
+        let se = SimpleExtents::new(&[(0,None)]);
+        builder.chunk(WRITE_BATCH_SIZE).empty::<InitialAtomPositionRecord>().shape(se).create(Self::run_dataset_name("atoms", run).as_str())?;
+        let builder = file.new_dataset_builder();
+        let se = SimpleExtents::new(&[(0,None)]);
+        builder.chunk(WRITE_BATCH_SIZE).empty::<InitialAtomPositionRecord>().shape(se).create(Self::run_dataset_name("final_atoms", run).as_str())?;
+        Ok(PhotonOutputter { file, extended: options.extended, precision: options.precision, fields: None, run, photons, photons_written: AtomicU64::new(0) })
     }
 
-    pub fn append_photons(&self, photons: Vec<PhotonEmission>) {
-        // create records and append them to the dataset.
-        let n = photons.len();
-        let records: Vec<PhotonRecord> = photons.into_iter().map(|p| PhotonRecord::new(&p)).collect();
-        let dataset = self.file.dataset("photons").expect("Could not open dataset.");
+    /// Converts items to records and appends them to the end of the dataset, in batches of [WRITE_BATCH_SIZE].
+    ///
+    /// The length is read from the dataset on each call, so a handle kept across appends stays valid as the dataset is resized.
+    fn append_records<I, T, F>(dataset: &Dataset, items: &[I], to_record: F) -> Result<(), Error>
+    where
+        T: H5Type + Clone,
+        F: Fn(&I) -> T,
+    {
         let old_length = dataset.size();
-        let new_length = old_length + n;
+        let new_length = old_length + items.len();
         // resize to new length
-        dataset.resize(new_length).expect("Unable to resize dataset.");
-        // select a new slice at the end
-        dataset.write_slice(&arr1(records.as_slice()), SliceOrIndex::Unlimited{ start: old_length, step: 1, block: 1}).expect("Unable to write photons to file.");
+        dataset.resize(new_length)?;
+        // write each batch into the new slice at the end
+        for (i, batch) in items.chunks(WRITE_BATCH_SIZE).enumerate() {
+            let records: Vec<T> = batch.iter().map(&to_record).collect();
+            let start = old_length + i * WRITE_BATCH_SIZE;
+            dataset.write_slice(&arr1(records.as_slice()), SliceOrIndex::SliceCount{ start, step: 1, block: 1, count: records.len() })?;
+        }
+        Ok(())
+    }
+}
+impl PhotonSink for PhotonOutputter {
+    /// Appends photons to the photons dataset.
+    ///
+    /// The dataset is resized once, and the photons are then converted to records and written in batches of
+    /// [WRITE_BATCH_SIZE], so that the transient memory required is bounded independently of the number of photons.
+    /// Returns an error, without writing any photons, if a photon lacks a value for one of the enabled [PhotonFields].
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), Error> {
+        if let Some(fields) = &self.fields {
+            for photon in photons {
+                fields.check(photon)?;
+            }
+        }
+        // create records and append them to the dataset.
+        let dataset = &self.photons;
+        match (self.extended, self.precision) {
+            (false, PhotonPrecision::Double) => Self::append_records(dataset, photons, PhotonRecord::new)?,
+            (true, PhotonPrecision::Double) => Self::append_records(dataset, photons, ExtendedPhotonRecord::new)?,
+            (false, PhotonPrecision::Single) => Self::append_records(dataset, photons, SinglePhotonRecord::new)?,
+            (true, PhotonPrecision::Single) => Self::append_records(dataset, photons, SingleExtendedPhotonRecord::new)?,
+        }
+        self.photons_written.fetch_add(photons.len() as u64, Ordering::SeqCst);
+        Ok(())
     }
 
-    fn write_initial_atom_positions(&self, records: Vec<InitialAtomPositionRecord>) {
+    fn photons_written(&self) -> u64 {
+        self.photons_written.load(Ordering::SeqCst)
+    }
+
+    /// Only the extended layout has a frequency column, which may also be disabled with the [PhotonOutputterBuilder].
+    fn records_frequency(&self) -> bool {
+        self.extended && self.fields.map_or(true, |fields| fields.frequency)
+    }
+}
+impl PhotonOutput for PhotonOutputter {
+    /// Appends initial atom positions and velocities to the atoms dataset.
+    ///
+    /// This may be called multiple times, as new atoms are created.
+    fn write_initial_atom_positions(&self, records: Vec<InitialAtomPositionRecord>) -> Result<(), Error> {
         let n = records.len();
         println!("Writing {:?} initial atom positions and velocities to h5 file.", n);
-        let builder = self.file.new_dataset_builder();
-        builder.with_data(&arr1(records.as_slice())).create("atoms").expect("Could not create dataset");
+        Self::append_records(&self.file.dataset(&self.dataset_name("atoms"))?, &records, InitialAtomPositionRecord::clone)
+    }
+
+    /// Appends final atom positions and velocities to the final_atoms dataset.
+    fn write_final_atom_positions(&self, records: Vec<InitialAtomPositionRecord>) -> Result<(), Error> {
+        let n = records.len();
+        println!("Writing {:?} final atom positions and velocities to h5 file.", n);
+        Self::append_records(&self.file.dataset(&self.dataset_name("final_atoms"))?, &records, InitialAtomPositionRecord::clone)
+    }
+
+    /// Appends records to the 'trajectory_{atom}' dataset, which is created on the first append.
+    fn append_trajectory(&self, atom: u64, records: &[TrajectoryRecord]) -> Result<(), Error> {
+        let name = self.dataset_name(&format!("trajectory_{}", atom));
+        let dataset = if self.file.link_exists(&name) {
+            self.file.dataset(&name)?
+        } else {
+            let se = SimpleExtents::new(&[(0,None)]);
+            self.file.new_dataset_builder().chunk(TRAJECTORY_CHUNK).empty::<TrajectoryRecord>().shape(se).create(name.as_str())?
+        };
+        Self::append_records(&dataset, records, TrajectoryRecord::clone)
+    }
+
+    /// Creates the dataset `name` of this run, see [PhotonOutputter::dataset_name].
+    fn write_diagnostic<'d, D: H5Type, Dim: Dimension>(&self, name: &str, data: ArrayView<'d, D, Dim>) -> Result<Option<Dataset>, Error> {
+        Ok(Some(self.file.new_dataset_builder().with_data(data).create(self.dataset_name(name).as_str())?))
+    }
+}
+
+/// Number of records per chunk of each trajectory dataset, see [PhotonOutput::append_trajectory].
+const TRAJECTORY_CHUNK: usize = 1024;
+
+/// A [PhotonOutput] which discards all photons and atoms.
+///
+/// This allows the emission and recoil of photons to be simulated without the cost of writing them to disk,
+/// e.g. when only the final atom distribution is of interest, or for benchmarking the simulation core.
+#[derive(Default)]
+pub struct NullPhotonOutputter {
+    photons_written: AtomicU64
+}
+impl PhotonSink for NullPhotonOutputter {
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), Error> {
+        self.photons_written.fetch_add(photons.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn photons_written(&self) -> u64 {
+        self.photons_written.load(Ordering::SeqCst)
+    }
+
+    fn records_frequency(&self) -> bool {
+        false
+    }
+}
+impl PhotonOutput for NullPhotonOutputter {
+    fn write_initial_atom_positions(&self, _records: Vec<InitialAtomPositionRecord>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write_final_atom_positions(&self, _records: Vec<InitialAtomPositionRecord>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn append_trajectory(&self, _atom: u64, _records: &[TrajectoryRecord]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn write_diagnostic<'d, D: H5Type, Dim: Dimension>(&self, _name: &str, _data: ArrayView<'d, D, Dim>) -> Result<Option<Dataset>, Error> {
+        Ok(None)
+    }
+}
+
+/// A [PhotonSink] which keeps all photons in memory, e.g. to inspect the emitted photons in tests.
+#[derive(Default)]
+pub struct MemoryPhotonSink {
+    photons: Mutex<Vec<PhotonEmission>>,
+    /// Total number of photons appended, including any since removed by [MemoryPhotonSink::take].
+    photons_written: AtomicU64,
+}
+impl MemoryPhotonSink {
+    /// Get a copy of all photons appended so far.
+    pub fn photons(&self) -> Vec<PhotonEmission> {
+        self.photons.lock().expect("Photon sink lock poisoned.").clone()
+    }
+
+    /// Remove and return all photons appended so far. They are still counted by [PhotonSink::photons_written].
+    pub fn take(&self) -> Vec<PhotonEmission> {
+        std::mem::take(&mut *self.photons.lock().expect("Photon sink lock poisoned."))
+    }
+}
+impl PhotonSink for MemoryPhotonSink {
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), Error> {
+        self.photons.lock().map_err(|e| Error::from(e.to_string()))?.extend_from_slice(photons);
+        self.photons_written.fetch_add(photons.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn photons_written(&self) -> u64 {
+        self.photons_written.load(Ordering::SeqCst)
+    }
+}
+
+/// Stores the first error encountered by a system while writing output.
+///
+/// Systems cannot return errors, so they record them here instead of panicking.
+/// The main loop should check this resource after each dispatch and decide how to handle the failure.
+#[derive(Default)]
+pub struct OutputError(pub Option<String>);
+impl OutputError {
+    /// Record the error of a failed result, unless an earlier error has already been recorded.
+    pub fn record<T>(&mut self, result: Result<T, Error>) {
+        if let Err(error) = result {
+            if self.0.is_none() {
+                self.0 = Some(error.to_string());
+            }
+        }
+    }
+}
+
+/// An externally assigned index of an atom, e.g. its row in the input files.
+///
+/// Photons are tagged with this index when [RecordPhotonAtoms] is enabled. Atoms without an [AtomIndex] are tagged
+/// with the id of their entity instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AtomIndex(pub u64);
+impl Component for AtomIndex {
+    type Storage = VecStorage<Self>;
+}
+
+/// A random number generator owned by a single atom, used by [RegisterPhotonsSystem] to sample the photons it emits.
+///
+/// The generator of each atom is seeded from a global seed and the [AtomIndex] of the atom, see [seed_atoms], so the
+/// photons sampled for an atom do not depend on the order in which atoms are joined, nor on the thread scheduling.
+#[derive(Clone, Debug)]
+pub struct AtomRng(pub StdRng);
+impl AtomRng {
+    /// Create the generator of the atom with the given index, from the global `seed`.
+    pub fn new(seed: u64, index: u64) -> Self {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&seed.to_le_bytes());
+        bytes[8..16].copy_from_slice(&index.to_le_bytes());
+        AtomRng(StdRng::from_seed(bytes))
+    }
+}
+impl Component for AtomRng {
+    type Storage = VecStorage<Self>;
+}
+
+/// The global seed of the [AtomRng] of each atom, inserted by [seed_atoms].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AtomSeed(pub u64);
+
+/// Attaches an [AtomRng] to each atom, seeded from the global `seed` and the [AtomIndex] of the atom, or the id of
+/// its entity if it has no index. The seed is stored in the [AtomSeed] resource, so that the atoms can be reseeded.
+///
+/// Only the photons sampled by [RegisterPhotonsSystem] use the generator; the scattering fluctuations and emission
+/// force of atomecs still draw from per-thread generators, so they should be disabled for bit-identical trajectories.
+pub fn seed_atoms(world: &mut World, seed: u64) {
+    world.insert(AtomSeed(seed));
+    world.register::<AtomRng>();
+    world.register::<AtomIndex>();
+    let entities = world.entities();
+    let atoms = world.read_storage::<Atom>();
+    let indices = world.read_storage::<AtomIndex>();
+    let mut rngs = world.write_storage::<AtomRng>();
+    for (entity, _, index) in (&entities, &atoms, (&indices).maybe()).join() {
+        let index = index.map_or(entity.id() as u64, |index| index.0);
+        rngs.insert(entity, AtomRng::new(seed, index)).expect("Could not attach random number generator to atom.");
     }
 }
 
-pub struct RegisterPhotonsSystem;
-impl<'a> System<'a> for RegisterPhotonsSystem {
+/// Whether [RegisterPhotonsSystem] tags each photon with the index of the emitting atom. Defaults to false.
+///
+/// The index is only written to the output file by the extended layout, see [PhotonOutputter::new_extended].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecordPhotonAtoms(pub bool);
+
+/// A box outside of which emitted photons are not output, e.g. the field of view of the camera.
+///
+/// Photons emitted outside the region still apply a recoil to the emitting atom. With [Reabsorption], the photon is
+/// output if it escapes the cloud from an atom inside the region, wherever it was first emitted.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct RegionOfInterest {
+    /// Lower corner of the box, in units of m.
+    pub min: Vector3<f64>,
+    /// Upper corner of the box, in units of m.
+    pub max: Vector3<f64>,
+}
+impl RegionOfInterest {
+    /// Returns true if the position lies within the box.
+    pub fn contains(&self, position: &Vector3<f64>) -> bool {
+        (0..3).all(|i| position[i] >= self.min[i] && position[i] <= self.max[i])
+    }
+}
+
+/// This system generates the photons scattered by each atom in the frame, and appends them to the [PhotonSink] resource `T`.
+///
+/// Photons emitted by atoms with a non-finite position, outside the optional [RegionOfInterest], or which are lost
+/// according to the optional [DetectionEfficiency], are not output, and are tallied in the [DroppedPhotons] resource instead.
+/// Every sampled photon is counted in the [PhotonCounter] resource, whether or not it is output, and its direction is
+/// added to the [EmissionStatistics] resource, if present.
+/// If [RecordPhotonAtoms] is enabled, each photon is tagged with the [AtomIndex] of the emitting atom.
+/// The frequency of each photon is only sampled if the sink uses it, see [PhotonSink::records_frequency].
+/// Atoms with an [AtomRng] sample their photons from it, and other atoms from the per-thread generator.
+/// With a [Reabsorption] resource, each photon may be reabsorbed by other atoms of the cloud before it escapes. It is
+/// then output from the last atom to re-emit it, and the absorbing atoms receive the recoil of each absorption and
+/// re-emission. The recoil of the first emission is left to atomecs, as for photons which are not reabsorbed.
+/// The system does nothing during the [WarmupSteps], so those photons are neither output nor counted.
+/// A warning is printed, once, the first time an atom scatters more than [MaxScatteredPerFrame] photons in a frame.
+///
+/// Each rayon worker accumulates photons into its own list, rather than allocating a list per atom.
+/// The lists are gathered into a buffer which is kept between frames, so that its capacity tracks
+/// the number of photons emitted per frame and it is not reallocated each frame. The emptied lists are then returned
+/// to a pool, from which the workers of the next frame take them with their capacity intact. New lists are allocated
+/// with the number of photons per thread of the previous frame.
+pub struct RegisterPhotonsSystem<T: PhotonSink = PhotonOutputter> {
+    buffer: Vec<PhotonEmission>,
+    /// The photon lists of the workers, kept between frames.
+    pool: Mutex<Vec<Vec<PhotonEmission>>>,
+    /// Whether the [MaxScatteredPerFrame] warning has been printed.
+    warned: bool,
+    output: PhantomData<T>,
+}
+impl<T: PhotonSink> Default for RegisterPhotonsSystem<T> {
+    fn default() -> Self {
+        RegisterPhotonsSystem { buffer: Vec::new(), pool: Mutex::new(Vec::new()), warned: false, output: PhantomData }
+    }
+}
+impl<'a, T: PhotonSink> System<'a> for RegisterPhotonsSystem<T> {
     type SystemData = (
-        ReadExpect<'a, PhotonOutputter>,
+        ReadExpect<'a, T>,
+        Write<'a, OutputError>,
         ReadStorage<'a, ActualPhotonsScatteredVector>,
         ReadStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        ReadStorage<'a, AtomicTransition>,
+        Read<'a, PhotonCountingMode>,
+        Read<'a, EmissionPattern>,
+        Read<'a, FrameNumber>,
+        WriteStorage<'a, EmissionRecoil>,
+        Write<'a, DroppedPhotons>,
+        Read<'a, PhotonCounter>,
+        Option<Read<'a, RegionOfInterest>>,
+        Option<Read<'a, DetectionEfficiency>>,
+        Option<Write<'a, EmissionStatistics>>,
+        Entities<'a>,
+        ReadStorage<'a, AtomIndex>,
+        Read<'a, RecordPhotonAtoms>,
+        Read<'a, WarmupSteps>,
+        WriteStorage<'a, AtomRng>,
+        Read<'a, MaxScatteredPerFrame>,
+        Option<Read<'a, Reabsorption>>,
+        ReadStorage<'a, Mass>,
     );
-    fn run(&mut self, (output, totals, positions): Self::SystemData) {
+    fn run(&mut self, (output, mut error, totals, positions, mut velocities, transitions, mode, pattern, frame, mut recoils, mut dropped, counter, region, efficiency, mut statistics, entities, indices, record_atoms, warmup, mut rngs, limit, reabsorption, masses): Self::SystemData) {
         use rayon::prelude::*;
 
+        if warmup.is_warming_up(*frame) {
+            return;
+        }
+
+        let region = region.as_deref().copied();
+        let efficiency = efficiency.as_deref().copied();
+        let track_directions = statistics.is_some();
+        let record_frequency = output.records_frequency();
+
+        let record_atoms = record_atoms.0;
+        let atom_index = |entity: Entity, index: Option<&AtomIndex>| {
+            if record_atoms {
+                Some(index.map_or(entity.id() as u64, |index| index.0))
+            } else {
+                None
+            }
+        };
+
+        // The density of the cloud is binned once per frame, before any photons are reabsorbed.
+        let reabsorption = reabsorption.as_deref().map(|model| {
+            let atoms = (&entities, &positions, &transitions, (&indices).maybe()).join()
+                .map(|(entity, position, _, index)| GridAtom { entity, position: position.pos, index: atom_index(entity, index) });
+            (model, DensityGrid::new(model.cell_size, atoms))
+        });
+
+        let capacity = self.buffer.len() / rayon::current_num_threads();
+        let pool = &self.pool;
+        let new_list = || pool.lock().ok().and_then(|mut pool| pool.pop()).unwrap_or_else(|| Vec::with_capacity(capacity));
+
         // Generate photons scattered by each atom in the system.
-        let photons: Vec<PhotonEmission> = (&totals, &positions).par_join().map(
-            |(total, position)| {
-            let mut rng = rand::thread_rng();
-            let number = total.contents.iter().map(|a| a.scattered.round() as u32).sum();
-            let mut list = Vec::<PhotonEmission>::new();
-            for _i in 0..number {
-                let v: [f64; 3] = UnitSphere.sample(&mut rng);
-                list.push(PhotonEmission {
-                    position: position.pos,
-                    direction: Vector3::new(v[0], v[1], v[2])
-                });
+        let workers: Vec<WorkerPhotons> = (&entities, &totals, &positions, &velocities, &transitions, (&mut recoils).maybe(), (&indices).maybe(), (&mut rngs).maybe()).par_join().fold(
+            || WorkerPhotons { photons: new_list(), directions: track_directions.then(EmissionStatistics::default), ..WorkerPhotons::default() },
+            |mut worker, (entity, total, position, velocity, transition, mut recoil, index, atom_rng)| {
+            let mut thread_rng = rand::thread_rng();
+            let mut rng: &mut dyn RngCore = match atom_rng {
+                Some(atom_rng) => &mut atom_rng.0,
+                None => &mut thread_rng,
             };
-            list
-        }).flatten().collect();
-        output.append_photons(photons);
+            let atom = atom_index(entity, index);
+            worker.max_scattered = worker.max_scattered.max(total.contents.iter().map(|s| s.scattered).sum::<f64>());
+            let finite = position.pos.iter().all(|x| x.is_finite());
+            // The photons scattered from each beam are emitted separately, so that each is tagged with its source.
+            for (source, scattered) in total.contents.iter().enumerate() {
+                let number = mode.sample(scattered.scattered, &mut rng);
+                worker.emitted += number as u64;
+                if !finite {
+                    worker.dropped.non_finite += number as u64;
+                    continue;
+                }
+                for _i in 0..number {
+                    let direction = pattern.sample(&mut rng);
+                    if let Some(recoil) = recoil.as_mut() {
+                        recoil.add(transition, &direction);
+                    }
+                    if let Some(directions) = worker.directions.as_mut() {
+                        directions.add(&direction);
+                    }
+                    // Photons are reabsorbed within the cloud, so whether or not they would be detected.
+                    let (atom, emitter, emission, direction) = match &reabsorption {
+                        Some((model, grid)) => {
+                            let emitter = GridAtom { entity, position: position.pos, index: atom };
+                            let (emitter, emission, direction) = model.scatter(grid, &pattern, emitter, position.pos, direction, &mut worker.absorptions, &mut rng);
+                            (emitter.index, emitter.entity, emission, direction)
+                        }
+                        None => (atom, entity, position.pos, direction),
+                    };
+                    if !region.map_or(true, |region| region.contains(&emission)) {
+                        worker.dropped.outside_region += 1;
+                        continue;
+                    }
+                    if let Some(efficiency) = efficiency {
+                        if !efficiency.detects(&mut rng) {
+                            worker.dropped.undetected += 1;
+                            continue;
+                        }
+                    }
+                    debug_assert!((direction.norm() - 1.0).abs() < 1e-9, "Photon direction {:?} is not a unit vector.", direction);
+                    worker.photons.push(PhotonEmission {
+                        position: emission,
+                        direction,
+                        frequency: if record_frequency {
+                            // The photon is Doppler shifted by the atom which re-emitted it last.
+                            let velocity = velocities.get(emitter).unwrap_or(velocity);
+                            let transition = transitions.get(emitter).unwrap_or(transition);
+                            Some(PhotonEmission::sample_frequency(transition, &velocity.vel, &direction, &mut rng))
+                        } else {
+                            None
+                        },
+                        frame: frame.0,
+                        source: source as u32,
+                        atom,
+                    });
+                }
+            }
+            worker
+        }).collect();
+
+        let max_scattered = workers.iter().map(|worker| worker.max_scattered).fold(0.0, f64::max);
+        if !self.warned && max_scattered > limit.0 {
+            println!(
+                "Warning: an atom scattered {:.2} photons in frame {}, more than the limit of {}. \
+                 Reduce the timestep or the beam power, so that each atom scatters ~0-1 photons per frame.",
+                max_scattered, frame.0, limit.0
+            );
+            self.warned = true;
+        }
+
+        self.buffer.clear();
+        self.buffer.reserve(workers.iter().map(|worker| worker.photons.len()).sum());
+        for mut worker in workers {
+            counter.add(worker.emitted);
+            self.buffer.append(&mut worker.photons);
+            self.pool.get_mut().expect("Photon list pool lock poisoned.").push(worker.photons);
+            dropped.non_finite += worker.dropped.non_finite;
+            dropped.outside_region += worker.dropped.outside_region;
+            dropped.undetected += worker.dropped.undetected;
+            if let (Some(statistics), Some(directions)) = (statistics.as_mut(), worker.directions.as_ref()) {
+                statistics.merge(directions);
+            }
+            // Each reabsorbed photon pushes the absorbing atom along its arrival, and recoils it from its re-emission.
+            for absorption in worker.absorptions {
+                if let (Some(velocity), Some(transition), Some(mass)) =
+                    (velocities.get_mut(absorption.entity), transitions.get(absorption.entity), masses.get(absorption.entity)) {
+                    let hbar_k = HBAR * 2.0 * std::f64::consts::PI * transition.frequency / C;
+                    velocity.vel += hbar_k / (mass.value * AMU) * (absorption.absorbed - absorption.emitted);
+                }
+            }
+        }
+        error.record(output.append_photons(&self.buffer));
     }
 }
 
-/// This system gets the initial positions and velocities of atoms immediately after creation, and stores them in the h5 output file.
-pub struct RegisterInitialAtomsSystem;
-impl<'a> System<'a> for RegisterInitialAtomsSystem {
+/// The photons generated by a single rayon worker during a frame.
+#[derive(Default)]
+struct WorkerPhotons {
+    photons: Vec<PhotonEmission>,
+    dropped: DroppedPhotons,
+    emitted: u64,
+    /// The largest expected number of photons scattered by an atom of the worker in the frame.
+    max_scattered: f64,
+    /// The atoms which reabsorbed photons emitted by the worker, see [Reabsorption].
+    absorptions: Vec<Absorption>,
+    /// The directions of all photons sampled by the worker, if [EmissionStatistics] are being accumulated.
+    directions: Option<EmissionStatistics>,
+}
+
+/// Collects the photons emitted each frame into a [MemoryPhotonSink] resource, which can be inspected after `dispatch`.
+///
+/// The [MemoryPhotonSink] must be inserted into the world before the system runs.
+pub type CollectPhotonsSystem = RegisterPhotonsSystem<MemoryPhotonSink>;
+
+/// Samples the photons emitted each frame once, and appends them to every sink of a [PhotonBroadcast] resource.
+///
+/// The [PhotonBroadcast] must be inserted into the world before the system runs.
+pub type PhotonBroadcastSystem = RegisterPhotonsSystem<PhotonBroadcast>;
+
+/// Whether [RegisterInitialAtomsSystem] writes the initial atoms to the output. Defaults to true.
+///
+/// Disabling this reduces the size of the output file for large clouds when only the photons are of interest.
+#[derive(Clone, Copy, Debug)]
+pub struct RecordInitialAtoms(pub bool);
+impl Default for RecordInitialAtoms {
+    fn default() -> Self {
+        RecordInitialAtoms(true)
+    }
+}
+
+/// This system gets the initial positions and velocities of atoms immediately after creation, and stores them in the [PhotonOutput] resource `T`.
+///
+/// The system does nothing if the [RecordInitialAtoms] resource is false.
+pub struct RegisterInitialAtomsSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for RegisterInitialAtomsSystem<T> {
+    fn default() -> Self {
+        RegisterInitialAtomsSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for RegisterInitialAtomsSystem<T> {
     type SystemData = (
-        ReadExpect<'a, PhotonOutputter>,
+        ReadExpect<'a, T>,
+        Write<'a, OutputError>,
         ReadStorage<'a, Atom>,
         ReadStorage<'a, NewlyCreated>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, Velocity>,
+        Read<'a, RecordInitialAtoms>,
     );
-    fn run(&mut self, (output, atoms, new, positions, velocities): Self::SystemData) {
+    fn run(&mut self, (output, mut error, atoms, new, positions, velocities, record): Self::SystemData) {
         use rayon::prelude::*;
 
+        if !record.0 {
+            return;
+        }
+
         // Get initial atom positions
         let atoms: Vec<InitialAtomPositionRecord> = (&atoms, &new, &positions, &velocities).par_join().map(
             |(_atom, _new, pos, vel)| {
                 InitialAtomPositionRecord::new(&pos, &vel)
         }).collect();
         if atoms.len() > 0 {
-            output.write_initial_atom_positions(atoms);
+            error.record(output.write_initial_atom_positions(atoms));
         }
     }
-}
\ No newline at end of file
+}
+/// This system gets the final positions and velocities of all atoms, and stores them in the [PhotonOutput] resource `T`.
+///
+/// It should be run once, after the simulation has finished. Atoms are joined in the same order as in
+/// [RegisterInitialAtomsSystem], so that rows of the final and initial atoms correspond to the same atom.
+pub struct RegisterFinalAtomsSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for RegisterFinalAtomsSystem<T> {
+    fn default() -> Self {
+        RegisterFinalAtomsSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for RegisterFinalAtomsSystem<T> {
+    type SystemData = (
+        ReadExpect<'a, T>,
+        Write<'a, OutputError>,
+        ReadStorage<'a, Atom>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+    );
+    fn run(&mut self, (output, mut error, atoms, positions, velocities): Self::SystemData) {
+        use rayon::prelude::*;
+
+        let atoms: Vec<InitialAtomPositionRecord> = (&atoms, &positions, &velocities).par_join().map(
+            |(_atom, pos, vel)| {
+                InitialAtomPositionRecord::new(&pos, &vel)
+        }).collect();
+        error.record(output.write_final_atom_positions(atoms));
+    }
+}
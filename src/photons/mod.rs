@@ -1,31 +1,217 @@
 //! A module that generates photons scattered by atoms.
 
+use std::error::Error;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufRead, BufReader, BufWriter};
 use std::path::Path;
-use std::sync::atomic::{Ordering, AtomicU32};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
 use atomecs::{atom::Position};
 use nalgebra::Vector3;
 use specs::prelude::*;
+use rand::Rng;
 use rand_distr;
-use rand_distr::{Distribution, UnitSphere};
-use std::io::Write;
+use rand_distr::{Distribution, Poisson};
+// The traits are imported anonymously, so that `Write` still refers to the specs resource.
+use std::fmt::Write as _;
+use std::io::Write as _;
 
+pub mod absorption;
+pub mod camera;
+pub mod cylindrical;
+pub mod emission;
+pub mod histogram;
 pub mod list;
+pub mod rate;
+pub mod reabsorption;
+pub mod recoil;
+pub mod spectrum;
+pub mod statistics;
+pub mod threshold;
+pub mod tracer;
+
+pub use histogram::{HistogramSnapshots, PhotonHistogram, PhotonHistogramSystem, SnapshotMode};
+
+use emission::EmissionPattern;
+use list::PhotonEmission;
+
+/// The index of the current simulation frame, incremented by the main loop after each dispatch.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FrameNumber(pub u32);
+
+/// Number of frames at the start of the run during which photons are not recorded, so that the cloud can reach a
+/// steady state before imaging begins. Defaults to 0.
+///
+/// The atoms still scatter photons and recoil during the warmup, but the [list::RegisterPhotonsSystem] and the
+/// histogram systems do nothing.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WarmupSteps(pub u32);
+impl WarmupSteps {
+    /// True if photons emitted in the given frame are not recorded.
+    pub fn is_warming_up(&self, frame: FrameNumber) -> bool {
+        frame.0 < self.0
+    }
+}
+
+/// Total number of photons emitted so far, whether or not they were output.
+///
+/// The count is atomic so that it can be read at any time during the run, e.g. for progress reports.
+/// Compare with [list::PhotonSink::photons_written] to find the photons filtered out before output.
+#[derive(Default, Debug)]
+pub struct PhotonCounter(pub AtomicU64);
+impl PhotonCounter {
+    /// Add a number of emitted photons to the count.
+    pub fn add(&self, photons: u64) {
+        self.0.fetch_add(photons, Ordering::Relaxed);
+    }
+
+    /// The number of photons emitted so far.
+    pub fn emitted(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Numbers of photons which were emitted, but not output.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DroppedPhotons {
+    /// Photons emitted by an atom with a non-finite position.
+    ///
+    /// A non-zero count usually indicates a diverging simulation, e.g. from a pathological force.
+    pub non_finite: u64,
+    /// Photons emitted by an atom outside the [list::RegionOfInterest]. A reabsorbed photon is dropped if the last
+    /// atom to re-emit it is outside the region.
+    pub outside_region: u64,
+    /// Photons which were not detected, see [DetectionEfficiency].
+    pub undetected: u64,
+}
+
+/// The quantum efficiency of the detector, the probability (0-1) that an emitted photon is detected.
+///
+/// When this resource is present, each photon is kept with this probability. Undetected photons still
+/// apply a recoil to the emitting atom.
+#[derive(Clone, Copy, Debug)]
+pub struct DetectionEfficiency(pub f64);
+impl DetectionEfficiency {
+    /// Randomly decide whether a photon is detected.
+    pub fn detects<R: Rng + ?Sized>(&self, rng: &mut R) -> bool {
+        self.0 >= 1.0 || rng.gen::<f64>() < self.0
+    }
+}
+
+/// Rate of background counts in each cell of a histogram, or pixel of a camera image, in units of counts per second.
+///
+/// When this resource is present, the [PhotonHistogramSystem] and [camera::CameraHistogramSystem] deposit a
+/// Poisson-distributed number of background counts each frame, with mean `rate × timestep` per cell, uniformly
+/// across the cells. This models stray light and the dark counts of the detector. The background counts are tallied
+/// separately by each histogram, so that the mean background can be subtracted from the signal of the atoms.
+#[derive(Clone, Copy, Debug)]
+pub struct BackgroundRate(pub f64);
+impl BackgroundRate {
+    /// Samples the total number of background counts in `cells` cells during a frame of duration `dt`, in units of s.
+    pub fn sample<R: Rng + ?Sized>(&self, cells: usize, dt: f64, rng: &mut R) -> u64 {
+        let mean = self.0 * dt * cells as f64;
+        if mean > 0.0 {
+            Poisson::new(mean).expect("Invalid Poisson mean.").sample(rng) as u64
+        } else {
+            0
+        }
+    }
+}
+
+/// Expected number of photons scattered by an atom in a frame, above which the [list::RegisterPhotonsSystem] warns
+/// that the timestep is too large. Defaults to 2.
+///
+/// The timestep should be small enough that each atom scatters ~0-1 photons per frame. Otherwise, the photons of a
+/// frame are all emitted from the same position, and the rounding or Poisson sampling of a large mean no longer
+/// resolves the scattering within the frame.
+#[derive(Clone, Copy, Debug)]
+pub struct MaxScatteredPerFrame(pub f64);
+impl Default for MaxScatteredPerFrame {
+    fn default() -> Self {
+        MaxScatteredPerFrame(2.0)
+    }
+}
+
+/// Determines how the expected number of photons scattered by an atom in a frame is
+/// converted into an integer number of emitted photons.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PhotonCountingMode {
+    /// Round the expected number of scattered photons to the nearest integer.
+    Round,
+    /// Draw the number of photons from a Poisson distribution with mean equal to the expected number.
+    ///
+    /// This preserves the shot noise of the scattering process.
+    Poisson,
+}
+impl Default for PhotonCountingMode {
+    fn default() -> Self {
+        PhotonCountingMode::Round
+    }
+}
+impl PhotonCountingMode {
+    /// Get the integer number of photons emitted for an expected number `mean`.
+    pub fn sample<R: Rng + ?Sized>(&self, mean: f64, rng: &mut R) -> u32 {
+        match self {
+            PhotonCountingMode::Round => mean.round() as u32,
+            PhotonCountingMode::Poisson => {
+                if mean > 0.0 {
+                    Poisson::new(mean).expect("Invalid Poisson mean.").sample(rng) as u32
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+/// When the [WritePhotonsSystem] flushes its buffered output to the file.
+///
+/// Flushing makes the photons written so far visible to other readers of the file, e.g. `tail -f`, and ensures
+/// they are not lost if the program crashes, at the cost of more frequent writes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FlushPolicy {
+    /// Only flush when the buffer is full, and when the system is dropped.
+    Never,
+    /// Flush after every given number of frames.
+    Frames(u32),
+    /// Flush once at least the given number of bytes have been written since the last flush.
+    Bytes(usize),
+}
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy::Never
+    }
+}
+
+/// Default capacity of the [WritePhotonsSystem] buffer, in bytes.
+pub const DEFAULT_CSV_BUFFER_CAPACITY: usize = 8 * 1024;
 
 /// This system writes to an output file when an atom scatters a photon.
 ///
-/// The emission is assumed to be isotropic.
+/// The emission direction is sampled from the [EmissionPattern] resource, which defaults to isotropic.
+/// Output is buffered, and flushed according to the [FlushPolicy] and when the system is dropped.
 pub struct WritePhotonsSystem {
     stream: BufWriter<File>,
+    flush_policy: FlushPolicy,
+    frames_since_flush: u32,
+    bytes_since_flush: usize,
 }
 impl WritePhotonsSystem {
     /// Create a new [WritePhotonSystem] with given output filename.
     pub fn new(
         file_name: String,
     ) -> Self
+    {
+        Self::with_options(file_name, DEFAULT_CSV_BUFFER_CAPACITY, FlushPolicy::default())
+    }
+
+    /// Create a new [WritePhotonSystem] with given output filename, buffer capacity in bytes, and [FlushPolicy].
+    pub fn with_options(
+        file_name: String,
+        capacity: usize,
+        flush_policy: FlushPolicy,
+    ) -> Self
     {
         let path = Path::new(&file_name);
         let display = path.display();
@@ -33,9 +219,32 @@ impl WritePhotonsSystem {
             Err(why) => panic!("couldn't open {}: {}", display, why),
             Ok(file) => file,
         };
-        let writer = BufWriter::new(file);
+        let writer = BufWriter::with_capacity(capacity, file);
         WritePhotonsSystem {
-            stream: writer
+            stream: writer,
+            flush_policy,
+            frames_since_flush: 0,
+            bytes_since_flush: 0,
+        }
+    }
+
+    /// Capacity of the output buffer, in bytes.
+    pub fn capacity(&self) -> usize {
+        self.stream.capacity()
+    }
+
+    /// Flushes the buffered output to the file.
+    pub fn flush(&mut self) {
+        self.stream.flush().expect("Could not flush output.");
+        self.frames_since_flush = 0;
+        self.bytes_since_flush = 0;
+    }
+}
+impl Drop for WritePhotonsSystem {
+    fn drop(&mut self) {
+        // BufWriter flushes when dropped, but silently ignores errors.
+        if let Err(why) = self.stream.flush() {
+            eprintln!("Could not flush photon output: {}", why);
         }
     }
 }
@@ -43,115 +252,77 @@ impl<'a> System<'a> for WritePhotonsSystem {
     type SystemData = (
         ReadStorage<'a, ActualPhotonsScatteredVector>,
         ReadStorage<'a, Position>,
+        Read<'a, PhotonCountingMode>,
+        Read<'a, EmissionPattern>,
+        Write<'a, DroppedPhotons>,
     );
-    fn run(&mut self, (totals, positions): Self::SystemData) {
+    fn run(&mut self, (totals, positions, mode, pattern, mut dropped): Self::SystemData) {
 
         let mut rng = rand::thread_rng();
+        let mut line = String::new();
 
         // Generate photons scattered by each atom in the system.
         for (total, position) in (&totals, &positions).join() {
-            let number = total.contents.iter().map(|a| a.scattered.round() as u32).sum();
+            let number: u32 = total.contents.iter().map(|a| mode.sample(a.scattered, &mut rng)).sum();
+            if !position.pos.iter().all(|x| x.is_finite()) {
+                dropped.non_finite += number as u64;
+                continue;
+            }
             for _ in 0..number {
                 // Pick a random direction
-                let v: [f64; 3] = UnitSphere.sample(&mut rng);
+                let v = pattern.sample(&mut rng);
 
                 // Write a line for this photon in the output file
-                writeln!(self.stream, "{:?},{:?},{:?},{:?},{:?},{:?}", position.pos[0], position.pos[1], position.pos[2], v[0], v[1], v[2]).expect("Could not write output.");
+                line.clear();
+                writeln!(line, "{:?},{:?},{:?},{:?},{:?},{:?}", position.pos[0], position.pos[1], position.pos[2], v[0], v[1], v[2]).expect("Could not format output.");
+                self.stream.write_all(line.as_bytes()).expect("Could not write output.");
+                self.bytes_since_flush += line.len();
             }
         }
-    }
-}
-
-const ELEMENT: AtomicU32 = AtomicU32::new(0);
-
-/// This system constructs a spatial histogram of where photons are produced.
-/// 
-/// AtomicU32 are used so that elements in the histogram can be updated from parallel threads -
-/// only a non-mutable borrow is required for the [PhotonHistogram] itself.
-pub struct PhotonHistogram {
-    pub cell_size: f64,
-    cell_number: usize,
-    cells: Vec<AtomicU32>
-}
-impl PhotonHistogram {
-    /// Create a new [PhotonHistogram]. 
-    /// 
-    /// # Arguments
-    /// 
-    /// * `domain_size`: size of the histogram domain in units of m.
-    /// 
-    /// * `cell_number`: number of cells along one dimension of the histogram.
-    pub fn new(
-        domain_size: f64,
-        cell_number: usize
-    ) -> Self
-    {
-        let mut cells = Vec::new();
-        for _ in 0..(cell_number*cell_number*cell_number) {
-            cells.push(ELEMENT);
-        }
-        PhotonHistogram {
-            cell_size: domain_size / cell_number as f64,
-            cells,
-            cell_number
-        }
-    }
-
-    /// Counts a given position into the histogram.
-    pub fn count(&self, position: Vector3<f64>) {
-        if let Some(index) = self.get_index(position) {
-            self.cells[index].fetch_add(1, Ordering::SeqCst);
-        }
-    }
-
-    /// Get the cell index for a given position.
-    fn get_index(&self, position: Vector3<f64>) -> Option<usize> {
-        let x = (position[0] / self.cell_size) as i32 + (self.cell_number as i32) / 2;
-        let y = (position[1] / self.cell_size) as i32 + (self.cell_number  as i32) / 2;
-        let z = (position[2] / self.cell_size) as i32 + (self.cell_number  as i32) / 2;
-
-        if (x < 0 || x >= self.cell_number  as i32) || (y < 0 || y >= self.cell_number  as i32) || (z < 0 || z >= self.cell_number  as i32) {
-            return None;
-        } else {
-            return Some(
-                (z as usize) * self.cell_number  * self.cell_number 
-                + (y as usize) * self.cell_number 
-                + x as usize
-            );
-        }
-    }
 
-    pub fn write_to_file(&self, file_name: String) {
-        let path = Path::new(&file_name);
-        let display = path.display();
-        let file = match File::create(&path) {
-            Err(why) => panic!("couldn't open {}: {}", display, why),
-            Ok(file) => file,
+        self.frames_since_flush += 1;
+        let flush = match self.flush_policy {
+            FlushPolicy::Never => false,
+            FlushPolicy::Frames(frames) => self.frames_since_flush >= frames,
+            FlushPolicy::Bytes(bytes) => self.bytes_since_flush >= bytes,
         };
-        let mut writer = BufWriter::new(file);
-        for v in self.cells.iter() {
-            write!(writer, "{:?},", v.load(Ordering::SeqCst)).expect("Could not write output.");
+        if flush {
+            self.flush();
         }
     }
 }
-pub struct PhotonHistogramSystem;
-impl<'a> System<'a> for PhotonHistogramSystem {
-    type SystemData = (
-        ReadExpect<'a, PhotonHistogram>,
-        ReadStorage<'a, ActualPhotonsScatteredVector>,
-        ReadStorage<'a, Position>,
-    );
-    fn run(&mut self, (histogram, totals, positions): Self::SystemData) {
 
-        use rayon::prelude::*;
-
-        // Generate photons scattered by each atom in the system.
-        (&totals, &positions).par_join().for_each(|(total, position)| {
-            let number = total.contents.iter().map(|a| a.scattered.round() as u32).sum();
-            for _ in 0..number {
-                // sow positions into the histogram
-                histogram.count(position.pos);
-            }
+/// Reads photons written by the [WritePhotonsSystem], with one `x,y,z,dx,dy,dz` line per photon.
+///
+/// The csv file does not store the frequency, frame, source or atom of the photons, so these are `None`, 0, 0 and `None`.
+/// Blank lines are skipped. A malformed line gives an error with the line number and content.
+pub fn read_photons_csv(path: &str) -> Result<Vec<PhotonEmission>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut photons = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let values: Vec<f64> = line
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Could not parse line {} of {} ({}): '{}'", i + 1, path, e, line))?;
+        if values.len() != 6 {
+            return Err(format!(
+                "Line {} of {} has {} columns, but expected 6 (x,y,z,dx,dy,dz): '{}'",
+                i + 1, path, values.len(), line
+            ).into());
+        }
+        photons.push(PhotonEmission {
+            position: Vector3::new(values[0], values[1], values[2]),
+            direction: Vector3::new(values[3], values[4], values[5]),
+            frequency: None,
+            frame: 0,
+            source: 0,
+            atom: None,
         });
     }
-}
\ No newline at end of file
+    Ok(photons)
+}
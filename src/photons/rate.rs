@@ -0,0 +1,97 @@
+//! A diagnostic of the scattering rate against the speed of the atoms, frame by frame.
+//!
+//! Atoms heated by the imaging light are Doppler shifted off resonance, so their scattering rate falls as their
+//! speed grows. Recording both over the run shows whether this feedback behaves as expected.
+
+use std::marker::PhantomData;
+
+use atomecs::atom::Velocity;
+use atomecs::integrator::Timestep;
+use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
+use hdf5::H5Type;
+use ndarray::arr1;
+use specs::prelude::*;
+
+use super::list::{OutputError, PhotonOutput, PhotonOutputter};
+use super::FrameNumber;
+
+/// The mean scattering rate and speed of the atoms in a frame.
+#[derive(H5Type, Clone, Copy, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+pub struct ScatteringRateRecord {
+    /// Index of the frame.
+    pub frame: u32,
+    /// Mean number of photons scattered per atom per second during the frame.
+    pub mean_rate: f64,
+    /// Mean speed of the atoms at the end of the frame, in units of m/s.
+    pub mean_speed: f64,
+}
+
+/// The [ScatteringRateRecord] of each frame of the run.
+///
+/// When this resource is present, the [ScatteringRateSystem] appends a record every frame.
+#[derive(Clone, Default, Debug)]
+pub struct ScatteringRateHistory(pub Vec<ScatteringRateRecord>);
+
+/// Appends the mean scattering rate and speed of the atoms in this frame to the [ScatteringRateHistory], if present.
+pub struct ScatteringRateSystem;
+impl<'a> System<'a> for ScatteringRateSystem {
+    type SystemData = (
+        ReadStorage<'a, ActualPhotonsScatteredVector>,
+        ReadStorage<'a, Velocity>,
+        ReadExpect<'a, Timestep>,
+        Read<'a, FrameNumber>,
+        Option<Write<'a, ScatteringRateHistory>>,
+    );
+    fn run(&mut self, (totals, velocities, timestep, frame, history): Self::SystemData) {
+        use rayon::prelude::*;
+
+        let mut history = match history {
+            Some(history) => history,
+            None => return,
+        };
+        let (atoms, scattered, speed) = (&totals, &velocities).par_join()
+            .map(|(total, velocity)| (1u64, total.contents.iter().map(|a| a.scattered).sum::<f64>(), velocity.vel.norm()))
+            .reduce(|| (0, 0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+        if atoms == 0 {
+            return;
+        }
+        history.0.push(ScatteringRateRecord {
+            frame: frame.0,
+            mean_rate: scattered / atoms as f64 / timestep.delta,
+            mean_speed: speed / atoms as f64,
+        });
+    }
+}
+
+/// Prints the change in the mean scattering rate and speed over the run, from the [ScatteringRateHistory], and writes
+/// the history to the 'scattering_rate' dataset of the [PhotonOutput] `T`.
+pub struct ScatteringRateOutputSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for ScatteringRateOutputSystem<T> {
+    fn default() -> Self {
+        ScatteringRateOutputSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for ScatteringRateOutputSystem<T> {
+    type SystemData = (
+        Read<'a, ScatteringRateHistory>,
+        Option<Read<'a, T>>,
+        Write<'a, OutputError>,
+    );
+    fn run(&mut self, (history, output, mut error): Self::SystemData) {
+        let (first, last) = match (history.0.first(), history.0.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        println!(
+            "Scattering rate: {:.3e} photons/s at a mean speed of {:.3} m/s in frame {}, {:.3e} photons/s at {:.3} m/s in frame {}.",
+            first.mean_rate, first.mean_speed, first.frame, last.mean_rate, last.mean_speed, last.frame
+        );
+
+        if let Some(output) = output {
+            error.record(output.write_diagnostic("scattering_rate", arr1(&history.0).view()));
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! An approximate model of the reabsorption of emitted photons by other atoms, for optically thick clouds.
+//!
+//! The atoms are binned into a coarse grid each frame, which gives a mean-field density. Each emitted photon is
+//! marched through the grid along its direction, and is absorbed with probability `1 - exp(-σ ∫n dl)`, where the
+//! integral is the column density along its path. An absorbed photon is handed to an atom in the cell where it is
+//! absorbed, which receives the momentum of the photon and re-emits it in a new direction. The photon may be
+//! reabsorbed several times before it escapes the cloud.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use atomecs::atom::AtomicTransition;
+use atomecs::constant::C;
+use nalgebra::Vector3;
+use rand::Rng;
+use specs::Entity;
+
+use super::emission::EmissionPattern;
+
+/// Enables the reabsorption of emitted photons by the [RegisterPhotonsSystem](super::list::RegisterPhotonsSystem).
+///
+/// The model is a mean-field approximation: the atoms within a cell of the density grid are treated as uniformly
+/// spread across it, and the cross section does not depend on the Doppler shift of the absorbing atom.
+pub struct Reabsorption {
+    /// Absorption cross section of an atom for an emitted photon, in units of m².
+    pub cross_section: f64,
+    /// Size of the cells of the density grid, in units of m. The cells should be small compared to the cloud, but
+    /// contain several atoms, so that the density is not dominated by shot noise.
+    pub cell_size: f64,
+    /// Number of times a photon may be reabsorbed before it is assumed to escape, which bounds the cost of each photon.
+    pub max_scatterings: u32,
+    reabsorbed: AtomicU64,
+}
+impl Reabsorption {
+    /// Create a new [Reabsorption] model.
+    ///
+    /// # Arguments
+    ///
+    /// * `cross_section`: absorption cross section, in units of m², see [Reabsorption::resonant_cross_section].
+    ///
+    /// * `cell_size`: size of the cells of the density grid, in units of m.
+    pub fn new(cross_section: f64, cell_size: f64) -> Self {
+        assert!(cross_section >= 0.0 && cell_size > 0.0, "The cross section must not be negative, and the cell size must be positive.");
+        Reabsorption { cross_section, cell_size, max_scatterings: 10, reabsorbed: AtomicU64::new(0) }
+    }
+
+    /// Set the number of times a photon may be reabsorbed before it is assumed to escape.
+    pub fn with_max_scatterings(self, max_scatterings: u32) -> Self {
+        Reabsorption { max_scatterings, ..self }
+    }
+
+    /// The resonant cross section of a two-level atom, `3λ²/2π`, in units of m².
+    ///
+    /// The emitted photons are close to resonance, so this is an upper bound on the cross section.
+    pub fn resonant_cross_section(transition: &AtomicTransition) -> f64 {
+        let wavelength = C / transition.frequency;
+        3.0 * wavelength * wavelength / (2.0 * std::f64::consts::PI)
+    }
+
+    /// Number of times photons have been reabsorbed so far.
+    pub fn reabsorbed(&self) -> u64 {
+        self.reabsorbed.load(Ordering::Relaxed)
+    }
+
+    /// Zero the number of reabsorptions, e.g. between the points of a sweep.
+    pub fn reset(&self) {
+        self.reabsorbed.store(0, Ordering::Relaxed);
+    }
+
+    /// Follows a photon emitted by `emitter` at `position` along `direction` until it escapes the cloud.
+    ///
+    /// Each atom which absorbs the photon is pushed onto `absorbers`, with the direction in which the photon arrived
+    /// and the direction in which it was re-emitted, so that the recoils can be applied. Returns the emitter, position
+    /// and direction of the photon which escapes.
+    pub fn scatter<R: Rng + ?Sized>(
+        &self,
+        grid: &DensityGrid,
+        pattern: &EmissionPattern,
+        emitter: GridAtom,
+        position: Vector3<f64>,
+        direction: Vector3<f64>,
+        absorbers: &mut Vec<Absorption>,
+        rng: &mut R,
+    ) -> (GridAtom, Vector3<f64>, Vector3<f64>) {
+        let (mut emitter, mut position, mut direction) = (emitter, position, direction);
+        for _ in 0..self.max_scatterings {
+            // The optical depth at which the photon is absorbed is exponentially distributed.
+            let depth = -(1.0 - rng.gen::<f64>()).ln();
+            let absorber = match grid.absorber(emitter.entity, position, direction, self.cross_section, depth, rng) {
+                Some(absorber) => absorber,
+                None => break,
+            };
+            let emitted = pattern.sample(rng);
+            absorbers.push(Absorption { entity: absorber.entity, absorbed: direction, emitted });
+            self.reabsorbed.fetch_add(1, Ordering::Relaxed);
+            emitter = absorber;
+            position = absorber.position;
+            direction = emitted;
+        }
+        (emitter, position, direction)
+    }
+}
+
+/// An atom in the [DensityGrid].
+#[derive(Clone, Copy, Debug)]
+pub struct GridAtom {
+    pub entity: Entity,
+    /// Position of the atom, in units of m.
+    pub position: Vector3<f64>,
+    /// The index of the atom which photons emitted by it are tagged with, if any.
+    pub index: Option<u64>,
+}
+
+/// An atom which absorbed a photon arriving along `absorbed`, and re-emitted it along `emitted`.
+#[derive(Clone, Copy, Debug)]
+pub struct Absorption {
+    pub entity: Entity,
+    pub absorbed: Vector3<f64>,
+    pub emitted: Vector3<f64>,
+}
+
+/// The atoms of the cloud, binned into cubic cells, which gives the mean-field density of the cloud.
+pub struct DensityGrid {
+    cell_size: f64,
+    cells: HashMap<[i64; 3], Vec<GridAtom>>,
+    /// Lower and upper cell indices of the box which contains every atom.
+    bounds: ([i64; 3], [i64; 3]),
+}
+impl DensityGrid {
+    /// Bins the given atoms into cubic cells of size `cell_size`, in units of m. Atoms at non-finite positions are ignored.
+    pub fn new<I: IntoIterator<Item = GridAtom>>(cell_size: f64, atoms: I) -> Self {
+        let mut grid = DensityGrid { cell_size, cells: HashMap::new(), bounds: ([i64::MAX; 3], [i64::MIN; 3]) };
+        for atom in atoms {
+            if !atom.position.iter().all(|x| x.is_finite()) {
+                continue;
+            }
+            let cell = grid.cell_of(&atom.position);
+            for axis in 0..3 {
+                grid.bounds.0[axis] = grid.bounds.0[axis].min(cell[axis]);
+                grid.bounds.1[axis] = grid.bounds.1[axis].max(cell[axis]);
+            }
+            grid.cells.entry(cell).or_insert_with(Vec::new).push(atom);
+        }
+        grid
+    }
+
+    fn cell_of(&self, position: &Vector3<f64>) -> [i64; 3] {
+        [
+            (position[0] / self.cell_size).floor() as i64,
+            (position[1] / self.cell_size).floor() as i64,
+            (position[2] / self.cell_size).floor() as i64,
+        ]
+    }
+
+    fn contains(&self, cell: &[i64; 3]) -> bool {
+        (0..3).all(|axis| cell[axis] >= self.bounds.0[axis] && cell[axis] <= self.bounds.1[axis])
+    }
+
+    /// Number density of atoms at the position, in units of m⁻³.
+    pub fn density(&self, position: &Vector3<f64>) -> f64 {
+        let atoms = self.cells.get(&self.cell_of(position)).map_or(0, |atoms| atoms.len());
+        atoms as f64 / self.cell_size.powi(3)
+    }
+
+    /// Column density from `position` along the unit `direction` until the photon leaves the grid, in units of m⁻².
+    pub fn column_density(&self, position: Vector3<f64>, direction: Vector3<f64>) -> f64 {
+        self.march(position, direction, |_, _, _| false)
+    }
+
+    /// Marches from `position` along `direction` in steps of half a cell, until `stop` returns true or the path leaves
+    /// the grid. `stop` is called with the cell, the density and the step length at each step. Returns the column
+    /// density traversed.
+    fn march<F: FnMut([i64; 3], f64, f64) -> bool>(&self, position: Vector3<f64>, direction: Vector3<f64>, mut stop: F) -> f64 {
+        let step = self.cell_size / 2.0;
+        let mut column = 0.0;
+        // The path starts half a step from the emitter, and samples the density at the middle of each step.
+        let mut point = position + direction * step / 2.0;
+        loop {
+            let cell = self.cell_of(&point);
+            if !self.contains(&cell) {
+                return column;
+            }
+            let density = self.density(&point);
+            column += density * step;
+            if stop(cell, density, step) {
+                return column;
+            }
+            point += direction * step;
+        }
+    }
+
+    /// The atom which absorbs a photon travelling from `position` along `direction`, if it is absorbed before it leaves
+    /// the grid. The photon is absorbed once the optical depth `σ ∫n dl` reaches `depth`, by a random atom of the cell
+    /// it has reached, other than the `emitter`.
+    fn absorber<R: Rng + ?Sized>(&self, emitter: Entity, position: Vector3<f64>, direction: Vector3<f64>, cross_section: f64, depth: f64, rng: &mut R) -> Option<GridAtom> {
+        let mut optical_depth = 0.0;
+        let mut absorber = None;
+        self.march(position, direction, |cell, density, step| {
+            optical_depth += cross_section * density * step;
+            if optical_depth < depth {
+                return false;
+            }
+            let candidates: Vec<&GridAtom> = self.cells.get(&cell)
+                .map(|atoms| atoms.iter().filter(|atom| atom.entity != emitter).collect())
+                .unwrap_or_default();
+            if candidates.is_empty() {
+                // Only the emitter is in this cell, so it cannot absorb its own photon.
+                return false;
+            }
+            absorber = Some(*candidates[rng.gen_range(0..candidates.len())]);
+            true
+        });
+        absorber
+    }
+}
@@ -0,0 +1,101 @@
+//! A diagnostic which ties emitted photons back to the momentum kicks they impart to the atoms.
+
+use std::marker::PhantomData;
+
+use atomecs::atom::AtomicTransition;
+use atomecs::constant::{C, HBAR};
+use hdf5::H5Type;
+use nalgebra::Vector3;
+use ndarray::arr1;
+use specs::prelude::*;
+
+use super::list::{OutputError, PhotonOutput, PhotonOutputter};
+
+/// Accumulates the photon momentum emitted by an atom.
+///
+/// Attach this component to atoms to enable the recoil diagnostic; the [RegisterPhotonsSystem](super::list::RegisterPhotonsSystem)
+/// adds the momentum ħk of each photon it emits along the photon's emission direction.
+#[derive(Clone, Default)]
+pub struct EmissionRecoil {
+    /// Sum of the momenta of the emitted photons, in units of kg m/s.
+    pub momentum: Vector3<f64>,
+    /// Number of photons emitted.
+    pub photons: u64,
+    /// Expected variance of `momentum` for an isotropic random walk, the sum of (ħk)² over emitted photons.
+    pub expected_variance: f64,
+}
+impl Component for EmissionRecoil {
+    type Storage = VecStorage<Self>;
+}
+impl EmissionRecoil {
+    /// Add the recoil of a photon emitted along `direction` on the given transition.
+    pub fn add(&mut self, transition: &AtomicTransition, direction: &Vector3<f64>) {
+        let hbar_k = HBAR * 2.0 * std::f64::consts::PI * transition.frequency / C;
+        self.momentum += hbar_k * direction;
+        self.photons += 1;
+        self.expected_variance += hbar_k * hbar_k;
+    }
+}
+
+/// Summary of the [EmissionRecoil] of all atoms.
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+pub struct RecoilDiagnosticRecord {
+    /// Number of atoms included.
+    pub atoms: u64,
+    /// Mean number of photons emitted per atom.
+    pub mean_photons: f64,
+    /// RMS magnitude of the summed photon momentum per atom, in units of kg m/s.
+    pub rms_momentum: f64,
+    /// Expected RMS momentum for a random walk of the same number of photons, in units of kg m/s.
+    pub expected_rms_momentum: f64,
+}
+
+/// Summarises the [EmissionRecoil] of all atoms and writes it to the 'recoil_diagnostic' dataset of the
+/// [PhotonOutput] `T`.
+///
+/// For isotropic emission, the photon momenta perform a random walk, so `rms_momentum` should agree with
+/// `expected_rms_momentum`, which scales as the square root of the number of photons.
+pub struct RecoilDiagnosticSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for RecoilDiagnosticSystem<T> {
+    fn default() -> Self {
+        RecoilDiagnosticSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for RecoilDiagnosticSystem<T> {
+    type SystemData = (
+        ReadStorage<'a, EmissionRecoil>,
+        Option<Read<'a, T>>,
+        Write<'a, OutputError>,
+    );
+    fn run(&mut self, (recoils, output, mut error): Self::SystemData) {
+        let mut atoms = 0u64;
+        let mut photons = 0u64;
+        let mut momentum_squared = 0.0;
+        let mut expected_variance = 0.0;
+        for recoil in recoils.join() {
+            atoms += 1;
+            photons += recoil.photons;
+            momentum_squared += recoil.momentum.norm_squared();
+            expected_variance += recoil.expected_variance;
+        }
+        if atoms == 0 {
+            return;
+        }
+        let record = RecoilDiagnosticRecord {
+            atoms,
+            mean_photons: photons as f64 / atoms as f64,
+            rms_momentum: (momentum_squared / atoms as f64).sqrt(),
+            expected_rms_momentum: (expected_variance / atoms as f64).sqrt(),
+        };
+        println!(
+            "Recoil diagnostic: {:.1} photons per atom, rms momentum {:e} kg m/s (expected {:e} kg m/s).",
+            record.mean_photons, record.rms_momentum, record.expected_rms_momentum
+        );
+        if let Some(output) = output {
+            error.record(output.write_diagnostic("recoil_diagnostic", arr1(&[record]).view()));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! A histogram of the frequencies of the emitted photons, i.e. the emission spectrum.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hdf5::H5Type;
+use ndarray::arr1;
+
+use super::list::{PhotonEmission, PhotonSink};
+
+/// A 1D histogram of photon frequencies.
+///
+/// The bins cover `center - span/2 <= f < center + span/2`, in units of Hz. The frequency of each photon includes
+/// the Doppler shift of the emitting atom and the natural linewidth, see [PhotonEmission::sample_frequency], so the
+/// spectrum is a Voigt profile for a thermal cloud.
+///
+/// The spectrum is a [PhotonSink], so it counts the photons sampled by a [super::list::RegisterPhotonsSystem], e.g.
+/// as one of the sinks of a [super::list::PhotonBroadcast].
+pub struct SpectrumHistogram {
+    /// Frequency at the center of the spectrum, in units of Hz.
+    pub center: f64,
+    /// Width of a frequency bin, in units of Hz.
+    pub bin_width: f64,
+    /// Counts of each bin, in order of increasing frequency.
+    bins: Vec<AtomicU64>,
+    /// Number of photons with a frequency outside the spectrum.
+    outside: AtomicU64,
+    /// Number of photons appended as a [PhotonSink].
+    photons_written: AtomicU64,
+}
+impl SpectrumHistogram {
+    /// Create a new [SpectrumHistogram].
+    ///
+    /// # Arguments
+    ///
+    /// * `center`: frequency at the center of the spectrum, in units of Hz, e.g. the transition frequency.
+    ///
+    /// * `span`: width of the spectrum, in units of Hz.
+    ///
+    /// * `bins`: number of frequency bins.
+    pub fn new(center: f64, span: f64, bins: usize) -> Self {
+        assert!(span > 0.0 && bins > 0, "The spectrum must have a positive span and at least one bin.");
+        SpectrumHistogram {
+            center,
+            bin_width: span / bins as f64,
+            bins: (0..bins).map(|_| AtomicU64::new(0)).collect(),
+            outside: AtomicU64::new(0),
+            photons_written: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a photon of the given frequency, in units of Hz, into the spectrum.
+    ///
+    /// Frequencies outside the spectrum, or which are not finite, are tallied in [SpectrumHistogram::outside].
+    pub fn count(&self, frequency: f64) {
+        match self.get_index(frequency) {
+            Some(index) => { self.bins[index].fetch_add(1, Ordering::Relaxed); }
+            None => { self.outside.fetch_add(1, Ordering::Relaxed); }
+        }
+    }
+
+    /// Number of photons with a frequency outside the spectrum, or which was not finite.
+    pub fn outside(&self) -> u64 {
+        self.outside.load(Ordering::Relaxed)
+    }
+
+    /// Frequency at the center of each bin, in units of Hz.
+    pub fn frequencies(&self) -> Vec<f64> {
+        (0..self.bins.len()).map(|i| self.lowest() + (i as f64 + 0.5) * self.bin_width).collect()
+    }
+
+    /// Frequency of the lower edge of the first bin, in units of Hz.
+    fn lowest(&self) -> f64 {
+        self.center - self.bins.len() as f64 * self.bin_width / 2.0
+    }
+
+    /// Get the counts of each bin, in order of increasing frequency.
+    pub fn counts(&self) -> Vec<u64> {
+        self.bins.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+
+    fn get_index(&self, frequency: f64) -> Option<usize> {
+        if !frequency.is_finite() {
+            return None;
+        }
+        let i = ((frequency - self.lowest()) / self.bin_width).floor() as i64;
+        if i < 0 || i >= self.bins.len() as i64 {
+            return None;
+        }
+        Some(i as usize)
+    }
+
+    /// Writes the spectrum to an h5 file, as a 'spectrum' dataset of [SpectrumRecord]s, with attributes:
+    ///
+    /// * `bin_width`: width of a frequency bin, in units of Hz.
+    ///
+    /// * `outside`: number of photons with a frequency outside the spectrum.
+    pub fn write_to_h5(&self, file_name: String) -> Result<(), hdf5::Error> {
+        let records: Vec<SpectrumRecord> = self.frequencies().into_iter().zip(self.counts())
+            .map(|(frequency, count)| SpectrumRecord { frequency, count })
+            .collect();
+        let file = hdf5::File::create(file_name)?;
+        let dataset = file.new_dataset_builder().with_data(&arr1(&records)).create("spectrum")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.bin_width])).create("bin_width")?;
+        dataset.new_attr_builder().with_data(&arr1(&[self.outside()])).create("outside")?;
+        Ok(())
+    }
+}
+
+/// A bin of the [SpectrumHistogram]: the frequency at the center of the bin, in units of Hz, and the photons counted in it.
+#[derive(H5Type, Clone, Copy, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+pub struct SpectrumRecord {
+    pub frequency: f64,
+    pub count: u64,
+}
+
+/// Counts the [PhotonEmission::frequency] of each photon into the spectrum. As a sink of a
+/// [super::list::PhotonBroadcast], the spectrum is of the same photons as the other sinks. Photons without a
+/// frequency are tallied as outside.
+impl PhotonSink for SpectrumHistogram {
+    fn append_photons(&self, photons: &[PhotonEmission]) -> Result<(), hdf5::Error> {
+        for photon in photons {
+            self.count(photon.frequency.unwrap_or(f64::NAN));
+        }
+        self.photons_written.fetch_add(photons.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn photons_written(&self) -> u64 {
+        self.photons_written.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,119 @@
+//! A diagnostic of the number of photons scattered by each atom over the run.
+
+use std::marker::PhantomData;
+
+use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
+use hdf5::H5Type;
+use ndarray::arr1;
+use specs::prelude::*;
+
+use super::list::{OutputError, PhotonOutput, PhotonOutputter};
+
+/// Number of bins in the histogram of photons scattered per atom.
+pub const PHOTON_STATISTICS_BINS: usize = 20;
+
+/// Accumulates the number of photons scattered by an atom over the run.
+///
+/// Attach this component to atoms to enable the photon statistics diagnostic.
+#[derive(Clone, Copy, Default)]
+pub struct ScatteredPhotonTally(pub f64);
+impl Component for ScatteredPhotonTally {
+    type Storage = VecStorage<Self>;
+}
+
+/// Adds the photons scattered by each atom in the frame to its [ScatteredPhotonTally].
+pub struct TallyScatteredPhotonsSystem;
+impl<'a> System<'a> for TallyScatteredPhotonsSystem {
+    type SystemData = (
+        ReadStorage<'a, ActualPhotonsScatteredVector>,
+        WriteStorage<'a, ScatteredPhotonTally>,
+    );
+    fn run(&mut self, (totals, mut tallies): Self::SystemData) {
+        use rayon::prelude::*;
+
+        (&totals, &mut tallies).par_join().for_each(|(total, tally)| {
+            tally.0 += total.contents.iter().map(|a| a.scattered).sum::<f64>();
+        });
+    }
+}
+
+/// Summary of the [ScatteredPhotonTally] of all atoms.
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+pub struct PhotonStatisticsRecord {
+    /// Number of atoms included.
+    pub atoms: u64,
+    /// Mean number of photons scattered per atom.
+    pub mean: f64,
+    /// Standard deviation of the number of photons scattered per atom.
+    pub std_dev: f64,
+    /// Median number of photons scattered per atom.
+    pub median: f64,
+    /// 99th percentile of the number of photons scattered per atom.
+    pub percentile_99: f64,
+    /// Largest number of photons scattered by an atom.
+    pub max: f64,
+}
+
+/// Summarises the [ScatteredPhotonTally] of all atoms and prints it, with a histogram of [PHOTON_STATISTICS_BINS] bins.
+///
+/// The summary is written to the 'photon_statistics' dataset of the [PhotonOutput] `T`, and the histogram to the
+/// 'photon_statistics_histogram' dataset, which has a `bin_width` attribute.
+pub struct PhotonStatisticsSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for PhotonStatisticsSystem<T> {
+    fn default() -> Self {
+        PhotonStatisticsSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for PhotonStatisticsSystem<T> {
+    type SystemData = (
+        ReadStorage<'a, ScatteredPhotonTally>,
+        Option<Read<'a, T>>,
+        Write<'a, OutputError>,
+    );
+    fn run(&mut self, (tallies, output, mut error): Self::SystemData) {
+        let mut photons: Vec<f64> = tallies.join().map(|tally| tally.0).collect();
+        if photons.is_empty() {
+            return;
+        }
+        photons.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let n = photons.len() as f64;
+        let mean = photons.iter().sum::<f64>() / n;
+        let variance = photons.iter().map(|p| (p - mean) * (p - mean)).sum::<f64>() / n;
+        let percentile = |q: f64| photons[((q * (n - 1.0)).round() as usize).min(photons.len() - 1)];
+        let record = PhotonStatisticsRecord {
+            atoms: photons.len() as u64,
+            mean,
+            std_dev: variance.sqrt(),
+            median: percentile(0.5),
+            percentile_99: percentile(0.99),
+            max: photons[photons.len() - 1],
+        };
+
+        let bin_width = (record.max / PHOTON_STATISTICS_BINS as f64).max(f64::MIN_POSITIVE);
+        let mut histogram = [0u64; PHOTON_STATISTICS_BINS];
+        for p in photons.iter() {
+            histogram[((p / bin_width) as usize).min(PHOTON_STATISTICS_BINS - 1)] += 1;
+        }
+
+        println!(
+            "Photon statistics: {:.1} ± {:.1} photons per atom, median {:.1}, 99th percentile {:.1}, max {:.1}.",
+            record.mean, record.std_dev, record.median, record.percentile_99, record.max
+        );
+        for (i, count) in histogram.iter().enumerate() {
+            println!("  {:>10.1} - {:>10.1}: {}", i as f64 * bin_width, (i + 1) as f64 * bin_width, count);
+        }
+
+        if let Some(output) = output {
+            error.record(output.write_diagnostic("photon_statistics", arr1(&[record]).view()));
+            error.record(
+                output.write_diagnostic("photon_statistics_histogram", arr1(&histogram).view()).and_then(|dataset| match dataset {
+                    Some(dataset) => dataset.new_attr_builder().with_data(&arr1(&[bin_width])).create("bin_width").map(|_| ()),
+                    None => Ok(()),
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! A diagnostic of how often the emission force falls back to its random walk approximation, frame by frame.
+//!
+//! The atomecs emission force samples the recoil of each emitted photon explicitly while an atom scatters at most
+//! `explicit_threshold` photons in a frame. Above the threshold, the recoils are replaced by a single gaussian kick
+//! with the same mean square momentum, which is faster but loses the discreteness of the random walk. A lower
+//! threshold is faster for bright clouds; a higher threshold is more accurate for atoms which scatter few photons.
+//! Recording the fraction of atoms above the threshold shows when the approximation is in use.
+
+use std::marker::PhantomData;
+
+use atomecs::laser_cooling::force::EmissionForceOption;
+use atomecs::laser_cooling::photons_scattered::ActualPhotonsScatteredVector;
+use hdf5::H5Type;
+use ndarray::arr1;
+use specs::prelude::*;
+
+use super::list::{OutputError, PhotonOutput, PhotonOutputter};
+use super::FrameNumber;
+
+/// The fraction of atoms whose recoil was approximated in a frame.
+#[derive(H5Type, Clone, Copy, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+pub struct ExplicitThresholdRecord {
+    /// Index of the frame.
+    pub frame: u32,
+    /// Fraction of the atoms which scattered more than `explicit_threshold` photons in the frame, and so received
+    /// the random walk approximation of the recoil rather than individually sampled kicks.
+    pub approximated_fraction: f64,
+    /// Largest number of photons scattered by an atom in the frame.
+    pub max_scattered: u64,
+}
+
+/// The [ExplicitThresholdRecord] of each frame of the run.
+///
+/// When this resource is present, the [ExplicitThresholdSystem] appends a record every frame.
+#[derive(Clone, Default, Debug)]
+pub struct ExplicitThresholdHistory(pub Vec<ExplicitThresholdRecord>);
+
+/// Appends the fraction of atoms above the emission force `explicit_threshold` in this frame to the
+/// [ExplicitThresholdHistory], if present. Nothing is recorded if the emission force is off.
+pub struct ExplicitThresholdSystem;
+impl<'a> System<'a> for ExplicitThresholdSystem {
+    type SystemData = (
+        ReadStorage<'a, ActualPhotonsScatteredVector>,
+        ReadExpect<'a, EmissionForceOption>,
+        Read<'a, FrameNumber>,
+        Option<Write<'a, ExplicitThresholdHistory>>,
+    );
+    fn run(&mut self, (totals, option, frame, history): Self::SystemData) {
+        use rayon::prelude::*;
+
+        let mut history = match history {
+            Some(history) => history,
+            None => return,
+        };
+        let threshold = match &*option {
+            EmissionForceOption::On(configuration) => configuration.explicit_threshold,
+            EmissionForceOption::Off => return,
+        };
+        // The photons of each atom are totalled as by the atomecs emission force.
+        let (atoms, approximated, max_scattered) = totals.par_join()
+            .map(|total| {
+                let scattered = total.contents.iter().map(|a| a.scattered).sum::<f64>() as u64;
+                (1u64, (scattered > threshold) as u64, scattered)
+            })
+            .reduce(|| (0, 0, 0), |a, b| (a.0 + b.0, a.1 + b.1, a.2.max(b.2)));
+        if atoms == 0 {
+            return;
+        }
+        history.0.push(ExplicitThresholdRecord {
+            frame: frame.0,
+            approximated_fraction: approximated as f64 / atoms as f64,
+            max_scattered,
+        });
+    }
+}
+
+/// Prints how often the recoil was approximated over the run, and writes the [ExplicitThresholdHistory] to the
+/// 'explicit_threshold' dataset of the [PhotonOutput] `T`.
+pub struct ExplicitThresholdOutputSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for ExplicitThresholdOutputSystem<T> {
+    fn default() -> Self {
+        ExplicitThresholdOutputSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for ExplicitThresholdOutputSystem<T> {
+    type SystemData = (
+        Read<'a, ExplicitThresholdHistory>,
+        Option<Read<'a, T>>,
+        Write<'a, OutputError>,
+    );
+    fn run(&mut self, (history, output, mut error): Self::SystemData) {
+        if history.0.is_empty() {
+            return;
+        }
+        let frames = history.0.len();
+        let mean = history.0.iter().map(|r| r.approximated_fraction).sum::<f64>() / frames as f64;
+        let approximated_frames = history.0.iter().filter(|r| r.approximated_fraction > 0.0).count();
+        let max_scattered = history.0.iter().map(|r| r.max_scattered).max().unwrap_or(0);
+        println!(
+            "Emission force: on average {:.2}% of atoms per frame exceeded the explicit threshold, in {} of {} frames. At most {} photons were scattered by an atom in a frame.",
+            100.0 * mean, approximated_frames, frames, max_scattered
+        );
+
+        if let Some(output) = output {
+            error.record(output.write_diagnostic("explicit_threshold", arr1(&history.0).view()));
+        }
+    }
+}
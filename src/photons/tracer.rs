@@ -0,0 +1,104 @@
+//! Records the full trajectories of a few tracer atoms, to follow individual recoil random walks.
+
+use std::marker::PhantomData;
+
+use atomecs::atom::{Atom, Position, Velocity};
+use hdf5::H5Type;
+use specs::prelude::*;
+
+use super::list::{AtomIndex, OutputError, PhotonOutput, PhotonOutputter};
+use super::FrameNumber;
+
+/// Number of tracer atoms above which a warning is printed, as each tracer writes a record every frame.
+pub const TRACER_WARNING_LIMIT: usize = 100;
+
+/// Marks an atom whose position and velocity are recorded every frame by the [RecordTracersSystem].
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TracerAtom;
+impl Component for TracerAtom {
+    type Storage = NullStorage<Self>;
+}
+
+/// The position and velocity of a tracer atom at the end of a frame.
+#[derive(H5Type, Clone, Copy, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+pub struct TrajectoryRecord {
+    /// Index of the frame.
+    pub frame: u32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub vx: f64,
+    pub vy: f64,
+    pub vz: f64,
+}
+
+/// Attaches a [TracerAtom] to each atom whose index is in `indices`.
+///
+/// Atoms are identified by their [AtomIndex], i.e. their row in the input files, or by their entity id if they have
+/// no index, as for the photon atom index. Indices which match no atom are reported.
+pub fn attach_tracers(world: &mut World, indices: &[u64]) {
+    if indices.len() > TRACER_WARNING_LIMIT {
+        println!(
+            "Warning: {} tracer atoms were requested. Each writes a record every frame, so the output may be large.",
+            indices.len()
+        );
+    }
+    world.register::<TracerAtom>();
+    world.register::<AtomIndex>();
+    let tracers: Vec<(Entity, u64)> = {
+        let entities = world.entities();
+        let atoms = world.read_storage::<Atom>();
+        let atom_indices = world.read_storage::<AtomIndex>();
+        (&entities, &atoms, (&atom_indices).maybe()).join()
+            .map(|(entity, _, index)| (entity, index.map_or(entity.id() as u64, |index| index.0)))
+            .filter(|(_, index)| indices.contains(index))
+            .collect()
+    };
+    let mut storage = world.write_storage::<TracerAtom>();
+    for &(entity, _) in tracers.iter() {
+        storage.insert(entity, TracerAtom).expect("Could not attach tracer to atom.");
+    }
+    let missing: Vec<u64> = indices.iter().copied().filter(|i| !tracers.iter().any(|(_, index)| index == i)).collect();
+    if !missing.is_empty() {
+        println!("Warning: no atoms were found for tracer indices {:?}.", missing);
+    }
+}
+
+/// Appends the position and velocity of each [TracerAtom] to its trajectory in the [PhotonOutput] resource `T`,
+/// every frame. The trajectory of each atom is written to the 'trajectory_{index}' dataset.
+pub struct RecordTracersSystem<T: PhotonOutput = PhotonOutputter> {
+    output: PhantomData<T>,
+}
+impl<T: PhotonOutput> Default for RecordTracersSystem<T> {
+    fn default() -> Self {
+        RecordTracersSystem { output: PhantomData }
+    }
+}
+impl<'a, T: PhotonOutput> System<'a> for RecordTracersSystem<T> {
+    type SystemData = (
+        ReadExpect<'a, T>,
+        Write<'a, OutputError>,
+        Entities<'a>,
+        ReadStorage<'a, TracerAtom>,
+        ReadStorage<'a, AtomIndex>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        Read<'a, FrameNumber>,
+    );
+    fn run(&mut self, (output, mut error, entities, tracers, indices, positions, velocities, frame): Self::SystemData) {
+        for (entity, _, index, position, velocity) in (&entities, &tracers, (&indices).maybe(), &positions, &velocities).join() {
+            let record = TrajectoryRecord {
+                frame: frame.0,
+                x: position.pos[0],
+                y: position.pos[1],
+                z: position.pos[2],
+                vx: velocity.vel[0],
+                vy: velocity.vel[1],
+                vz: velocity.vel[2],
+            };
+            let atom = index.map_or(entity.id() as u64, |index| index.0);
+            error.record(output.append_trajectory(atom, &[record]));
+        }
+    }
+}
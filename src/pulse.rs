@@ -0,0 +1,71 @@
+//! Time-varying power of the imaging beams.
+
+use atomecs::integrator::Timestep;
+use atomecs::laser::gaussian::GaussianBeam;
+use serde::Deserialize;
+use specs::prelude::*;
+
+use crate::photons::FrameNumber;
+
+/// The temporal shape of an imaging pulse.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum PowerProfile {
+    /// The beam is on at full power for the whole exposure.
+    Constant,
+    /// The beam is on at full power between `t_on` and `t_off`, and off otherwise. Times in units of s.
+    Square { t_on: f64, t_off: f64 },
+    /// The beam power follows a gaussian envelope with the given `center` and standard deviation `sigma`, in units of s.
+    Gaussian { center: f64, sigma: f64 },
+}
+impl Default for PowerProfile {
+    fn default() -> Self {
+        PowerProfile::Constant
+    }
+}
+impl PowerProfile {
+    /// Fraction of the peak power at time `t`.
+    pub fn factor(&self, t: f64) -> f64 {
+        match *self {
+            PowerProfile::Constant => 1.0,
+            PowerProfile::Square { t_on, t_off } => {
+                if t >= t_on && t < t_off {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            PowerProfile::Gaussian { center, sigma } => (-(t - center).powi(2) / (2.0 * sigma * sigma)).exp(),
+        }
+    }
+}
+
+/// A component attached to a beam entity to vary the power of its [GaussianBeam] over time.
+pub struct PulsedBeam {
+    /// Power of the beam when the profile is at its maximum, in units of W.
+    pub peak_power: f64,
+    pub profile: PowerProfile,
+}
+impl Component for PulsedBeam {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Sets the power of each [PulsedBeam] for the current frame.
+///
+/// This system must run before the scattering rates are calculated each frame, so it should be run
+/// with `run_now` before the simulation dispatcher, rather than added to the dispatcher.
+pub struct UpdatePulsedBeamPowerSystem;
+impl<'a> System<'a> for UpdatePulsedBeamPowerSystem {
+    type SystemData = (
+        ReadStorage<'a, PulsedBeam>,
+        WriteStorage<'a, GaussianBeam>,
+        Read<'a, FrameNumber>,
+        ReadExpect<'a, Timestep>,
+    );
+    fn run(&mut self, (pulses, mut beams, frame, timestep): Self::SystemData) {
+        let t = frame.0 as f64 * timestep.delta;
+        for (pulse, beam) in (&pulses, &mut beams).join() {
+            beam.power = pulse.peak_power * pulse.profile.factor(t);
+        }
+    }
+}
@@ -0,0 +1,698 @@
+//! Runs the imaging simulation for a [SimulationConfig].
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use atomecs::atom::{Atom, AtomicTransition, Force, Mass, Position, Velocity};
+use atomecs::ecs;
+use atomecs::initiate::NewlyCreated;
+use atomecs::integrator::Timestep;
+use atomecs::laser::gaussian::GaussianBeam;
+use atomecs::laser_cooling::CoolingLight;
+use atomecs::magnetic::uniform::UniformMagneticField;
+use atomecs::output::file;
+use atomecs::output::file::Text;
+use hdf5::{Dataset, File, SliceOrIndex, Error, H5Type};
+use hdf5::types::TypeDescriptor;
+use nalgebra::Vector3;
+use specs::prelude::*;
+
+use crate::cloud::{generate_thermal_cloud, scale_velocities};
+use crate::config::{BeamConfig, SimulationConfig};
+use crate::photons::{DetectionEfficiency, DroppedPhotons, FrameNumber, MaxScatteredPerFrame, PhotonCounter, WarmupSteps};
+use crate::photons::emission::{EmissionStatistics, EmissionStatisticsSystem};
+use crate::photons::list::{AtomIndex, OutputError, PhotonOutput, PhotonSink, RecordInitialAtoms, RecordPhotonAtoms, RegisterFinalAtomsSystem, RegisterInitialAtomsSystem, RegisterPhotonsSystem, seed_atoms, AtomSeed};
+use crate::photons::rate::{ScatteringRateHistory, ScatteringRateOutputSystem, ScatteringRateSystem};
+use crate::photons::threshold::{ExplicitThresholdHistory, ExplicitThresholdOutputSystem, ExplicitThresholdSystem};
+use crate::photons::reabsorption::Reabsorption;
+use crate::photons::recoil::{EmissionRecoil, RecoilDiagnosticSystem};
+use crate::photons::statistics::{PhotonStatisticsSystem, ScatteredPhotonTally, TallyScatteredPhotonsSystem};
+use crate::photons::tracer::{attach_tracers, RecordTracersSystem};
+use crate::pulse::{PulsedBeam, UpdatePulsedBeamPowerSystem};
+use crate::species::{Species, Transition};
+
+/// Inputs and outputs of a simulation run, other than the [SimulationConfig] and photon output.
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions<'a> {
+    /// Atoms to create. If `None`, a thermal cloud is generated from the config instead.
+    pub atoms: Option<&'a [InputAtom]>,
+    /// Path of a text file to which the atom positions are written every 10 frames, if any.
+    pub pos_output: Option<String>,
+    /// If true, progress is not reported during the run.
+    pub quiet: bool,
+    /// If set, the run stops early once this flag is true, e.g. when the user presses Ctrl-C.
+    ///
+    /// The flag is checked after each frame. The final atoms and diagnostics are still written, so the output of the
+    /// frames already simulated is kept.
+    pub stop: Option<&'a AtomicBool>,
+    /// If set, the dispatcher runs on this pool, e.g. one shared by the members of an ensemble, rather than on a new
+    /// pool of [SimulationConfig::threads] threads.
+    pub pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+/// The results of a simulation run.
+pub struct SimulationOutput<T: PhotonOutput> {
+    /// The photon output, which has received all photons emitted during the run.
+    pub output: T,
+    /// Number of frames simulated. This is fewer than [SimulationConfig::n_steps] if the run was stopped early.
+    pub steps: u32,
+    /// Total number of photons emitted, including those which were not output.
+    pub emitted: u64,
+    /// Photons which were emitted, but not output.
+    pub dropped: DroppedPhotons,
+    /// Final position of each atom, in units of m, in the same order as the initial atoms.
+    pub final_positions: Vec<Vector3<f64>>,
+    /// Final velocity of each atom, in units of m/s, in the same order as the initial atoms.
+    pub final_velocities: Vec<Vector3<f64>>,
+}
+
+/// Runs the simulation for the given config, appending photons to `outputter`.
+///
+/// Returns an error if the output could not be written.
+pub fn run_simulation<T: PhotonOutput>(config: &SimulationConfig, options: &RunOptions, outputter: T) -> Result<SimulationOutput<T>, Error> {
+
+    let now = Instant::now();
+
+    // Create the simulation world
+    let mut world = World::new();
+    ecs::register_components(&mut world);
+    ecs::register_resources(&mut world);
+    
+    // Create our dispatcher - which will run the different systems that comprise the simulation.
+    let mut builder =
+        ecs::create_simulation_dispatcher_builder();
+
+    // Add our extra systems, which do things like generate output.
+    //
+    // Our photon output system must run after the total scattered each frame has been calculated.
+    if let Some(pos_output) = &options.pos_output {
+        builder.add(
+            file::new::<Position, Text>(pos_output.clone(), 10),
+            "",
+            &[],
+        );
+    }
+
+    // Output atoms to an h5 file
+    builder.add(RegisterPhotonsSystem::<T>::default(), "", &[]);
+    builder.add(RegisterInitialAtomsSystem::<T>::default(), "", &[]);
+    builder.add(TallyScatteredPhotonsSystem, "", &[]);
+    builder.add(ScatteringRateSystem, "", &[]);
+    builder.add(ExplicitThresholdSystem, "", &[]);
+    builder.add(RecordTracersSystem::<T>::default(), "", &[]);
+    world.insert(outputter);
+
+    // The dispatcher runs systems on its own rayon pool, rather than the global pool, so a limited number of
+    // threads must be given to the dispatcher. The `par_join`s within each system then run on the same pool.
+    if let Some(pool) = &options.pool {
+        builder = builder.with_pool(pool.clone());
+    } else if config.threads > 0 {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .build()
+            .map_err(|e| Error::from(format!("Could not create a pool of {} threads: {}", config.threads, e)))?;
+        builder = builder.with_pool(Arc::new(pool));
+    }
+
+    // // Having defined the dispatcher, we now build it and set up required resources in the world.
+    let mut dispatcher = builder.build();
+    dispatcher.setup(&mut world);
+
+    // The beam power must be updated before the scattering rates are calculated, so this system is run before each dispatch.
+    let mut pulse_system = UpdatePulsedBeamPowerSystem;
+    System::setup(&mut pulse_system, &mut world);
+
+    // Create the input atoms, or alternatively generate a thermal cloud.
+    match options.atoms {
+        Some(atoms) => create_atoms(&mut world, atoms, config.transition),
+        None => {
+            let cloud = &config.thermal_cloud;
+            generate_thermal_cloud(
+                &mut world,
+                cloud.atoms,
+                cloud.temperature,
+                cloud.sigma_position,
+                config.transition.default_species().mass(),
+                config.transition,
+            );
+        }
+    }
+    if config.velocity_scale != Vector3::repeat(1.0) {
+        scale_velocities(&mut world, config.velocity_scale);
+    }
+    if config.recoil_diagnostic {
+        attach_to_atoms::<EmissionRecoil>(&mut world);
+    }
+    if config.photon_statistics {
+        attach_to_atoms::<ScatteredPhotonTally>(&mut world);
+    }
+    if config.emission_statistics {
+        world.insert(EmissionStatistics::default());
+    }
+    if config.scattering_rate_diagnostic {
+        world.insert(ScatteringRateHistory::default());
+    }
+    if config.explicit_threshold_diagnostic {
+        world.insert(ExplicitThresholdHistory::default());
+    }
+    if !config.tracer_atoms.is_empty() {
+        attach_tracers(&mut world, &config.tracer_atoms);
+    }
+    if let Some(seed) = config.seed {
+        seed_atoms(&mut world, seed);
+    }
+
+    // Create the imaging lasers. By default, there is a single beam aligned to the origin, propagating along +x, and with zero detuning.
+    create_beams(&mut world, &config.beams, config.transition);
+    if config.magnetic_field != Vector3::zeros() {
+        world
+            .create_entity()
+            .with(UniformMagneticField::gauss(config.magnetic_field))
+            .build();
+    }
+
+    // Configure scattering fluctuations and emission forces, which are both enabled by default.
+    world.insert(config.emission_force_option());
+    world.insert(config.scattering_fluctuations_option());
+
+    if let Some(region) = config.region_of_interest {
+        world.insert(region);
+    }
+    world.insert(RecordInitialAtoms(config.record_initial_atoms));
+    world.insert(RecordPhotonAtoms(config.record_photon_atoms));
+    if config.detection_efficiency < 1.0 {
+        world.insert(DetectionEfficiency(config.detection_efficiency));
+    }
+    if let Some(reabsorption) = &config.reabsorption {
+        let cross_section = reabsorption.cross_section(config.transition);
+        world.insert(Reabsorption::new(cross_section, reabsorption.cell_size).with_max_scatterings(reabsorption.max_scatterings));
+    }
+
+    // Define timestep - by default we use a small timestep of 0.1 us to keep it so only ~0-1 photons are emitted each frame.
+    world.insert(Timestep { delta: config.timestep });
+    world.insert(WarmupSteps(config.warmup_steps));
+    world.insert(MaxScatteredPerFrame(config.max_scattered_per_frame));
+
+    println!("Initialisation took {} ms.", now.elapsed().as_millis());
+
+    // Run the simulation for a number of steps to generate the output.
+    let n_steps = config.n_steps();
+    let progress_interval = if options.quiet { 0 } else { config.progress_interval };
+    let run_start = Instant::now();
+    world.insert(FrameNumber(0));
+    let mut steps = 0;
+    for i in 0..n_steps {
+        if options.stop.map_or(false, |stop| stop.load(Ordering::Relaxed)) {
+            println!("Stopping early after {} of {} steps.", steps, n_steps);
+            break;
+        }
+        pulse_system.run_now(&world);
+        dispatcher.dispatch(&mut world);
+        world.maintain();
+        world.write_resource::<FrameNumber>().0 += 1;
+        steps += 1;
+        check_output_error(&world)?;
+        if progress_interval > 0 && (i + 1) % progress_interval == 0 {
+            report_progress::<T>(&world, i + 1, n_steps, run_start);
+        }
+    }
+
+    // Store the final state of the atoms, so that the diffusion during imaging can be measured.
+    RegisterFinalAtomsSystem::<T>::default().run_now(&world);
+    if config.recoil_diagnostic {
+        RecoilDiagnosticSystem::<T>::default().run_now(&world);
+    }
+    if config.photon_statistics {
+        PhotonStatisticsSystem::<T>::default().run_now(&world);
+    }
+    if config.emission_statistics {
+        EmissionStatisticsSystem::<T>::default().run_now(&world);
+    }
+    if config.scattering_rate_diagnostic {
+        ScatteringRateOutputSystem::<T>::default().run_now(&world);
+    }
+    if config.explicit_threshold_diagnostic {
+        ExplicitThresholdOutputSystem::<T>::default().run_now(&world);
+    }
+    check_output_error(&world)?;
+
+    let skipped = steps.min(config.warmup_steps);
+    if skipped > 0 {
+        println!("Photons were not recorded during the first {} frames of warmup.", skipped);
+    }
+    if config.warmup_steps > 0 && config.warmup_steps >= n_steps {
+        println!("Warning: the warmup of {} steps covers the whole exposure, so no photons were recorded.", config.warmup_steps);
+    }
+    let dropped = *world.read_resource::<DroppedPhotons>();
+    let emitted = world.read_resource::<PhotonCounter>().emitted();
+    let written = world.read_resource::<T>().photons_written();
+    if written == emitted {
+        println!("{} photons were emitted and written.", emitted);
+    } else {
+        println!("{} photons were emitted, of which {} were written.", emitted, written);
+    }
+    if dropped.non_finite > 0 {
+        println!("Warning: {} photons were dropped because they were emitted at non-finite positions.", dropped.non_finite);
+    }
+    if dropped.outside_region > 0 {
+        println!("{} photons were emitted outside the region of interest and not written.", dropped.outside_region);
+    }
+    if dropped.undetected > 0 {
+        println!("{} photons were not detected and not written.", dropped.undetected);
+    }
+    if let Some(reabsorption) = world.try_fetch::<Reabsorption>() {
+        println!("Photons were reabsorbed {} times before escaping the cloud.", reabsorption.reabsorbed());
+    }
+
+    println!("Simulation completed in {} ms.", now.elapsed().as_millis());
+
+    let (final_positions, final_velocities) = {
+        let atoms = world.read_storage::<Atom>();
+        let positions = world.read_storage::<Position>();
+        let velocities = world.read_storage::<Velocity>();
+        (&atoms, &positions, &velocities).join().map(|(_, p, v)| (p.pos, v.vel)).unzip()
+    };
+    let output = world.remove::<T>().ok_or_else(|| Error::from("The photon output was removed from the world.".to_string()))?;
+    Ok(SimulationOutput { output, steps, emitted, dropped, final_positions, final_velocities })
+}
+
+/// Returns the first error recorded while writing output, if any.
+fn check_output_error(world: &World) -> Result<(), Error> {
+    match &world.read_resource::<OutputError>().0 {
+        Some(error) => Err(Error::from(format!("Unable to write output: {}", error))),
+        None => Ok(()),
+    }
+}
+
+/// Prints the number of steps completed, elapsed and estimated remaining time, and the numbers of photons emitted and written.
+fn report_progress<T: PhotonSink>(world: &World, steps_completed: u32, n_steps: u32, run_start: Instant) {
+    let elapsed = run_start.elapsed().as_secs_f64();
+    let remaining = elapsed / steps_completed as f64 * (n_steps - steps_completed) as f64;
+    println!(
+        "Step {}/{}: {:.1} s elapsed, ~{:.1} s remaining, {} photons emitted, {} written.",
+        steps_completed, n_steps, elapsed, remaining,
+        world.read_resource::<PhotonCounter>().emitted(),
+        world.read_resource::<T>().photons_written()
+    );
+}
+
+/// Attaches a default diagnostic component, such as an [EmissionRecoil] or [ScatteredPhotonTally], to each atom.
+fn attach_to_atoms<C>(world: &mut World)
+where
+    C: Component + Default,
+    C::Storage: Default,
+{
+    world.register::<C>();
+    let entities = world.entities();
+    let atoms = world.read_storage::<Atom>();
+    let mut components = world.write_storage::<C>();
+    for (entity, _) in (&entities, &atoms).join() {
+        components.insert(entity, C::default()).expect("Could not attach diagnostic to atom.");
+    }
+}
+
+/// The positions and velocities of the atoms in a world, which can be restored between the points of a sweep.
+///
+/// Restoring a snapshot is much cheaper than re-reading the input file and rebuilding the world, so a single world
+/// can be reused for each point of a detuning or power sweep, see [reset_world].
+#[derive(Clone, Debug)]
+pub struct AtomSnapshot {
+    atoms: Vec<(Entity, Vector3<f64>, Vector3<f64>)>,
+}
+impl AtomSnapshot {
+    /// Records the current position and velocity of every atom in the world, e.g. immediately after creating them.
+    pub fn take(world: &World) -> Self {
+        let entities = world.entities();
+        let atoms = world.read_storage::<Atom>();
+        let positions = world.read_storage::<Position>();
+        let velocities = world.read_storage::<Velocity>();
+        AtomSnapshot {
+            atoms: (&entities, &atoms, &positions, &velocities).join().map(|(e, _, p, v)| (e, p.pos, v.vel)).collect(),
+        }
+    }
+
+    /// Number of atoms in the snapshot.
+    pub fn len(&self) -> usize {
+        self.atoms.len()
+    }
+
+    /// Returns true if the snapshot contains no atoms.
+    pub fn is_empty(&self) -> bool {
+        self.atoms.is_empty()
+    }
+
+    /// Restores the position and velocity of each atom in the snapshot, and clears the forces acting on it.
+    ///
+    /// Atoms which have since been deleted from the world are skipped.
+    pub fn restore(&self, world: &mut World) {
+        let entities = world.entities();
+        let mut positions = world.write_storage::<Position>();
+        let mut velocities = world.write_storage::<Velocity>();
+        let mut forces = world.write_storage::<Force>();
+        for &(entity, pos, vel) in self.atoms.iter() {
+            if !entities.is_alive(entity) {
+                continue;
+            }
+            if let Some(position) = positions.get_mut(entity) {
+                position.pos = pos;
+            }
+            if let Some(velocity) = velocities.get_mut(entity) {
+                velocity.vel = vel;
+            }
+            if let Some(force) = forces.get_mut(entity) {
+                *force = Force::new();
+            }
+        }
+    }
+}
+
+/// Restores a world to the state captured in `snapshot`, ready for the next point of a sweep.
+///
+/// The atoms are returned to their snapshot positions and velocities, the per-atom diagnostics and photon counters
+/// are zeroed, the frame number is reset, and the imaging beams are replaced by `beams`. Seeded atoms are reseeded,
+/// see [seed_atoms], so each point samples the same photons from the same atom states.
+/// The photon output resource is not changed, so a new output should be inserted for each point if required.
+pub fn reset_world(world: &mut World, snapshot: &AtomSnapshot, beams: &[BeamConfig], transition: Transition) {
+    snapshot.restore(world);
+    reset_diagnostic::<EmissionRecoil>(world);
+    reset_diagnostic::<ScatteredPhotonTally>(world);
+    world.insert(PhotonCounter::default());
+    world.insert(DroppedPhotons::default());
+    world.insert(FrameNumber(0));
+    world.insert(OutputError::default());
+    if world.has_value::<EmissionStatistics>() {
+        world.insert(EmissionStatistics::default());
+    }
+    if world.has_value::<ScatteringRateHistory>() {
+        world.insert(ScatteringRateHistory::default());
+    }
+    if world.has_value::<ExplicitThresholdHistory>() {
+        world.insert(ExplicitThresholdHistory::default());
+    }
+    if let Some(reabsorption) = world.try_fetch::<Reabsorption>() {
+        reabsorption.reset();
+    }
+    let seed = world.try_fetch::<AtomSeed>().map(|seed| seed.0);
+    if let Some(seed) = seed {
+        seed_atoms(world, seed);
+    }
+    recreate_beams(world, beams, transition);
+}
+
+/// Resets each attached diagnostic component, such as an [EmissionRecoil] or [ScatteredPhotonTally], to its default.
+fn reset_diagnostic<C>(world: &mut World)
+where
+    C: Component + Default,
+    C::Storage: Default,
+{
+    world.register::<C>();
+    for component in (&mut world.write_storage::<C>()).join() {
+        *component = C::default();
+    }
+}
+
+/// Deletes all imaging beams from the world, and creates new ones from `beams`.
+pub fn recreate_beams(world: &mut World, beams: &[BeamConfig], transition: Transition) {
+    let old: Vec<Entity> = (&world.entities(), &world.read_storage::<GaussianBeam>()).join().map(|(e, _)| e).collect();
+    world.delete_entities(&old).expect("Could not delete imaging beams.");
+    world.maintain();
+    create_beams(world, beams, transition);
+}
+
+/// Creates an entity for each imaging beam, driving the given transition.
+///
+/// AtomECS calculates the photons scattered from each beam separately, and stores them in the
+/// `ActualPhotonsScatteredVector` of each atom. The photon systems sum over the contents of this vector,
+/// so the photons scattered from all beams are emitted.
+pub fn create_beams(world: &mut World, beams: &[BeamConfig], transition: Transition) {
+    for beam in beams {
+        world
+            .create_entity()
+            .with(GaussianBeam {
+                intersection: beam.intersection,
+                e_radius: beam.e_radius,
+                power: beam.power,
+                direction: beam.direction.normalize(),
+                rayleigh_range: f64::INFINITY,
+                ellipticity: 0.0,
+            })
+            .with(PulsedBeam {
+                peak_power: beam.power,
+                profile: beam.profile,
+            })
+            .with(CoolingLight::for_species(
+                transition.atomic_transition(),
+                beam.detuning,
+                beam.polarization.index(),
+            ))
+            .build();
+    }
+    println!("Created {} imaging beams.", beams.len());
+}
+
+const READ_BATCH_SIZE: usize = 1000;
+
+/// Names of the fields of an input atom, in the order of the positional layout.
+const INPUT_ATOM_FIELDS: [&str; 6] = ["x", "y", "z", "vx", "vy", "vz"];
+
+/// An input atom in a compound dataset with named fields. HDF5 converts the fields by name, so they may be in any order.
+#[derive(H5Type, Clone, PartialEq, Debug)] // register with HDF5
+#[repr(C)]
+pub struct InputAtomPositionRecord {
+    x: f64,
+    y: f64,
+    z: f64,
+    vx: f64,
+    vy: f64,
+    vz: f64
+}
+
+/// How the initial atoms are stored in an input file, see [read_atoms_from_h5].
+enum InputAtomsLayout {
+    /// A compound 'atoms' dataset with fields named x, y, z, vx, vy and vz, which are read by name.
+    Named(Dataset),
+    /// A compound 'atoms' dataset with six unnamed fields, as written from a tuple, read in the order (x,y,z,vx,vy,vz).
+    Positional(Dataset),
+    /// An 'atoms' group, with a 1D dataset for each of x, y, z, vx, vy and vz.
+    Columns(Vec<Dataset>),
+}
+impl InputAtomsLayout {
+    /// Detects the layout of the atoms in the file, returning an error which names any missing fields.
+    fn of(file: &File, path: &str) -> Result<Self, Error> {
+        if let Ok(group) = file.group("atoms") {
+            let columns = INPUT_ATOM_FIELDS.iter()
+                .map(|&name| group.dataset(name).map_err(
+                    |_| Error::from(format!("The 'atoms' group in {} has no '{}' dataset.", path, name))
+                ))
+                .collect::<Result<Vec<Dataset>, Error>>()?;
+            if let Some(column) = columns.iter().find(|column| column.size() != columns[0].size()) {
+                return Err(Error::from(format!(
+                    "The datasets of the 'atoms' group in {} must all have {} rows, but '{}' has {} rows.",
+                    path, columns[0].size(), column.name(), column.size()
+                )));
+            }
+            return Ok(InputAtomsLayout::Columns(columns));
+        }
+        let ds = file.dataset("atoms").map_err(
+            |_| Error::from(format!("{} does not contain an 'atoms' dataset.", path))
+        )?;
+        let names: Vec<String> = match ds.dtype()?.to_descriptor()? {
+            TypeDescriptor::Compound(compound) => compound.fields.iter().map(|field| field.name.clone()).collect(),
+            descriptor => return Err(Error::from(format!(
+                "The 'atoms' dataset in {} should have a compound type with fields (x,y,z,vx,vy,vz), but has type {:?}.",
+                path, descriptor
+            ))),
+        };
+        // Tuples are written with fields named by their position.
+        if names.len() == 6 && names.iter().enumerate().all(|(i, name)| *name == i.to_string()) {
+            return Ok(InputAtomsLayout::Positional(ds));
+        }
+        let missing: Vec<&str> = INPUT_ATOM_FIELDS.iter().copied().filter(|field| !names.iter().any(|name| name == field)).collect();
+        if !missing.is_empty() {
+            return Err(Error::from(format!(
+                "The 'atoms' dataset in {} has fields {:?}, but is missing the fields {:?}.",
+                path, names, missing
+            )));
+        }
+        Ok(InputAtomsLayout::Named(ds))
+    }
+
+    /// Number of atoms in the file.
+    fn len(&self) -> usize {
+        match self {
+            InputAtomsLayout::Named(ds) | InputAtomsLayout::Positional(ds) => ds.size(),
+            InputAtomsLayout::Columns(columns) => columns[0].size(),
+        }
+    }
+
+    /// Reads the (position, velocity) of the selected atoms.
+    fn read(&self, selection: SliceOrIndex) -> Result<Vec<(Vector3<f64>, Vector3<f64>)>, Error> {
+        match self {
+            InputAtomsLayout::Named(ds) => Ok(
+                ds.read_slice_1d::<InputAtomPositionRecord, SliceOrIndex>(selection)?.iter()
+                    .map(|atom| (Vector3::new(atom.x, atom.y, atom.z), Vector3::new(atom.vx, atom.vy, atom.vz)))
+                    .collect()
+            ),
+            InputAtomsLayout::Positional(ds) => Ok(
+                ds.read_slice_1d::<(f64, f64, f64, f64, f64, f64), SliceOrIndex>(selection)?.iter()
+                    .map(|&(x, y, z, vx, vy, vz)| (Vector3::new(x, y, z), Vector3::new(vx, vy, vz)))
+                    .collect()
+            ),
+            InputAtomsLayout::Columns(columns) => {
+                let values = columns.iter()
+                    .map(|column| column.read_slice_1d::<f64, SliceOrIndex>(selection))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok((0..values[0].len())
+                    .map(|i| (
+                        Vector3::new(values[0][i], values[1][i], values[2][i]),
+                        Vector3::new(values[3][i], values[4][i], values[5][i]),
+                    ))
+                    .collect())
+            }
+        }
+    }
+}
+
+/// An initial atom, which can be created in any number of worlds.
+#[derive(Clone, Debug)]
+pub struct InputAtom {
+    /// Initial position, in units of m.
+    pub position: Vector3<f64>,
+    /// Initial velocity, in units of m/s.
+    pub velocity: Vector3<f64>,
+    pub species: Species,
+}
+
+/// An input h5 file of atoms, optionally tagged with the species of all atoms in the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AtomInput {
+    pub path: String,
+    /// If set, all atoms in the file are of this species, and any 'species' dataset in the file is ignored.
+    pub species: Option<Species>,
+}
+impl AtomInput {
+    /// Parses an input given as `path` or `path:code`, where `code` is the [Species::from_code] of all atoms in the file.
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        if let Some((path, code)) = input.rsplit_once(':') {
+            if let Ok(code) = code.parse::<u32>() {
+                let species = Species::from_code(code)
+                    .ok_or_else(|| Error::from(format!("Unknown species code {} for input {}.", code, path)))?;
+                return Ok(AtomInput { path: path.to_string(), species: Some(species) });
+            }
+        }
+        Ok(AtomInput { path: input.to_string(), species: None })
+    }
+}
+
+/// Reads the atoms from each of the input files, and concatenates them in order.
+///
+/// The number of atoms loaded from each file, and in total, is reported.
+pub fn read_atoms_from_h5_files(inputs: &[AtomInput], transition: Transition) -> Result<Vec<InputAtom>, Error> {
+    let mut input_atoms = Vec::new();
+    for input in inputs {
+        let atoms = read_atoms_from_h5(&input.path, input.species, transition)?;
+        println!("Loaded {} atoms from {}.", atoms.len(), input.path);
+        input_atoms.extend(atoms);
+    }
+    if inputs.len() > 1 {
+        println!("Loaded {} atoms in total from {} input files.", input_atoms.len(), inputs.len());
+    }
+    Ok(input_atoms)
+}
+
+/// Reads atoms from the h5 file at the given path.
+///
+/// The atoms are read from an 'atoms' compound dataset with fields named x, y, z, vx, vy and vz, which are mapped by
+/// name, or from an 'atoms' group with a 1D dataset for each of these fields. A compound dataset with six fields named
+/// by their position, as written from a tuple, is read in the order (x,y,z,vx,vy,vz). The layout is detected from the
+/// file, and an error is returned if a field is missing.
+///
+/// If the file contains a 'species' dataset, it must have one entry per atom giving the species code
+/// (see [Species::from_code]). Otherwise, all atoms are the [Transition::default_species].
+/// If `species` is set, all atoms are of that species instead.
+/// Every atom must be of a species which supports the imaging `transition`.
+pub fn read_atoms_from_h5(path: &str, species: Option<Species>, transition: Transition) -> Result<Vec<InputAtom>, Error> {
+    let file = File::open(path)?;
+    let layout = InputAtomsLayout::of(&file, path)?;
+    let n_atoms = layout.len();
+    if n_atoms == 0 {
+        return Err(Error::from(format!("{} has 0 rows in the 'atoms' dataset.", path)));
+    }
+    let species_ds = if species.is_some() { None } else { file.dataset("species").ok() };
+    if let Some(species_ds) = &species_ds {
+        if species_ds.size() != n_atoms {
+            return Err(Error::from(format!(
+                "The 'species' dataset in {} has {} rows, but the 'atoms' dataset has {} rows.",
+                path, species_ds.size(), n_atoms
+            )));
+        }
+    }
+    let mut input_atoms = Vec::with_capacity(n_atoms);
+    
+    for i in (0..n_atoms).step_by(READ_BATCH_SIZE) {
+        let n_to_read = READ_BATCH_SIZE.min(n_atoms-i);
+        let selection = SliceOrIndex::SliceCount{ start: i, step: 1, block: 1, count: n_to_read };
+        let atoms = layout.read(selection)?;
+        let row_species: Vec<Species> = match &species_ds {
+            Some(species_ds) => species_ds.read_slice_1d::<u32, SliceOrIndex>(selection)?
+                .iter()
+                .enumerate()
+                .map(|(j, &code)| Species::from_code(code).ok_or_else(
+                    || Error::from(format!("Unknown species code {} for atom in row {} of {}.", code, i + j, path))
+                ))
+                .collect::<Result<_, _>>()?,
+            None => vec![species.unwrap_or_else(|| transition.default_species()); atoms.len()],
+        };
+        if let Some(j) = row_species.iter().position(|s| !s.supports(transition)) {
+            return Err(Error::from(format!(
+                "Atom in row {} of {} is {:?}, which cannot be imaged on the {:?} transition of the beams.",
+                i + j, path, row_species[j], transition
+            )));
+        }
+        input_atoms.extend(
+            atoms.into_iter().zip(row_species).map(|((position, velocity), species)| InputAtom {
+                position,
+                velocity,
+                species,
+            })
+        );
+    }
+
+    Ok(input_atoms)
+}
+
+/// Creates an entity for each of the input atoms, imaged on the given `transition`.
+///
+/// Each atom is given an [AtomIndex] equal to its index in `atoms`.
+///
+/// The entities are allocated together, and each component storage is fetched once for all atoms, rather than once
+/// per component of each atom as by `world.create_entity()`, which dominates the startup of large clouds.
+pub fn create_atoms(world: &mut World, atoms: &[InputAtom], transition: Transition) {
+    world.register::<AtomIndex>();
+    world.exec(|(entities, mut positions, mut markers, mut forces, mut velocities, mut newly_created, mut transitions, mut masses, mut indices): (
+        Entities,
+        WriteStorage<Position>,
+        WriteStorage<Atom>,
+        WriteStorage<Force>,
+        WriteStorage<Velocity>,
+        WriteStorage<NewlyCreated>,
+        WriteStorage<AtomicTransition>,
+        WriteStorage<Mass>,
+        WriteStorage<AtomIndex>,
+    )| {
+        for ((index, atom), entity) in atoms.iter().enumerate().zip(entities.create_iter()) {
+            let inserted = positions.insert(entity, Position { pos: atom.position })
+                .and(markers.insert(entity, Atom))
+                .and(forces.insert(entity, Force::new()))
+                .and(velocities.insert(entity, Velocity { vel: atom.velocity }))
+                .and(newly_created.insert(entity, NewlyCreated))
+                .and(transitions.insert(entity, atom.species.atomic_transition(transition)))
+                .and(masses.insert(entity, Mass { value: atom.species.mass() }))
+                .and(indices.insert(entity, AtomIndex(index as u64)));
+            inserted.expect("Could not insert the components of a new atom.");
+        }
+    });
+    // Entities allocated atomically are only merged into the world on maintain.
+    world.maintain();
+}
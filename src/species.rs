@@ -0,0 +1,113 @@
+//! Atomic species which can be simulated.
+
+use atomecs::atom::AtomicTransition;
+use serde::Deserialize;
+
+/// The transition used for imaging, corresponding to the [AtomicTransition] constructors provided by atomecs.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+pub enum Transition {
+    /// The rubidium D2 line at 780 nm.
+    Rubidium,
+    /// The broad strontium 461 nm line.
+    Strontium,
+    /// The narrow strontium 689 nm intercombination line.
+    StrontiumRed,
+    /// The narrow erbium 583 nm line.
+    Erbium,
+    /// The broad erbium 401 nm line.
+    Erbium401,
+}
+impl Default for Transition {
+    fn default() -> Self {
+        Transition::Rubidium
+    }
+}
+impl Transition {
+    /// The atomecs [AtomicTransition] for this transition.
+    pub fn atomic_transition(&self) -> AtomicTransition {
+        match self {
+            Transition::Rubidium => AtomicTransition::rubidium(),
+            Transition::Strontium => AtomicTransition::strontium(),
+            Transition::StrontiumRed => AtomicTransition::strontium_red(),
+            Transition::Erbium => AtomicTransition::erbium(),
+            Transition::Erbium401 => AtomicTransition::erbium_401(),
+        }
+    }
+
+    /// The species assumed for atoms when the input file does not specify one.
+    pub fn default_species(&self) -> Species {
+        match self {
+            Transition::Rubidium => Species::Rubidium87,
+            Transition::Strontium | Transition::StrontiumRed => Species::Strontium88,
+            Transition::Erbium | Transition::Erbium401 => Species::Erbium166,
+        }
+    }
+}
+
+/// Frequency of the ⁸⁵Rb D2 cycling transition, F=3 → F'=4, above the ⁸⁷Rb cycling transition, F=2 → F'=3, in
+/// units of Hz.
+///
+/// The isotope shift of the D2 line centroids is only -78 MHz, but the ground and excited state hyperfine splittings
+/// of the two isotopes differ, which puts the cycling transitions 1.13 GHz apart. See D. A. Steck, "Rubidium 85 D Line
+/// Data" and "Rubidium 87 D Line Data".
+pub const RUBIDIUM_85_SHIFT: f64 = 1.1265e9;
+
+/// An atomic species, identified in input files by its mass number.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Species {
+    Rubidium85,
+    Rubidium87,
+    Strontium88,
+    Erbium166,
+}
+impl Default for Species {
+    fn default() -> Self {
+        Species::Rubidium87
+    }
+}
+impl Species {
+    /// Get the species with the given code, which is the mass number of the isotope.
+    ///
+    /// Returns `None` if the code does not correspond to a known species.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            85 => Some(Species::Rubidium85),
+            87 => Some(Species::Rubidium87),
+            88 => Some(Species::Strontium88),
+            166 => Some(Species::Erbium166),
+            _ => None,
+        }
+    }
+
+    /// Returns true if atoms of this species can be imaged on the given transition.
+    pub fn supports(&self, transition: Transition) -> bool {
+        match self {
+            Species::Rubidium85 | Species::Rubidium87 => transition == Transition::Rubidium,
+            Species::Strontium88 => transition == Transition::Strontium || transition == Transition::StrontiumRed,
+            Species::Erbium166 => transition == Transition::Erbium || transition == Transition::Erbium401,
+        }
+    }
+
+    /// The [AtomicTransition] of an atom of this species imaged on `transition`.
+    ///
+    /// The atomecs transitions are those of the default species of each transition, see
+    /// [Transition::default_species]. Other isotopes are shifted by their isotope shift, see [RUBIDIUM_85_SHIFT], so
+    /// that a beam tuned to one isotope is detuned from the other.
+    pub fn atomic_transition(&self, transition: Transition) -> AtomicTransition {
+        let mut atomic = transition.atomic_transition();
+        if *self == Species::Rubidium85 {
+            atomic.frequency += RUBIDIUM_85_SHIFT;
+        }
+        atomic
+    }
+
+    /// Mass of the species, in atomic mass units.
+    pub fn mass(&self) -> f64 {
+        match self {
+            Species::Rubidium85 => 85.0,
+            Species::Rubidium87 => 87.0,
+            Species::Strontium88 => 88.0,
+            Species::Erbium166 => 166.0,
+        }
+    }
+}
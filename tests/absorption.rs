@@ -0,0 +1,18 @@
+//! Tests of the photons removed from the probe, as accumulated by an [AbsorptionImage].
+
+use imaging_diffusion::photons::absorption::AbsorptionImage;
+use nalgebra::Vector3;
+
+#[test]
+fn positions_off_the_image_are_not_deposited() {
+    let image = AbsorptionImage::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-6, [3, 3]);
+    image.deposit(Vector3::new(f64::NAN, 0.0, 0.0), 1.0);
+    image.deposit(Vector3::new(1.0e300, 0.0, 0.0), 1.0);
+    image.deposit(Vector3::new(0.0, -1.0e300, 0.0), 1.0);
+    image.deposit(Vector3::new(2.0e-6, 0.0, 0.0), 1.0);
+    assert!(image.removed_photons().iter().all(|&p| p == 0.0), "A position off the image was deposited: {:?}", image.removed_photons());
+
+    // The center of the image is in the middle pixel.
+    image.deposit(Vector3::zeros(), 2.0);
+    assert_eq!(image.removed_photons()[4], 2.0);
+}
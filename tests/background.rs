@@ -0,0 +1,40 @@
+//! Tests of the uniform background counts added to histograms by a [BackgroundRate].
+
+use imaging_diffusion::photons::camera::CameraHistogram;
+use imaging_diffusion::photons::{BackgroundRate, PhotonHistogram};
+use nalgebra::Vector3;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+const SEED: u64 = 80;
+
+#[test]
+fn background_counts_have_the_expected_mean() {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let rate = BackgroundRate(2.0e3);
+    let (cells, dt, frames) = (1000, 1.0e-6, 5000);
+    let total: u64 = (0..frames).map(|_| rate.sample(cells, dt, &mut rng)).sum();
+    // The mean is 1e4 counts, with a Poisson standard deviation of 100.
+    let expected = rate.0 * dt * (cells * frames) as f64;
+    assert!((total as f64 - expected).abs() < 5.0 * expected.sqrt(), "{} background counts, expected {}.", total, expected);
+
+    assert_eq!(BackgroundRate(0.0).sample(cells, dt, &mut rng), 0);
+}
+
+#[test]
+fn background_is_tallied_separately_from_the_signal() {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let histogram = PhotonHistogram::new_cubic(1.0, 4);
+    histogram.count(Vector3::repeat(0.1));
+    histogram.add_background(500, &mut rng);
+    assert_eq!(histogram.background(), 500);
+    assert_eq!(histogram.counts().iter().sum::<u64>(), 501);
+    // The background is spread over the cells, rather than deposited in one.
+    assert!(histogram.counts().iter().filter(|&&c| c > 0).count() > 32);
+
+    let camera = CameraHistogram::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-6, [8, 8]);
+    camera.count(Vector3::zeros());
+    camera.add_background(200, &mut rng);
+    assert_eq!(camera.background(), 200);
+    assert_eq!(camera.weights().iter().sum::<f64>(), 201.0);
+}
@@ -0,0 +1,132 @@
+//! Tests of projecting photons onto the pixels of a [CameraHistogram].
+
+extern crate atomecs as lib;
+
+mod common;
+
+use imaging_diffusion::photons::camera::{CameraHistogram, CameraHistogramSystem, CollectionModel, NumericalAperture};
+use imaging_diffusion::photons::emission::EmissionPattern;
+use imaging_diffusion::photons::list::seed_atoms;
+use imaging_diffusion::photons::DetectionEfficiency;
+use lib::integrator::Timestep;
+use lib::laser_cooling::force::EmissionForceOption;
+use lib::laser_cooling::photons_scattered::ScatteringFluctuationsOption;
+use nalgebra::Vector3;
+use specs::prelude::*;
+
+#[test]
+fn non_finite_positions_are_not_counted() {
+    let camera = CameraHistogram::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-6, [3, 3]);
+    camera.count(Vector3::new(f64::NAN, 0.0, 0.0));
+    camera.count(Vector3::new(0.0, f64::INFINITY, 0.0));
+    camera.count(Vector3::new(0.0, 0.0, f64::NAN));
+    assert!(camera.weights().iter().all(|&w| w == 0.0), "A non-finite position was counted: {:?}", camera.weights());
+
+    camera.count(Vector3::zeros());
+    assert_eq!(camera.weights().iter().sum::<f64>(), 1.0);
+}
+
+#[test]
+fn distant_positions_are_not_counted() {
+    let camera = CameraHistogram::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-6, [3, 3]);
+    // Far beyond the range of a pixel index, which once overflowed.
+    camera.count(Vector3::new(1.0e300, 0.0, 0.0));
+    camera.count(Vector3::new(0.0, -1.0e300, 0.0));
+    camera.count(Vector3::new(1.0e300, 1.0e300, 0.0));
+    assert!(camera.weights().iter().all(|&w| w == 0.0), "A distant position was counted: {:?}", camera.weights());
+}
+
+/// Total weight counted by the [CameraHistogramSystem] from a cloud symmetric about the image plane, with the given
+/// [CollectionModel] and [EmissionPattern].
+fn collected_weight(model: CollectionModel, pattern: EmissionPattern) -> f64 {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(CameraHistogramSystem, "", &[]);
+    });
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+    world.insert(CameraHistogram::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-5, [64, 64]));
+    world.insert(NumericalAperture(0.5));
+    world.insert(model);
+    world.insert(pattern);
+
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+    // Pairs of atoms either side of the image plane, z = 0.
+    for i in 0..100 {
+        let (x, y) = (((i % 10) as f64 - 4.5) * 1e-5, ((i / 10) as f64 - 4.5) * 1e-5);
+        common::create_atom(&mut world, Vector3::new(x, y, 1e-4), Vector3::zeros());
+        common::create_atom(&mut world, Vector3::new(x, y, -1e-4), Vector3::zeros());
+    }
+
+    common::step(&mut world, &mut dispatcher, 10);
+    let camera = world.read_resource::<CameraHistogram>();
+    camera.weights().iter().sum()
+}
+
+#[test]
+fn rejection_and_solid_angle_weight_collect_the_same_mean() {
+    let rejection = collected_weight(CollectionModel::Rejection, EmissionPattern::Isotropic);
+    let weighted = collected_weight(CollectionModel::SolidAngleWeight, EmissionPattern::Isotropic);
+    assert!(weighted > 100.0, "Too few photons were collected: {}", weighted);
+    // Photons from atoms on either side of the image plane are imaged, so the models agree within shot noise.
+    assert!(
+        (rejection - weighted).abs() < 0.1 * weighted,
+        "Rejection collected {} photons, but the solid angle weight {}.", rejection, weighted
+    );
+}
+
+#[test]
+fn solid_angle_weight_follows_the_emission_pattern() {
+    // A dipole along the optical axis emits few photons into the lens, so the isotropic weight would overcount.
+    let pattern = EmissionPattern::DipoleLinear { axis: Vector3::z() };
+    let rejection = collected_weight(CollectionModel::Rejection, pattern);
+    let weighted = collected_weight(CollectionModel::SolidAngleWeight, pattern);
+    let isotropic = collected_weight(CollectionModel::SolidAngleWeight, EmissionPattern::Isotropic);
+    assert!(weighted > 20.0, "Too few photons were collected: {}", weighted);
+    assert!(
+        (rejection - weighted).abs() < 0.15 * weighted,
+        "Rejection collected {} photons, but the solid angle weight {}.", rejection, weighted
+    );
+    assert!(weighted < 0.5 * isotropic, "The dipole weight {} is not below the isotropic weight {}.", weighted, isotropic);
+}
+
+/// Weights counted by the [CameraHistogramSystem] from seeded atoms, with half of the photons lost to the
+/// [DetectionEfficiency].
+fn seeded_weights(seed: u64) -> Vec<f64> {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(CameraHistogramSystem, "", &[]);
+    });
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+    world.insert(CameraHistogram::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-5, [16, 16]));
+    world.insert(DetectionEfficiency(0.5));
+
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+    for i in 0..10 {
+        common::create_atom(&mut world, Vector3::new((i as f64 - 4.5) * 1e-5, 0.0, 0.0), Vector3::zeros());
+    }
+    seed_atoms(&mut world, seed);
+
+    common::step(&mut world, &mut dispatcher, 5);
+    let camera = world.read_resource::<CameraHistogram>();
+    camera.weights()
+}
+
+#[test]
+fn seeded_detection_losses_are_reproducible() {
+    let first = seeded_weights(1234);
+    assert!(first.iter().sum::<f64>() > 0.0, "No photons were counted.");
+    assert_eq!(first, seeded_weights(1234));
+}
+
+#[test]
+fn rays_from_either_side_of_the_image_plane_are_traced_back_to_it() {
+    let camera = CameraHistogram::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-6, [3, 3]);
+    let direction = Vector3::new(1.0, 0.0, 1.0).normalize();
+    let behind = camera.ray_crossing(Vector3::new(0.0, 0.0, -1e-6), direction).unwrap();
+    let in_front = camera.ray_crossing(Vector3::new(0.0, 0.0, 1e-6), direction).unwrap();
+    assert!((behind - Vector3::new(1e-6, 0.0, 0.0)).norm() < 1e-15);
+    assert!((in_front - Vector3::new(-1e-6, 0.0, 0.0)).norm() < 1e-15);
+    assert!(camera.ray_crossing(Vector3::zeros(), Vector3::x()).is_none());
+}
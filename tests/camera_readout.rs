@@ -0,0 +1,63 @@
+//! Tests of the conversion of camera photon counts to digital counts by a [CameraReadout], and of pixel saturation.
+
+use imaging_diffusion::photons::camera::{Blooming, CameraHistogram, CameraReadout, Saturation};
+use nalgebra::Vector3;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+fn camera(pixels: [usize; 2]) -> CameraHistogram {
+    CameraHistogram::new(Vector3::zeros(), Vector3::x(), Vector3::y(), 1.0e-6, pixels)
+}
+
+#[test]
+fn noiseless_readout_scales_and_clips_the_counts() {
+    let camera = camera([4, 1]);
+    for _ in 0..3 {
+        camera.count(Vector3::new(-1.5e-6, 0.0, 0.0));
+    }
+    camera.count_weighted(Vector3::new(1.5e-6, 0.0, 0.0), 1.0e5);
+    let readout = CameraReadout::new(2.0, 0.0).with_adc(0.5, 10.0);
+
+    let frame = readout.read_out(&camera, &mut StdRng::seed_from_u64(1));
+    // 3 photons give 6 electrons, which are 12 ADU above the offset. The bright pixel saturates the converter.
+    assert_eq!(frame.as_slice().unwrap(), &[22, 10, 10, CameraReadout::MAX_ADU]);
+}
+
+#[test]
+fn read_noise_has_the_configured_rms() {
+    let camera = camera([100, 100]);
+    let readout = CameraReadout::new(1.0, 4.0).with_adc(2.0, 100.0);
+    let frame = readout.read_out(&camera, &mut StdRng::seed_from_u64(2));
+    let n = frame.len() as f64;
+    let mean = frame.iter().map(|&adu| adu as f64).sum::<f64>() / n;
+    let rms = (frame.iter().map(|&adu| (adu as f64 - mean).powi(2)).sum::<f64>() / n).sqrt();
+    assert!((mean - 100.0).abs() < 0.1, "Mean of a dark frame is {}, not the offset.", mean);
+    // 4 electrons of noise is 2 ADU, broadened slightly by the rounding to whole ADU.
+    assert!((rms - 2.0).abs() < 0.1, "Rms of a dark frame is {} ADU, not 2.", rms);
+}
+
+#[test]
+fn saturated_pixels_clip_at_the_full_well() {
+    let camera = camera([3, 1]).with_saturation(Saturation::new(10.0));
+    camera.count_weighted(Vector3::new(-1.5e-6, 0.0, 0.0), 4.0);
+    camera.count_weighted(Vector3::new(0.5e-6, 0.0, 0.0), 25.0);
+
+    assert_eq!(camera.weights(), vec![4.0, 0.0, 25.0]);
+    assert_eq!(camera.saturated_weights(), vec![4.0, 0.0, 10.0]);
+    assert_eq!(camera.saturated_pixels(), 1);
+}
+
+#[test]
+fn blooming_spills_the_overflow_along_the_column() {
+    // A single column of 5 pixels, with a bright pixel in the middle.
+    let camera = camera([1, 5]).with_saturation(Saturation::new(10.0).with_blooming(Blooming::Column));
+    camera.count_weighted(Vector3::new(0.0, -1.5e-6, 0.0), 2.0);
+    camera.count_weighted(Vector3::new(0.0, 0.5e-6, 0.0), 34.0);
+
+    // The overflow of 24 is split equally, and fills the nearest pixels above and below which are not yet full.
+    assert_eq!(camera.saturated_weights(), vec![4.0, 10.0, 10.0, 10.0, 2.0]);
+
+    // Overflow which reaches the end of the column is lost.
+    camera.count_weighted(Vector3::new(0.0, 0.5e-6, 0.0), 20.0);
+    assert_eq!(camera.saturated_weights(), vec![10.0; 5]);
+}
@@ -0,0 +1,67 @@
+//! Helpers for building small simulations in integration tests.
+
+#![allow(dead_code)]
+
+extern crate atomecs as lib;
+
+use lib::atom::{Atom, AtomicTransition, Force, Mass, Position, Velocity};
+use lib::ecs;
+use lib::initiate::NewlyCreated;
+use lib::laser::gaussian::GaussianBeam;
+use lib::laser_cooling::CoolingLight;
+use nalgebra::Vector3;
+use specs::prelude::*;
+
+/// Creates a world with the atomecs components and resources registered, and a dispatcher for the standard
+/// atomecs simulation with the given extra systems added by `add_systems`.
+pub fn create_simulation<F>(add_systems: F) -> (World, Dispatcher<'static, 'static>)
+where
+    F: FnOnce(&mut DispatcherBuilder<'static, 'static>),
+{
+    let mut world = World::new();
+    ecs::register_components(&mut world);
+    ecs::register_resources(&mut world);
+    let mut builder = ecs::create_simulation_dispatcher_builder();
+    add_systems(&mut builder);
+    let mut dispatcher = builder.build();
+    dispatcher.setup(&mut world);
+    (world, dispatcher)
+}
+
+/// Creates a rubidium imaging beam through the origin, with the given direction, detuning (MHz) and polarization.
+pub fn create_beam(world: &mut World, direction: Vector3<f64>, detuning: f64, polarization: i32) -> Entity {
+    world
+        .create_entity()
+        .with(GaussianBeam {
+            intersection: Vector3::zeros(),
+            e_radius: 0.01,
+            power: 0.01,
+            direction: direction.normalize(),
+            rayleigh_range: f64::INFINITY,
+            ellipticity: 0.0,
+        })
+        .with(CoolingLight::for_species(AtomicTransition::rubidium(), detuning, polarization))
+        .build()
+}
+
+/// Creates a rubidium 87 atom with the given position and velocity.
+pub fn create_atom(world: &mut World, pos: Vector3<f64>, vel: Vector3<f64>) -> Entity {
+    world
+        .create_entity()
+        .with(Position { pos })
+        .with(Atom)
+        .with(Force::new())
+        .with(Velocity { vel })
+        .with(NewlyCreated)
+        .with(AtomicTransition::rubidium())
+        .with(Mass { value: 87.0 })
+        .build()
+}
+
+/// Runs the dispatcher for the given number of steps.
+pub fn step(world: &mut World, dispatcher: &mut Dispatcher, steps: usize) {
+    for _ in 0..steps {
+        dispatcher.dispatch(world);
+        world.maintain();
+    }
+}
@@ -0,0 +1,191 @@
+//! End-to-end tests of the photons emitted by atoms in the simulation.
+
+extern crate atomecs as lib;
+
+mod common;
+
+use std::sync::Arc;
+
+use imaging_diffusion::config::BeamConfig;
+use imaging_diffusion::photons::histogram::HistogramSink;
+use imaging_diffusion::photons::list::{seed_atoms, AtomIndex, CollectPhotonsSystem, MemoryPhotonSink, PhotonBroadcast, PhotonBroadcastSystem, PhotonEmission, PhotonSink, RecordPhotonAtoms};
+use imaging_diffusion::photons::PhotonHistogram;
+use imaging_diffusion::photons::{FrameNumber, WarmupSteps};
+use imaging_diffusion::pulse::PulsedBeam;
+use imaging_diffusion::simulation::{create_beams, reset_world, AtomSnapshot};
+use imaging_diffusion::species::Transition;
+use lib::integrator::Timestep;
+use lib::laser_cooling::force::EmissionForceOption;
+use lib::laser_cooling::photons_scattered::ScatteringFluctuationsOption;
+use nalgebra::Vector3;
+use specs::prelude::*;
+
+#[test]
+fn photons_are_emitted_from_atoms_with_unit_directions() {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(CollectPhotonsSystem::default(), "", &[]);
+    });
+    world.insert(MemoryPhotonSink::default());
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+    let positions = [Vector3::new(1e-3, 0.0, 0.0), Vector3::new(0.0, -1e-3, 2e-3)];
+    for &position in positions.iter() {
+        common::create_atom(&mut world, position, Vector3::zeros());
+    }
+
+    common::step(&mut world, &mut dispatcher, 3);
+
+    let photons = world.read_resource::<MemoryPhotonSink>().photons();
+    assert!(!photons.is_empty(), "No photons were emitted.");
+    for photon in photons {
+        // The atoms start at rest, so move much less than their separation over a few microseconds.
+        assert!(
+            positions.iter().any(|p| (photon.position - p).norm() < 1e-5),
+            "Photon emitted at {:?}, which is not at any atom.", photon.position
+        );
+        assert!((photon.direction.norm() - 1.0).abs() < 1e-9, "Photon direction {:?} is not a unit vector.", photon.direction);
+    }
+}
+
+#[test]
+fn photons_are_not_recorded_during_warmup() {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(CollectPhotonsSystem::default(), "", &[]);
+    });
+    world.insert(MemoryPhotonSink::default());
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+    world.insert(FrameNumber(0));
+    world.insert(WarmupSteps(3));
+
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+    common::create_atom(&mut world, Vector3::zeros(), Vector3::zeros());
+
+    for _ in 0..6 {
+        common::step(&mut world, &mut dispatcher, 1);
+        world.write_resource::<FrameNumber>().0 += 1;
+    }
+
+    let photons = world.read_resource::<MemoryPhotonSink>().photons();
+    assert!(!photons.is_empty(), "No photons were recorded after the warmup.");
+    for photon in photons {
+        assert!(photon.frame >= 3, "Photon recorded in frame {}, during the warmup.", photon.frame);
+    }
+}
+
+/// Runs a few frames with seeded atoms created in the given order of indices, and returns the photons sorted by atom.
+fn seeded_photons(order: &[u64]) -> Vec<PhotonEmission> {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(CollectPhotonsSystem::default(), "", &[]);
+    });
+    world.insert(MemoryPhotonSink::default());
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+    world.insert(RecordPhotonAtoms(true));
+
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+    world.register::<AtomIndex>();
+    for &index in order.iter() {
+        let atom = common::create_atom(&mut world, Vector3::new(index as f64 * 1e-4, 0.0, 0.0), Vector3::zeros());
+        world.write_storage::<AtomIndex>().insert(atom, AtomIndex(index)).unwrap();
+    }
+    seed_atoms(&mut world, 1234);
+
+    common::step(&mut world, &mut dispatcher, 5);
+
+    let mut photons = world.read_resource::<MemoryPhotonSink>().photons();
+    // The photons of each atom are emitted in order, so a stable sort keeps them in order.
+    photons.sort_by_key(|photon| photon.atom);
+    photons
+}
+
+#[test]
+fn reset_world_reseeds_the_atoms() {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(CollectPhotonsSystem::default(), "", &[]);
+    });
+    world.insert(MemoryPhotonSink::default());
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+    world.insert(RecordPhotonAtoms(true));
+
+    world.register::<PulsedBeam>();
+    let beams = [BeamConfig::default()];
+    create_beams(&mut world, &beams, Transition::Rubidium);
+    for i in 0..4 {
+        common::create_atom(&mut world, Vector3::new(i as f64 * 1e-4, 0.0, 0.0), Vector3::zeros());
+    }
+    seed_atoms(&mut world, 1234);
+    let snapshot = AtomSnapshot::take(&world);
+
+    common::step(&mut world, &mut dispatcher, 5);
+    let first = world.read_resource::<MemoryPhotonSink>().photons();
+    assert!(!first.is_empty(), "No photons were emitted.");
+
+    reset_world(&mut world, &snapshot, &beams, Transition::Rubidium);
+    world.insert(MemoryPhotonSink::default());
+    common::step(&mut world, &mut dispatcher, 5);
+    let second = world.read_resource::<MemoryPhotonSink>().photons();
+
+    // Without reseeding, the generators would continue from where the first point left them.
+    let fields = |mut photons: Vec<PhotonEmission>| -> Vec<_> {
+        photons.sort_by_key(|photon| photon.atom);
+        photons.into_iter().map(|p| (p.position, p.direction, p.frame, p.frequency)).collect()
+    };
+    assert_eq!(fields(first), fields(second));
+}
+
+#[test]
+fn seeded_atoms_emit_the_same_photons_in_any_order() {
+    let forward = seeded_photons(&[0, 1, 2, 3]);
+    let reverse = seeded_photons(&[3, 2, 1, 0]);
+    assert!(!forward.is_empty(), "No photons were emitted.");
+    assert_eq!(forward.len(), reverse.len());
+    for (a, b) in forward.iter().zip(reverse.iter()) {
+        assert_eq!(a.atom, b.atom);
+        assert_eq!(a.frame, b.frame);
+        assert_eq!(a.direction, b.direction, "Atom {:?} emitted a different photon when created in another order.", a.atom);
+        assert_eq!(a.frequency, b.frequency);
+    }
+}
+
+#[test]
+fn broadcast_sinks_receive_the_same_photons() {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(PhotonBroadcastSystem::default(), "", &[]);
+    });
+    let first = Arc::new(MemoryPhotonSink::default());
+    let second = Arc::new(MemoryPhotonSink::default());
+    let histogram = Arc::new(PhotonHistogram::new_cubic(1e-2, 16));
+    world.insert(
+        PhotonBroadcast::new()
+            .with_sink(first.clone())
+            .with_sink(second.clone())
+            .with_sink(HistogramSink::new(histogram.clone()))
+    );
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+    common::create_atom(&mut world, Vector3::zeros(), Vector3::zeros());
+    common::create_atom(&mut world, Vector3::new(1e-3, 0.0, 0.0), Vector3::zeros());
+
+    common::step(&mut world, &mut dispatcher, 5);
+
+    let photons = first.photons();
+    assert!(!photons.is_empty(), "No photons were emitted.");
+    // The photons are sampled once, so each sink receives identical photons rather than its own sample.
+    let fields = |photons: Vec<PhotonEmission>| -> Vec<_> {
+        photons.into_iter().map(|p| (p.position, p.direction, p.frame)).collect()
+    };
+    assert_eq!(fields(photons.clone()), fields(second.photons()));
+    assert_eq!(histogram.counts().iter().sum::<u64>(), photons.len() as u64);
+    assert_eq!(world.read_resource::<PhotonBroadcast>().photons_written(), photons.len() as u64);
+}
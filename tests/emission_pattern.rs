@@ -0,0 +1,75 @@
+//! Checks the sampled emission directions against the analytic moments of each [EmissionPattern].
+
+use imaging_diffusion::photons::emission::{EmissionPattern, EmissionStatistics};
+use nalgebra::Vector3;
+
+const N_PHOTONS: usize = 200_000;
+
+fn sample_statistics(pattern: &EmissionPattern) -> EmissionStatistics {
+    let mut rng = rand::thread_rng();
+    let mut statistics = EmissionStatistics::default();
+    for _ in 0..N_PHOTONS {
+        statistics.add(&pattern.sample(&mut rng));
+    }
+    statistics
+}
+
+#[test]
+fn sampled_directions_match_expected_moments() {
+    let axis = Vector3::new(1.0, 1.0, 0.0);
+    let patterns = [
+        EmissionPattern::Isotropic,
+        EmissionPattern::DipoleLinear { axis },
+        EmissionPattern::DipoleCircular { axis },
+    ];
+    for pattern in patterns.iter() {
+        let statistics = sample_statistics(pattern);
+        assert_eq!(statistics.photons, N_PHOTONS as u64);
+
+        // Each component of a unit vector has a standard deviation below 1, so the standard error of the mean
+        // over 200,000 photons is below 0.0023.
+        let mean = statistics.mean();
+        assert!(mean.norm() < 0.015, "Mean direction {:?} of {:?} is not zero.", mean, pattern);
+
+        let error = (statistics.second_moment() - pattern.expected_second_moment()).amax();
+        assert!(error < 0.01, "Second moment of {:?} differs from the expected tensor by {}.", pattern, error);
+    }
+}
+
+#[test]
+fn sampled_directions_have_unit_norm() {
+    let mut rng = rand::thread_rng();
+    let patterns = [
+        EmissionPattern::Isotropic,
+        EmissionPattern::DipoleLinear { axis: Vector3::z() },
+        EmissionPattern::DipoleCircular { axis: Vector3::new(0.0, 3.0, 4.0) },
+    ];
+    for pattern in patterns.iter() {
+        for _ in 0..N_PHOTONS {
+            let direction = pattern.sample(&mut rng);
+            assert!(
+                (direction.norm() - 1.0).abs() < 1e-12,
+                "Direction {:?} sampled from {:?} is not a unit vector.", direction, pattern
+            );
+        }
+    }
+}
+
+#[test]
+fn mean_cos_squared_about_the_axis_matches_the_analytic_value() {
+    let mut rng = rand::thread_rng();
+    let axis = Vector3::new(0.0, 3.0, 4.0);
+    // <cos²θ> is 1/3 for isotropic emission, 1/5 for sin²θ and 2/5 for (1+cos²θ)/2.
+    let patterns = [
+        (EmissionPattern::Isotropic, 1.0 / 3.0),
+        (EmissionPattern::DipoleLinear { axis }, 1.0 / 5.0),
+        (EmissionPattern::DipoleCircular { axis }, 2.0 / 5.0),
+    ];
+    for (pattern, expected) in patterns.iter() {
+        let mean = (0..N_PHOTONS)
+            .map(|_| pattern.sample(&mut rng).dot(&axis.normalize()).powi(2))
+            .sum::<f64>() / N_PHOTONS as f64;
+        // cos²θ lies in [0, 1], so the standard error of the mean over 200,000 photons is below 0.0012.
+        assert!((mean - expected).abs() < 0.005, "Mean cos²θ of {:?} is {}, not {}.", pattern, mean, expected);
+    }
+}
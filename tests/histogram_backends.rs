@@ -0,0 +1,82 @@
+//! Checks that every [HistogramBackend] assigns the same positions to the same cells.
+
+use imaging_diffusion::photons::histogram::{CounterType, HistogramBackend};
+use imaging_diffusion::photons::PhotonHistogram;
+use nalgebra::Vector3;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const SEED: u64 = 0x1d1f_f051_0ee5;
+const N_POSITIONS: usize = 100_000;
+/// Cells along each axis. The numbers differ, and one is odd, so that a transposed or off-by-one index is caught.
+const CELLS: [usize; 3] = [7, 12, 16];
+/// Size of the domain along each axis, in units of m.
+const DOMAIN: f64 = 1.0e-3;
+
+fn histogram(backend: HistogramBackend, counter_type: CounterType) -> PhotonHistogram {
+    PhotonHistogram::builder()
+        .domain(Vector3::repeat(DOMAIN))
+        .cells(CELLS)
+        .backend(backend)
+        .counter_type(counter_type)
+        .build()
+        .expect("Could not build histogram.")
+}
+
+/// Random positions spread slightly beyond the domain, so that some fall outside, together with positions on the
+/// cell boundaries, where rounding differences between the backends would show up.
+fn positions() -> Vec<Vector3<f64>> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut positions: Vec<Vector3<f64>> = (0..N_POSITIONS)
+        .map(|_| Vector3::from_fn(|_, _| rng.gen_range(-0.6 * DOMAIN..0.6 * DOMAIN)))
+        .collect();
+    for axis in 0..3 {
+        let cell_size = DOMAIN / CELLS[axis] as f64;
+        for i in 0..=CELLS[axis] {
+            let mut position = Vector3::repeat(1.0e-6);
+            position[axis] = -DOMAIN / 2.0 + i as f64 * cell_size;
+            positions.push(position);
+        }
+    }
+    positions
+}
+
+#[test]
+fn dense_and_sparse_backends_count_identically() {
+    let dense = histogram(HistogramBackend::Dense, CounterType::U64);
+    let dense_u32 = histogram(HistogramBackend::Dense, CounterType::U32);
+    let sparse = histogram(HistogramBackend::Sparse, CounterType::U64);
+    for &position in positions().iter() {
+        dense.count(position);
+        dense_u32.count(position);
+        sparse.count(position);
+    }
+
+    let expected = dense.counts();
+    assert_eq!(expected.len(), CELLS.iter().product::<usize>());
+    assert!(dense.outside() > 0, "No positions fell outside the domain, so the domain edges were not tested.");
+    for (name, other) in [("dense u32", &dense_u32), ("sparse", &sparse)].iter() {
+        let counts = other.counts();
+        assert_eq!(counts.len(), expected.len(), "The {} histogram has the wrong number of cells.", name);
+        for (cell, (a, b)) in expected.iter().zip(counts.iter()).enumerate() {
+            assert_eq!(a, b, "Cell {} of the {} histogram differs from the dense histogram.", cell, name);
+        }
+        assert_eq!(other.outside(), dense.outside(), "The {} histogram counted a different number of positions outside.", name);
+        assert_eq!(other.dropped(), dense.dropped());
+    }
+}
+
+#[test]
+fn dense_and_sparse_backends_accumulate_weights_identically() {
+    let mut rng = StdRng::seed_from_u64(SEED + 1);
+    let dense = histogram(HistogramBackend::Dense, CounterType::U64);
+    let sparse = histogram(HistogramBackend::Sparse, CounterType::U64);
+    for &position in positions().iter() {
+        // Integer weights are stored exactly by both backends.
+        let weight = rng.gen_range(0..5) as f64;
+        dense.count_weighted(position, weight);
+        sparse.count_weighted(position, weight);
+    }
+    assert_eq!(dense.counts(), sparse.counts());
+    assert_eq!(dense.weights(), sparse.weights());
+}
@@ -0,0 +1,67 @@
+//! Tests of down-binning a [PhotonHistogram] into coarser cells.
+
+use imaging_diffusion::photons::histogram::{DownsampleRemainder, HistogramBackend};
+use imaging_diffusion::photons::PhotonHistogram;
+use nalgebra::Vector3;
+
+/// Size of the domain along each axis, in units of m.
+const DOMAIN: f64 = 1.0;
+
+/// The center of each cell of a histogram with `N` cells along each axis, as (x, y, z) indices and position.
+fn cell_centers(n: usize) -> impl Iterator<Item = ([usize; 3], Vector3<f64>)> {
+    let cell = DOMAIN / n as f64;
+    (0..n * n * n).map(move |i| {
+        let indices = [i % n, (i / n) % n, i / (n * n)];
+        let position = Vector3::from_fn(|axis, _| -DOMAIN / 2.0 + (indices[axis] as f64 + 0.5) * cell);
+        (indices, position)
+    })
+}
+
+#[test]
+fn downsampled_cells_sum_blocks_of_cells() {
+    let histogram = PhotonHistogram::new_cubic(DOMAIN, 8);
+    // Count each cell a different number of times, so that a misplaced block would be detected.
+    for ([x, y, z], position) in cell_centers(8) {
+        for _ in 0..(x + 2 * y + 3 * z) {
+            histogram.count(position);
+        }
+    }
+
+    let coarse = histogram.downsampled([2, 4, 1], DownsampleRemainder::Error).expect("Could not downsample.");
+    assert_eq!(coarse.domain_size(), histogram.domain_size());
+    assert_eq!(coarse.lower_corner(), histogram.lower_corner());
+    let counts = coarse.counts();
+    assert_eq!(counts.len(), 4 * 2 * 8);
+    assert_eq!(counts.iter().sum::<u64>(), histogram.counts().iter().sum::<u64>());
+    for (i, &count) in counts.iter().enumerate() {
+        let [cx, cy, z] = [i % 4, (i / 4) % 2, i / 8];
+        let expected: usize = (0..2)
+            .flat_map(|dx| (0..4).map(move |dy| (2 * cx + dx) + 2 * (4 * cy + dy) + 3 * z))
+            .sum();
+        assert_eq!(count, expected as u64, "Coarse cell ({}, {}, {}) has the wrong count.", cx, cy, z);
+    }
+}
+
+#[test]
+fn indivisible_cells_are_truncated_or_rejected() {
+    let histogram = PhotonHistogram::builder()
+        .domain(Vector3::repeat(DOMAIN))
+        .cells([8, 8, 8])
+        .backend(HistogramBackend::Sparse)
+        .build()
+        .expect("Could not build histogram.");
+    for (_, position) in cell_centers(8) {
+        histogram.count(position);
+    }
+
+    assert!(histogram.downsampled([3, 1, 1], DownsampleRemainder::Error).is_err());
+    assert!(histogram.downsampled([0, 1, 1], DownsampleRemainder::Truncate).is_err());
+    assert!(histogram.downsampled([9, 1, 1], DownsampleRemainder::Truncate).is_err());
+
+    // Two coarse cells of 3 along x; the remaining 2 of the 8 cells are discarded.
+    let coarse = histogram.downsampled([3, 1, 1], DownsampleRemainder::Truncate).expect("Could not downsample.");
+    let counts = coarse.counts();
+    assert_eq!(counts.len(), 2 * 8 * 8);
+    assert!(counts.iter().all(|&c| c == 3));
+    assert_eq!(coarse.outside(), 2 * 8 * 8);
+}
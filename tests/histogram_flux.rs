@@ -0,0 +1,68 @@
+//! Tests of accumulating the directions of photons into a [PhotonHistogram], i.e. the flux map.
+
+use imaging_diffusion::photons::histogram::{DownsampleRemainder, HistogramBackend};
+use imaging_diffusion::photons::PhotonHistogram;
+use nalgebra::Vector3;
+
+fn flux_histogram() -> PhotonHistogram {
+    PhotonHistogram::builder()
+        .domain(Vector3::new(1.0, 1.0, 1.0))
+        .cells([4, 4, 4])
+        .flux(true)
+        .build()
+        .expect("Could not build histogram.")
+}
+
+#[test]
+fn mean_direction_is_the_average_photon_direction_of_each_cell() {
+    let histogram = flux_histogram();
+    assert!(histogram.has_flux());
+    let position = Vector3::new(0.1, 0.1, 0.1);
+    histogram.count_directed(position, Vector3::new(1.0, 0.0, 0.0));
+    histogram.count_directed(position, Vector3::new(0.0, 1.0, 0.0));
+    histogram.count_directed(position, Vector3::new(-1.0, 0.0, 0.0));
+    histogram.count_directed(position, Vector3::new(0.0, 1.0, 0.0));
+
+    let counts = histogram.counts();
+    let index = counts.iter().position(|&c| c > 0).expect("No cell was counted.");
+    assert_eq!(counts[index], 4);
+    let flux = histogram.flux().expect("The flux should be enabled.");
+    assert_eq!(flux[index], Vector3::new(0.0, 2.0, 0.0));
+    let directions = histogram.mean_directions().expect("The flux should be enabled.");
+    assert_eq!(directions[index], Vector3::new(0.0, 0.5, 0.0));
+    // Empty cells have no mean direction.
+    assert!(directions.iter().enumerate().all(|(i, d)| i == index || *d == Vector3::zeros()));
+
+    histogram.reset();
+    assert!(histogram.flux().unwrap().iter().all(|f| *f == Vector3::zeros()));
+}
+
+#[test]
+fn flux_is_disabled_by_default_and_summed_when_downsampled() {
+    let plain = PhotonHistogram::new_cubic(1.0, 4);
+    assert!(!plain.has_flux());
+    plain.count_directed(Vector3::zeros(), Vector3::new(0.0, 0.0, 1.0));
+    assert_eq!(plain.counts().iter().sum::<u64>(), 1);
+    assert!(plain.flux().is_none());
+
+    let histogram = flux_histogram();
+    for x in [-0.4, -0.2, 0.1, 0.3].iter() {
+        histogram.count_directed(Vector3::new(*x, 0.1, 0.1), Vector3::new(0.0, 0.0, 1.0));
+    }
+    let coarse = histogram.downsampled([4, 1, 1], DownsampleRemainder::Error).expect("Could not downsample.");
+    let flux = coarse.flux().expect("The flux should be kept when downsampled.");
+    let index = coarse.counts().iter().position(|&c| c > 0).unwrap();
+    assert_eq!(coarse.counts()[index], 4);
+    assert_eq!(flux[index], Vector3::new(0.0, 0.0, 4.0));
+}
+
+#[test]
+fn flux_cannot_be_combined_with_the_sparse_backend() {
+    let result = PhotonHistogram::builder()
+        .domain(Vector3::new(1.0, 1.0, 1.0))
+        .cells([4, 4, 4])
+        .backend(HistogramBackend::Sparse)
+        .flux(true)
+        .build();
+    assert!(result.is_err());
+}
@@ -0,0 +1,243 @@
+//! Tests of how positions are assigned to the cells of a [PhotonHistogram].
+
+use imaging_diffusion::photons::histogram::DomainPolicy;
+use imaging_diffusion::photons::PhotonHistogram;
+use nalgebra::Vector3;
+
+/// Number of cells along each axis. A power of two keeps the cell boundaries exactly representable.
+const N: usize = 8;
+/// Size of the domain along each axis, in units of m.
+const DOMAIN: f64 = 1.0;
+
+/// Counts a single position into a fresh histogram, and returns the (x,y,z) indices of the cell it landed in.
+fn cell_of(position: Vector3<f64>) -> Option<[usize; 3]> {
+    let histogram = PhotonHistogram::new_cubic(DOMAIN, N);
+    histogram.count(position);
+    let counts = histogram.counts();
+    assert!(counts.iter().sum::<u64>() <= 1, "A single position was counted more than once.");
+    counts
+        .iter()
+        .position(|&c| c == 1)
+        .map(|i| [i % N, (i / N) % N, i / (N * N)])
+}
+
+/// A position which lies inside the domain along every axis other than `axis`, where it takes value `x`.
+fn along(axis: usize, x: f64) -> Vector3<f64> {
+    let mut position = Vector3::repeat(0.01);
+    position[axis] = x;
+    position
+}
+
+#[test]
+fn upper_domain_edge_is_outside() {
+    for axis in 0..3 {
+        assert_eq!(cell_of(along(axis, DOMAIN / 2.0)), None);
+    }
+}
+
+#[test]
+fn points_just_inside_the_domain_land_in_the_boundary_cells() {
+    let epsilon = 1e-9;
+    for axis in 0..3 {
+        assert_eq!(cell_of(along(axis, DOMAIN / 2.0 - epsilon)).unwrap()[axis], N - 1);
+        assert_eq!(cell_of(along(axis, -DOMAIN / 2.0 + epsilon)).unwrap()[axis], 0);
+    }
+}
+
+#[test]
+fn lower_domain_edge_is_inside() {
+    for axis in 0..3 {
+        assert_eq!(cell_of(along(axis, -DOMAIN / 2.0)).unwrap()[axis], 0);
+    }
+}
+
+#[test]
+fn points_on_a_cell_boundary_belong_to_the_upper_cell() {
+    let cell_size = DOMAIN / N as f64;
+    for axis in 0..3 {
+        assert_eq!(cell_of(along(axis, 0.0)).unwrap()[axis], N / 2);
+        assert_eq!(cell_of(along(axis, cell_size)).unwrap()[axis], N / 2 + 1);
+        assert_eq!(cell_of(along(axis, -cell_size)).unwrap()[axis], N / 2 - 1);
+    }
+}
+
+#[test]
+fn symmetric_points_land_in_mirror_image_cells() {
+    let cell_size = DOMAIN / N as f64;
+    for axis in 0..3 {
+        for &x in [0.3 * cell_size, 1.7 * cell_size, 3.9 * cell_size].iter() {
+            let upper = cell_of(along(axis, x)).unwrap()[axis];
+            let lower = cell_of(along(axis, -x)).unwrap()[axis];
+            assert_eq!(upper + lower, N - 1, "x = {} is not mirrored about the origin.", x);
+        }
+    }
+}
+
+#[test]
+fn out_of_domain_points_are_not_counted() {
+    for axis in 0..3 {
+        for &x in [DOMAIN, -DOMAIN, 1e3, -1e3].iter() {
+            assert_eq!(cell_of(along(axis, x)), None);
+        }
+    }
+}
+
+#[test]
+fn rectangular_domains_use_per_axis_cell_sizes() {
+    // A domain which is 10 times longer along x than transverse, with the same number of cells on each axis.
+    let domain = Vector3::new(1.0, 0.1, 0.1);
+    let histogram = PhotonHistogram::new(domain, [N, N, N]);
+    assert_eq!(histogram.cell_size, Vector3::new(0.125, 0.0125, 0.0125));
+    assert_eq!(histogram.domain_size(), domain);
+
+    // Inside the domain along x, but well outside it transversely.
+    histogram.count(Vector3::new(0.45, 0.0, 0.0));
+    histogram.count(Vector3::new(0.0, 0.45, 0.0));
+    histogram.count(Vector3::new(0.0, 0.0, 0.45));
+    // Just inside the corner of the domain.
+    histogram.count(Vector3::new(-0.49, 0.049, -0.049));
+
+    let counts = histogram.counts();
+    assert_eq!(counts.iter().sum::<u64>(), 2);
+    let index = |x: usize, y: usize, z: usize| z * N * N + y * N + x;
+    assert_eq!(counts[index(N - 1, N / 2, N / 2)], 1);
+    assert_eq!(counts[index(0, N - 1, 0)], 1);
+}
+
+#[test]
+fn histogram_allocates_only_the_requested_cells() {
+    let histogram = PhotonHistogram::new_cubic(DOMAIN, 64);
+    assert_eq!(histogram.counts().len(), 64 * 64 * 64);
+    let histogram = PhotonHistogram::new(Vector3::repeat(DOMAIN), [4, 2, 3]);
+    assert_eq!(histogram.counts().len(), 4 * 2 * 3);
+}
+
+#[test]
+fn auto_sized_domain_contains_all_positions() {
+    let positions = [
+        Vector3::new(-3e-4, 1e-4, 2e-4),
+        Vector3::new(5e-4, -2e-4, 2e-4),
+        Vector3::new(1e-4, 4e-4, 7e-4),
+        Vector3::new(f64::NAN, 0.0, 0.0),
+    ];
+    // An odd number of cells along x, for which the domain is not centered on the origin of the histogram.
+    let histogram = PhotonHistogram::auto_size(&positions, 1e-6, 1.5, [7, 8, 8]).expect("Could not size histogram.");
+    for &position in positions.iter().take(3) {
+        histogram.count(position);
+    }
+    assert_eq!(histogram.outside(), 0);
+    assert_eq!(histogram.counts().iter().sum::<u64>(), 3);
+
+    // The extent along x is 8e-4, which the growth factor scales to 1.2e-3, so a point 7e-4 from the center is outside.
+    histogram.count(Vector3::new(1e-4 + 7e-4, 1e-4, 4.5e-4));
+    assert_eq!(histogram.outside(), 1);
+}
+
+#[test]
+fn off_origin_cloud_is_counted_about_the_histogram_origin() {
+    // A cloud centered far from the coordinate origin, relative to its size.
+    let center = Vector3::new(0.25, -0.5, 0.75);
+    let histogram = PhotonHistogram::builder()
+        .domain(Vector3::repeat(DOMAIN))
+        .cells([N, N, N - 1])
+        .origin(center)
+        .build()
+        .expect("Could not build histogram.");
+    // The domain is centered on the origin along x and y, which have an even number of cells, but along z the
+    // origin is the lower edge of the middle cell.
+    let cell = DOMAIN / (N - 1) as f64;
+    let lower = histogram.lower_corner();
+    assert_eq!([lower[0], lower[1]], [center[0] - DOMAIN / 2.0, center[1] - DOMAIN / 2.0]);
+    assert!((lower[2] - (center[2] - ((N - 1) / 2) as f64 * cell)).abs() < 1e-12);
+
+    for offset in [Vector3::new(0.1, -0.2, 0.3), Vector3::new(-0.45, 0.45, -0.4), Vector3::zeros()].iter() {
+        histogram.count(center + offset);
+    }
+    assert_eq!(histogram.outside(), 0);
+    // The cloud center lands in the cell whose lower corner is the origin.
+    let index = N / 2 + N * (N / 2) + N * N * ((N - 1) / 2);
+    assert_eq!(histogram.counts()[index], 1);
+
+    // The coordinate origin is far outside the domain.
+    histogram.count(Vector3::zeros());
+    assert_eq!(histogram.outside(), 1);
+}
+
+#[test]
+fn auto_size_requires_finite_positions() {
+    assert!(PhotonHistogram::auto_size(&[], 1e-6, 1.0, [N; 3]).is_err());
+    assert!(PhotonHistogram::auto_size(&[Vector3::repeat(f64::INFINITY)], 1e-6, 1.0, [N; 3]).is_err());
+}
+
+/// Counts a single position into a histogram with the given [DomainPolicy], and returns the cell it landed in.
+fn cell_with_policy(policy: DomainPolicy, position: Vector3<f64>) -> Option<[usize; 3]> {
+    let histogram = PhotonHistogram::builder()
+        .domain(Vector3::repeat(DOMAIN))
+        .cells([N; 3])
+        .domain_policy(policy)
+        .build()
+        .expect("Could not build histogram.");
+    histogram.count(position);
+    let counts = histogram.counts();
+    let cell = counts.iter().position(|&c| c == 1).map(|i| [i % N, (i / N) % N, i / (N * N)]);
+    assert_eq!(histogram.outside(), if cell.is_some() { 0 } else { 1 });
+    cell
+}
+
+#[test]
+fn points_just_outside_each_face_follow_the_domain_policy() {
+    let epsilon = 1e-9;
+    let inside = cell_of(along(0, 0.0)).unwrap()[1];
+    for axis in 0..3 {
+        let below = along(axis, -DOMAIN / 2.0 - epsilon);
+        let above = along(axis, DOMAIN / 2.0 + epsilon);
+
+        assert_eq!(cell_with_policy(DomainPolicy::Clip, below), None);
+        assert_eq!(cell_with_policy(DomainPolicy::Clip, above), None);
+
+        let clamped_below = cell_with_policy(DomainPolicy::ClampToEdge, below).unwrap();
+        let clamped_above = cell_with_policy(DomainPolicy::ClampToEdge, above).unwrap();
+        assert_eq!(clamped_below[axis], 0);
+        assert_eq!(clamped_above[axis], N - 1);
+
+        let wrapped_below = cell_with_policy(DomainPolicy::Wrap, below).unwrap();
+        let wrapped_above = cell_with_policy(DomainPolicy::Wrap, above).unwrap();
+        assert_eq!(wrapped_below[axis], N - 1);
+        assert_eq!(wrapped_above[axis], 0);
+
+        // The other axes are unaffected.
+        for other in (0..3).filter(|&other| other != axis) {
+            for cell in [clamped_below, clamped_above, wrapped_below, wrapped_above].iter() {
+                assert_eq!(cell[other], inside);
+            }
+        }
+    }
+}
+
+#[test]
+fn distant_points_are_clamped_or_wrapped() {
+    let cell_size = DOMAIN / N as f64;
+    for axis in 0..3 {
+        assert_eq!(cell_with_policy(DomainPolicy::ClampToEdge, along(axis, 1e3)).unwrap()[axis], N - 1);
+        assert_eq!(cell_with_policy(DomainPolicy::ClampToEdge, along(axis, -1e3)).unwrap()[axis], 0);
+        // Three domains above the cell at the origin wraps back to the same cell.
+        assert_eq!(cell_with_policy(DomainPolicy::Wrap, along(axis, 3.0 * DOMAIN + 0.5 * cell_size)).unwrap()[axis], N / 2);
+        assert_eq!(cell_with_policy(DomainPolicy::Wrap, along(axis, -2.0 * DOMAIN + 0.5 * cell_size)).unwrap()[axis], N / 2);
+    }
+}
+
+#[test]
+fn profile_sums_the_other_axes() {
+    let histogram = PhotonHistogram::new_cubic(DOMAIN, N);
+    let cell = DOMAIN / N as f64;
+    // Two counts in the first slice along y, at different x and z, and one in the last slice.
+    histogram.count(Vector3::new(-0.4, -0.45, 0.3));
+    histogram.count(Vector3::new(0.2, -0.45, -0.1));
+    histogram.count(Vector3::new(0.0, 0.45, 0.0));
+
+    let profile = histogram.profile(1);
+    assert_eq!(profile.len(), N);
+    assert_eq!(profile[0], (-DOMAIN / 2.0 + cell / 2.0, 2.0));
+    assert_eq!(profile[N - 1], (DOMAIN / 2.0 - cell / 2.0, 1.0));
+    assert_eq!(profile.iter().map(|(_, count)| count).sum::<f64>(), 3.0);
+}
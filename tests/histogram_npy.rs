@@ -0,0 +1,38 @@
+//! Tests of the NumPy export of a [PhotonHistogram].
+
+use std::fs;
+
+use imaging_diffusion::photons::PhotonHistogram;
+use nalgebra::Vector3;
+
+#[test]
+fn npy_file_has_aligned_header_and_c_ordered_counts() {
+    let histogram = PhotonHistogram::new(Vector3::new(4.0, 2.0, 1.0), [4, 2, 1]);
+    // Cell (x=3, y=0, z=0), which is element 3 in (z, y, x) C order.
+    histogram.count(Vector3::new(1.5, -0.5, 0.0));
+    histogram.count(Vector3::new(1.5, -0.5, 0.0));
+
+    let path = std::env::temp_dir().join(format!("imaging_diffusion_histogram_{}.npy", std::process::id()));
+    histogram.write_to_npy(path.to_str().unwrap().to_string()).expect("Could not write npy file.");
+    let bytes = fs::read(&path).expect("Could not read npy file.");
+    let json = fs::read_to_string(path.with_extension("json")).expect("Could not read json file.");
+    fs::remove_file(&path).ok();
+    fs::remove_file(path.with_extension("json")).ok();
+
+    assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+    let header_length = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+    assert_eq!((10 + header_length) % 64, 0, "The data does not start on a 64 byte boundary.");
+    let header = std::str::from_utf8(&bytes[10..10 + header_length]).unwrap();
+    assert!(header.contains("'descr': '<u8'"), "Unexpected header {}", header);
+    assert!(header.contains("'shape': (1, 2, 4)"), "Unexpected header {}", header);
+    assert!(header.ends_with('\n'));
+
+    let counts: Vec<u64> = bytes[10 + header_length..]
+        .chunks(8)
+        .map(|c| u64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]))
+        .collect();
+    assert_eq!(counts, vec![0, 0, 0, 2, 0, 0, 0, 0]);
+    assert!(json.contains("\"cell_number\": [4, 2, 1]"), "Unexpected json {}", json);
+    // With a single cell along z, the origin is the lower edge of that cell.
+    assert!(json.contains("\"lower_corner\": [-2e0, -1e0, 0e0]"), "Unexpected json {}", json);
+}
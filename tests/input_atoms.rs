@@ -0,0 +1,163 @@
+//! Tests of reading the initial atoms from each layout of input file, and of creating them in a world.
+
+use std::path::PathBuf;
+
+use atomecs::atom::{Atom, AtomicTransition, Mass, Position, Velocity};
+use atomecs::ecs;
+use hdf5::H5Type;
+use imaging_diffusion::photons::list::AtomIndex;
+use imaging_diffusion::simulation::{create_atoms, read_atoms_from_h5, InputAtom};
+use imaging_diffusion::species::{Species, Transition, RUBIDIUM_85_SHIFT};
+use nalgebra::Vector3;
+use ndarray::arr1;
+use specs::prelude::*;
+
+/// A uniquely named path in the temporary directory.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("imaging_diffusion_input_{}_{}.h5", name, std::process::id()))
+}
+
+/// The fields of the input atoms, named but not in the order (x,y,z,vx,vy,vz), with an extra field.
+#[derive(H5Type, Clone, Copy)]
+#[repr(C)]
+struct ShuffledRecord {
+    vz: f64,
+    x: f64,
+    vx: f64,
+    y: f64,
+    z: f64,
+    vy: f64,
+    id: u32,
+}
+
+/// A record which is missing the vy and vz fields.
+#[derive(H5Type, Clone, Copy)]
+#[repr(C)]
+struct IncompleteRecord {
+    x: f64,
+    y: f64,
+    z: f64,
+    vx: f64,
+    velocity: f64,
+}
+
+const ATOMS: [(f64, f64, f64, f64, f64, f64); 2] = [(1.0, 2.0, 3.0, 4.0, 5.0, 6.0), (-1.0, -2.0, -3.0, -4.0, -5.0, -6.0)];
+
+/// Writes a file with `write`, reads its atoms, and deletes it.
+fn read_back<F: Fn(&hdf5::File)>(name: &str, write: F) -> Result<Vec<(Vector3<f64>, Vector3<f64>)>, hdf5::Error> {
+    let path = temp_path(name);
+    {
+        let file = hdf5::File::create(&path).expect("Could not create input file.");
+        write(&file);
+    }
+    let atoms = read_atoms_from_h5(path.to_str().unwrap(), None, Transition::default());
+    std::fs::remove_file(&path).ok();
+    atoms.map(|atoms| atoms.into_iter().map(|atom| (atom.position, atom.velocity)).collect())
+}
+
+fn expected() -> Vec<(Vector3<f64>, Vector3<f64>)> {
+    ATOMS.iter().map(|&(x, y, z, vx, vy, vz)| (Vector3::new(x, y, z), Vector3::new(vx, vy, vz))).collect()
+}
+
+#[test]
+fn named_fields_are_read_by_name() {
+    let records: Vec<ShuffledRecord> = ATOMS.iter()
+        .map(|&(x, y, z, vx, vy, vz)| ShuffledRecord { vz, x, vx, y, z, vy, id: 7 })
+        .collect();
+    let atoms = read_back("named", |file| {
+        file.new_dataset_builder().with_data(&arr1(&records)).create("atoms").unwrap();
+    }).expect("Could not read named atoms.");
+    assert_eq!(atoms, expected());
+}
+
+#[test]
+fn tuple_fields_are_read_by_position() {
+    let atoms = read_back("positional", |file| {
+        file.new_dataset_builder().with_data(&arr1(&ATOMS)).create("atoms").unwrap();
+    }).expect("Could not read positional atoms.");
+    assert_eq!(atoms, expected());
+}
+
+#[test]
+fn a_group_of_columns_is_read_by_name() {
+    let atoms = read_back("columns", |file| {
+        let group = file.create_group("atoms").unwrap();
+        let columns: [(&str, fn(&(f64, f64, f64, f64, f64, f64)) -> f64); 6] = [
+            ("x", |a| a.0), ("y", |a| a.1), ("z", |a| a.2), ("vx", |a| a.3), ("vy", |a| a.4), ("vz", |a| a.5),
+        ];
+        // The columns are created in a different order to the fields, as they are looked up by name.
+        for (name, value) in columns.iter().rev() {
+            let data: Vec<f64> = ATOMS.iter().map(value).collect();
+            group.new_dataset_builder().with_data(&arr1(&data)).create(*name).unwrap();
+        }
+    }).expect("Could not read atoms from columns.");
+    assert_eq!(atoms, expected());
+}
+
+#[test]
+fn missing_fields_are_an_error() {
+    let records: Vec<IncompleteRecord> = ATOMS.iter()
+        .map(|&(x, y, z, vx, vy, _)| IncompleteRecord { x, y, z, vx, velocity: vy })
+        .collect();
+    let error = read_back("incomplete", |file| {
+        file.new_dataset_builder().with_data(&arr1(&records)).create("atoms").unwrap();
+    }).expect_err("Atoms with missing fields were read.");
+    let message = error.to_string();
+    assert!(message.contains("vy") && message.contains("vz"), "The error '{}' does not name the missing fields.", message);
+}
+
+#[test]
+fn created_atoms_have_their_index_and_components() {
+    let mut world = World::new();
+    ecs::register_components(&mut world);
+    ecs::register_resources(&mut world);
+    let atoms: Vec<InputAtom> = expected().into_iter().enumerate().map(|(i, (position, velocity))| InputAtom {
+        position,
+        velocity,
+        species: if i % 2 == 0 { Species::Rubidium87 } else { Species::Rubidium85 },
+    }).collect();
+
+    create_atoms(&mut world, &atoms, Transition::Rubidium);
+
+    let entities = world.entities();
+    let (markers, positions, velocities, masses, indices) = (
+        world.read_storage::<Atom>(),
+        world.read_storage::<Position>(),
+        world.read_storage::<Velocity>(),
+        world.read_storage::<Mass>(),
+        world.read_storage::<AtomIndex>(),
+    );
+    let mut created = 0;
+    for (_, _, position, velocity, mass, index) in (&entities, &markers, &positions, &velocities, &masses, &indices).join() {
+        let atom = &atoms[index.0 as usize];
+        assert_eq!(position.pos, atom.position);
+        assert_eq!(velocity.vel, atom.velocity);
+        assert_eq!(mass.value, atom.species.mass());
+        created += 1;
+    }
+    assert_eq!(created, atoms.len());
+}
+
+#[test]
+fn rubidium_85_is_detuned_from_the_rubidium_87_line() {
+    let mut world = World::new();
+    ecs::register_components(&mut world);
+    ecs::register_resources(&mut world);
+    let atoms: Vec<InputAtom> = [Species::Rubidium87, Species::Rubidium85].iter().map(|&species| InputAtom {
+        position: Vector3::zeros(),
+        velocity: Vector3::zeros(),
+        species,
+    }).collect();
+
+    create_atoms(&mut world, &atoms, Transition::Rubidium);
+
+    let transitions = world.read_storage::<AtomicTransition>();
+    let indices = world.read_storage::<AtomIndex>();
+    let mut frequencies = [0.0; 2];
+    for (transition, index) in (&transitions, &indices).join() {
+        frequencies[index.0 as usize] = transition.frequency;
+    }
+    assert_eq!(frequencies[0], Transition::Rubidium.atomic_transition().frequency);
+    // The cycling transitions of the two isotopes are far further apart than the 6 MHz natural linewidth.
+    assert!((frequencies[1] - frequencies[0] - RUBIDIUM_85_SHIFT).abs() < 1.0);
+}
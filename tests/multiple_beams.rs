@@ -0,0 +1,51 @@
+//! Tests of the scattering of photons from more than one imaging beam.
+
+extern crate atomecs as lib;
+
+mod common;
+
+use lib::integrator::Timestep;
+use lib::laser::gaussian::GaussianBeam;
+use lib::laser_cooling::photons_scattered::{ActualPhotonsScatteredVector, ScatteringFluctuationsOption};
+use lib::magnetic::uniform::UniformMagneticField;
+use nalgebra::Vector3;
+use specs::prelude::*;
+
+/// Power of each beam, in units of W, well below saturation so that the scattering rate is linear in intensity.
+const WEAK_POWER: f64 = 1.0e-4;
+
+/// Number of photons scattered in a frame by a stationary rubidium atom, from resonant beams along the given directions.
+fn photons_scattered(directions: &[Vector3<f64>]) -> f64 {
+    let (mut world, mut dispatcher) = common::create_simulation(|_| {});
+    for &direction in directions {
+        let beam = common::create_beam(&mut world, direction, 0.0, 1);
+        world.write_storage::<GaussianBeam>().get_mut(beam).expect("Beam has no GaussianBeam.").power = WEAK_POWER;
+    }
+    world
+        .create_entity()
+        .with(UniformMagneticField::gauss(Vector3::zeros()))
+        .build();
+    let atom = common::create_atom(&mut world, Vector3::zeros(), Vector3::zeros());
+
+    // Without fluctuations, the actual number of photons scattered equals the expected number.
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(Timestep { delta: 1.0e-7 });
+    common::step(&mut world, &mut dispatcher, 2);
+
+    let scattered = world.read_storage::<ActualPhotonsScatteredVector>();
+    scattered.get(atom).expect("Atom has no scattered photons.").contents.iter().map(|a| a.scattered).sum()
+}
+
+#[test]
+fn counter_propagating_beams_double_the_scattering() {
+    let one = photons_scattered(&[Vector3::z()]);
+    let two = photons_scattered(&[Vector3::z(), -Vector3::z()]);
+    assert!(one > 0.0);
+    // Both beams are weak, so the saturation they share barely reduces the scattering from each.
+    let ratio = two / one;
+    assert!(
+        (ratio - 2.0).abs() < 0.1,
+        "Two identical counter-propagating beams should scatter about twice as many photons as one, but scattered {} times as many.",
+        ratio
+    );
+}
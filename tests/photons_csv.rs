@@ -0,0 +1,50 @@
+//! Tests of reading photon lists written by the `WritePhotonsSystem`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use imaging_diffusion::photons::read_photons_csv;
+use nalgebra::Vector3;
+
+/// Writes `contents` to a uniquely named file in the temporary directory, and returns its path.
+fn write_temp(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("imaging_diffusion_{}_{}.csv", name, std::process::id()));
+    fs::write(&path, contents).expect("Could not write temporary file.");
+    path
+}
+
+#[test]
+fn reads_photons_in_writer_format() {
+    // The writer formats each value with `{:?}`.
+    let position = Vector3::new(1.5e-6, -2.0e-4, 0.0);
+    let direction = Vector3::new(0.6, -0.8, 0.0);
+    let line = format!(
+        "{:?},{:?},{:?},{:?},{:?},{:?}\n",
+        position[0], position[1], position[2], direction[0], direction[1], direction[2]
+    );
+    let path = write_temp("round_trip", &format!("{}\n{}", line, line));
+
+    let photons = read_photons_csv(path.to_str().unwrap()).unwrap();
+    fs::remove_file(&path).ok();
+
+    assert_eq!(photons.len(), 2);
+    for photon in photons {
+        assert_eq!(photon.position, position);
+        assert_eq!(photon.direction, direction);
+        assert_eq!(photon.frequency, None);
+    }
+}
+
+#[test]
+fn malformed_lines_report_line_number_and_content() {
+    let path = write_temp("malformed", "0.0,0.0,0.0,1.0,0.0,0.0\n0.0,0.0,oops,1.0,0.0,0.0\n");
+    let error = read_photons_csv(path.to_str().unwrap()).unwrap_err().to_string();
+    fs::remove_file(&path).ok();
+    assert!(error.contains("line 2"), "{}", error);
+    assert!(error.contains("oops"), "{}", error);
+
+    let path = write_temp("short", "0.0,0.0,0.0,1.0,0.0\n");
+    let error = read_photons_csv(path.to_str().unwrap()).unwrap_err().to_string();
+    fs::remove_file(&path).ok();
+    assert!(error.contains("Line 1"), "{}", error);
+}
@@ -0,0 +1,176 @@
+//! Tests of reading photon datasets written by the `PhotonOutputter`.
+
+use std::path::PathBuf;
+
+use hdf5::types::VarLenUnicode;
+use hdf5::{H5Type, SimpleExtents};
+use imaging_diffusion::photons::list::{
+    read_photons_h5, MemoryPhotonSink, PhotonEmission, PhotonOutputter, PhotonOutputterOptions, PhotonPrecision, PhotonSink, WRITE_BATCH_SIZE,
+};
+use nalgebra::Vector3;
+use ndarray::arr1;
+
+/// A uniquely named path in the temporary directory.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("imaging_diffusion_{}_{}.h5", name, std::process::id()))
+}
+
+fn photon(i: u32) -> PhotonEmission {
+    PhotonEmission {
+        position: Vector3::new(i as f64 * 1e-6, -2e-6, 3e-6),
+        direction: Vector3::new(0.0, 0.6, -0.8),
+        frequency: Some(3.8e14 + i as f64),
+        frame: i,
+        source: i % 2,
+        atom: if i % 3 == 0 { None } else { Some(i as u64) },
+    }
+}
+
+#[test]
+fn photons_round_trip_through_both_layouts() {
+    let photons: Vec<PhotonEmission> = (0..5).map(photon).collect();
+    for &extended in [false, true].iter() {
+        let path = temp_path(if extended { "named_extended" } else { "named" });
+        let name = path.to_str().unwrap().to_string();
+        {
+            let outputter = if extended {
+                PhotonOutputter::new_extended(name.clone())
+            } else {
+                PhotonOutputter::new(name.clone())
+            }.expect("Could not create output file.");
+            assert_eq!(outputter.records_frequency(), extended);
+            outputter.append_photons(&photons).expect("Could not write photons.");
+        }
+
+        let read = read_photons_h5(&name, "photons").expect("Could not read photons.");
+        std::fs::remove_file(&path).ok();
+        assert_eq!(read.len(), photons.len());
+        for (a, b) in photons.iter().zip(read.iter()) {
+            assert_eq!(a.position, b.position);
+            assert_eq!(a.direction, b.direction);
+            if extended {
+                assert_eq!(a.frequency, b.frequency);
+                assert_eq!((a.frame, a.source, a.atom), (b.frame, b.source, b.atom));
+            } else {
+                assert_eq!((b.frequency, b.frame, b.source, b.atom), (None, 0, 0, None));
+            }
+        }
+    }
+}
+
+/// The tuple layout written by earlier versions, with fields named `0`-`5`.
+#[derive(H5Type, Clone)]
+#[repr(C)]
+struct TupleRecord(f64, f64, f64, f64, f64, f64);
+
+#[test]
+fn reads_photons_with_the_legacy_tuple_layout() {
+    let path = temp_path("legacy");
+    let name = path.to_str().unwrap().to_string();
+    {
+        let file = hdf5::File::create(&name).unwrap();
+        let records = [TupleRecord(1.0, 2.0, 3.0, 0.0, 0.0, 1.0), TupleRecord(-1.0, -2.0, -3.0, 1.0, 0.0, 0.0)];
+        let dataset = file.new_dataset_builder().empty::<TupleRecord>().shape(SimpleExtents::new(&[(2, None)])).chunk(2).create("photons").unwrap();
+        dataset.write(&arr1(&records)).unwrap();
+    }
+
+    let read = read_photons_h5(&name, "photons").expect("Could not read legacy photons.");
+    std::fs::remove_file(&path).ok();
+    assert_eq!(read.len(), 2);
+    assert_eq!(read[0].position, Vector3::new(1.0, 2.0, 3.0));
+    assert_eq!(read[0].direction, Vector3::z());
+    assert_eq!(read[1].position, Vector3::new(-1.0, -2.0, -3.0));
+    assert_eq!(read[1].direction, Vector3::x());
+}
+
+#[test]
+fn builder_declares_fields_and_rejects_inconsistent_photons() {
+    let path = temp_path("builder");
+    let name = path.to_str().unwrap().to_string();
+    let outputter = PhotonOutputter::builder(&name).frame(true).atom(true).build().expect("Could not create output file.");
+    assert!(outputter.extended);
+    // The frequency column is not enabled, so the frequency of each photon need not be sampled.
+    assert!(!outputter.records_frequency());
+
+    let fields = outputter.file.dataset("photons").unwrap().attr("fields").unwrap().read_raw::<VarLenUnicode>().unwrap();
+    let fields: Vec<&str> = fields.iter().map(|f| f.as_str()).collect();
+    assert_eq!(fields, vec!["px", "py", "pz", "dx", "dy", "dz", "frame", "atom"]);
+
+    // Photon 0 has no atom index, so the whole batch is rejected before anything is written.
+    assert!(outputter.append_photons(&[photon(1), photon(0)]).is_err());
+    assert_eq!(outputter.file.dataset("photons").unwrap().size(), 0);
+    outputter.append_photons(&[photon(1), photon(2)]).expect("Could not write photons.");
+    drop(outputter);
+
+    let read = read_photons_h5(&name, "photons").expect("Could not read photons.");
+    std::fs::remove_file(&path).ok();
+    assert_eq!(read.iter().map(|p| p.atom).collect::<Vec<_>>(), vec![Some(1), Some(2)]);
+}
+
+#[test]
+fn repeated_appends_grow_the_dataset_in_order() {
+    let path = temp_path("append");
+    let name = path.to_str().unwrap().to_string();
+    // The last batch is larger than a write batch, so it is split across several writes.
+    let batches = [3, 0, 1, WRITE_BATCH_SIZE + 7, 2];
+    let mut next = 0;
+    {
+        let outputter = PhotonOutputter::new_extended(name.clone()).expect("Could not create output file.");
+        for &size in batches.iter() {
+            let batch: Vec<PhotonEmission> = (next..next + size as u32).map(photon).collect();
+            next += size as u32;
+            outputter.append_photons(&batch).expect("Could not write photons.");
+            // The cached handle follows the resizes, and agrees with a handle opened afresh.
+            assert_eq!(outputter.photons_dataset().size(), next as usize);
+            assert_eq!(outputter.file.dataset("photons").unwrap().size(), next as usize);
+        }
+        assert_eq!(outputter.photons_written(), next as u64);
+    }
+
+    let read = read_photons_h5(&name, "photons").expect("Could not read photons.");
+    std::fs::remove_file(&path).ok();
+    assert_eq!(read.len(), batches.iter().sum::<usize>());
+    for (i, p) in read.iter().enumerate() {
+        let expected = photon(i as u32);
+        assert_eq!(p.frame, expected.frame, "Photon {} is out of order.", i);
+        assert_eq!(p.position, expected.position);
+        assert_eq!(p.atom, expected.atom);
+    }
+}
+
+#[test]
+fn single_precision_photons_round_trip() {
+    let photons: Vec<PhotonEmission> = (0..5).map(photon).collect();
+    let path = temp_path("single");
+    let name = path.to_str().unwrap().to_string();
+    {
+        let options = PhotonOutputterOptions { extended: true, precision: PhotonPrecision::Single, ..Default::default() };
+        let outputter = PhotonOutputter::with_options(name.clone(), options).expect("Could not create output file.");
+        outputter.append_photons(&photons).expect("Could not write photons.");
+        let precision = outputter.file.dataset("photons").unwrap().attr("precision").unwrap().read_raw::<VarLenUnicode>().unwrap();
+        assert_eq!(precision[0].as_str(), "f32");
+    }
+
+    let read = read_photons_h5(&name, "photons").expect("Could not read photons.");
+    std::fs::remove_file(&path).ok();
+    assert_eq!(read.len(), photons.len());
+    for (a, b) in photons.iter().zip(read.iter()) {
+        assert!((a.position - b.position).amax() <= 1e-7 * a.position.amax());
+        assert!((a.direction - b.direction).amax() <= 1e-7);
+        // The frequency is kept in double precision.
+        assert_eq!(a.frequency, b.frequency);
+        assert_eq!((a.frame, a.source, a.atom), (b.frame, b.source, b.atom));
+    }
+}
+
+#[test]
+fn memory_sink_counts_photons_taken_from_it() {
+    let sink = MemoryPhotonSink::default();
+    let photons: Vec<PhotonEmission> = (0..3).map(photon).collect();
+    sink.append_photons(&photons).unwrap();
+    assert_eq!(sink.take().len(), 3);
+    sink.append_photons(&photons[..1]).unwrap();
+    // The photons taken were still written, so the count does not fall when the sink is drained.
+    assert_eq!(sink.photons().len(), 1);
+    assert_eq!(sink.photons_written(), 4);
+}
@@ -0,0 +1,122 @@
+//! Tests of the approximate reabsorption of photons in optically thick clouds.
+
+extern crate atomecs as lib;
+
+mod common;
+
+use imaging_diffusion::photons::emission::EmissionPattern;
+use imaging_diffusion::photons::list::{AtomIndex, CollectPhotonsSystem, MemoryPhotonSink, RecordPhotonAtoms, RegionOfInterest};
+use imaging_diffusion::photons::reabsorption::{DensityGrid, GridAtom, Reabsorption};
+use imaging_diffusion::photons::{DroppedPhotons, PhotonCountingMode};
+use lib::atom::AtomicTransition;
+use lib::integrator::Timestep;
+use lib::laser_cooling::force::EmissionForceOption;
+use lib::laser_cooling::photons_scattered::ScatteringFluctuationsOption;
+use nalgebra::Vector3;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use specs::prelude::*;
+
+/// Size of a cell of the density grid, in units of m.
+const CELL: f64 = 1.0e-6;
+/// Atoms per cell of the cube.
+const PER_CELL: usize = 4;
+/// Cells along each edge of the cube.
+const EDGE: i32 = 10;
+
+/// Creates a cube of `EDGE` cells along each axis, starting at the origin, with `PER_CELL` atoms at the center of each cell.
+fn cube(world: &mut World) -> Vec<GridAtom> {
+    let mut atoms = Vec::new();
+    for i in 0..EDGE {
+        for j in 0..EDGE {
+            for k in 0..EDGE {
+                let position = (Vector3::new(i as f64, j as f64, k as f64) + Vector3::repeat(0.5)) * CELL;
+                for _ in 0..PER_CELL {
+                    atoms.push(GridAtom { entity: world.create_entity().build(), position, index: None });
+                }
+            }
+        }
+    }
+    atoms
+}
+
+#[test]
+fn column_density_through_a_uniform_cube() {
+    let mut world = World::new();
+    let grid = DensityGrid::new(CELL, cube(&mut world));
+    let density = PER_CELL as f64 / CELL.powi(3);
+    let center = Vector3::repeat(0.5 * EDGE as f64 * CELL);
+    assert_eq!(grid.density(&center), density);
+    assert_eq!(grid.density(&Vector3::repeat(-CELL)), 0.0);
+
+    // From the center to the face of the cube, with half-cell steps from a quarter cell past the center.
+    let column = grid.column_density(center, Vector3::x());
+    let expected = density * 0.5 * EDGE as f64 * CELL;
+    assert!((column - expected).abs() <= density * CELL, "Column density {} is not close to {}.", column, expected);
+}
+
+#[test]
+fn photons_escape_a_transparent_cloud_and_are_reabsorbed_in_an_opaque_one() {
+    let mut world = World::new();
+    let atoms = cube(&mut world);
+    let emitter = atoms[atoms.len() / 2];
+    let grid = DensityGrid::new(CELL, atoms);
+    let pattern = EmissionPattern::default();
+    let mut rng = StdRng::seed_from_u64(5);
+
+    let transparent = Reabsorption::new(0.0, CELL);
+    let mut absorptions = Vec::new();
+    let (escaped, position, direction) = transparent.scatter(&grid, &pattern, emitter, emitter.position, Vector3::x(), &mut absorptions, &mut rng);
+    assert_eq!((escaped.entity, position, direction), (emitter.entity, emitter.position, Vector3::x()));
+    assert!(absorptions.is_empty());
+    assert_eq!(transparent.reabsorbed(), 0);
+
+    // An optical depth of 100 across a cell, so the photon is absorbed by another atom at every step.
+    let opaque = Reabsorption::new(100.0 * CELL.powi(3) / PER_CELL as f64 / CELL, CELL).with_max_scatterings(3);
+    let (escaped, position, _) = opaque.scatter(&grid, &pattern, emitter, emitter.position, Vector3::x(), &mut absorptions, &mut rng);
+    assert_eq!(opaque.reabsorbed(), 3);
+    assert_eq!(absorptions.len(), 3);
+    assert_eq!(absorptions[0].absorbed, Vector3::x());
+    assert_eq!(escaped.entity, absorptions[2].entity);
+    assert_eq!(position, escaped.position);
+    assert_ne!(absorptions[0].entity, emitter.entity, "The emitter absorbed its own photon.");
+}
+
+#[test]
+fn reabsorbed_photons_take_the_region_and_frequency_of_the_last_emitter() {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(CollectPhotonsSystem::default(), "", &[]);
+    });
+    world.insert(MemoryPhotonSink::default());
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(PhotonCountingMode::Poisson);
+    world.insert(Timestep { delta: 1.0e-8 });
+    world.insert(RecordPhotonAtoms(true));
+
+    // Two atoms in a single 1 mm cell, which is so opaque that each photon is reabsorbed by the other atom. The
+    // moving atom travels across the beam, so both scatter alike, but photons it re-emits are Doppler shifted.
+    let cell = 1.0e-3;
+    world.insert(Reabsorption::new(1.0, cell).with_max_scatterings(1));
+    let still = Vector3::new(0.1, 0.5, 0.5) * cell;
+    world.insert(RegionOfInterest { min: still - Vector3::repeat(0.05 * cell), max: still + Vector3::repeat(0.05 * cell) });
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+    world.register::<AtomIndex>();
+    let atoms = [(still, Vector3::zeros()), (Vector3::new(0.9, 0.5, 0.5) * cell, Vector3::new(0.0, 50.0, 0.0))];
+    for (index, &(position, velocity)) in atoms.iter().enumerate() {
+        let atom = common::create_atom(&mut world, position, velocity);
+        world.write_storage::<AtomIndex>().insert(atom, AtomIndex(index as u64)).unwrap();
+    }
+
+    common::step(&mut world, &mut dispatcher, 400);
+
+    let photons = world.read_resource::<MemoryPhotonSink>().photons();
+    assert!(!photons.is_empty(), "No photons were emitted.");
+    // Photons emitted by the still atom escape from the moving one, outside the region, and vice versa.
+    assert!(world.read_resource::<DroppedPhotons>().outside_region > 0);
+    assert!(photons.iter().all(|photon| photon.atom == Some(0)), "A photon was output from the atom outside the region.");
+    // The still atom re-emits at the natural line, whereas the moving atom would shift most photons by tens of MHz.
+    let center = AtomicTransition::rubidium().frequency;
+    let near = photons.iter().filter(|photon| (photon.frequency.unwrap() - center).abs() < 1.0e7).count();
+    assert!(near * 2 > photons.len(), "Only {} of {} photons are within 10 MHz of the line.", near, photons.len());
+}
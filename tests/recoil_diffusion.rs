@@ -0,0 +1,55 @@
+//! Validates the velocity spread of atoms against the random walk driven by photon recoil.
+
+extern crate atomecs as lib;
+
+use imaging_diffusion::config::{BeamConfig, SimulationConfig};
+use imaging_diffusion::photons::list::{NullPhotonOutputter, PhotonSink};
+use imaging_diffusion::simulation::{run_simulation, InputAtom, RunOptions};
+use imaging_diffusion::species::{Species, Transition};
+use lib::constant::{AMU, C, HBAR};
+use nalgebra::Vector3;
+
+const N_ATOMS: usize = 2000;
+
+/// Atoms initially at rest scatter photons from a single beam along x. The absorbed photons all push the atoms
+/// along x, but each emitted photon kicks the atom by ħk in a random direction, so that after N photons the
+/// variance of each perpendicular velocity component is N v_r² / 3, where v_r = ħk / m is the recoil velocity.
+///
+/// The number of photons differs between atoms, but the perpendicular velocity has zero mean, so the variance
+/// over all atoms is given by the mean number of photons.
+#[test]
+fn perpendicular_velocity_spread_matches_recoil_random_walk() {
+    let config = SimulationConfig {
+        beams: vec![BeamConfig { direction: Vector3::x(), ..BeamConfig::default() }],
+        transition: Transition::Rubidium,
+        timestep: 0.1e-6,
+        exposure: 10.0e-6,
+        progress_interval: 0,
+        ..SimulationConfig::default()
+    };
+    let atoms: Vec<InputAtom> = (0..N_ATOMS)
+        .map(|_| InputAtom { position: Vector3::zeros(), velocity: Vector3::zeros(), species: Species::Rubidium87 })
+        .collect();
+    let options = RunOptions { atoms: Some(&atoms), quiet: true, ..RunOptions::default() };
+
+    let result = run_simulation(&config, &options, NullPhotonOutputter::default()).expect("Simulation failed.");
+    assert_eq!(result.final_velocities.len(), N_ATOMS);
+
+    let mean_photons = result.output.photons_written() as f64 / N_ATOMS as f64;
+    assert!(mean_photons > 20.0, "Too few photons scattered ({} per atom) to test the random walk.", mean_photons);
+
+    let k = 2.0 * std::f64::consts::PI * Transition::Rubidium.atomic_transition().frequency / C;
+    let recoil_velocity = HBAR * k / (Species::Rubidium87.mass() * AMU);
+    let expected = (mean_photons / 3.0).sqrt() * recoil_velocity;
+
+    for axis in 1..3 {
+        let variance = result.final_velocities.iter().map(|v| v[axis] * v[axis]).sum::<f64>() / N_ATOMS as f64;
+        let rms = variance.sqrt();
+        // The statistical error on the rms of 2000 atoms is ~2%.
+        assert!(
+            (rms - expected).abs() < 0.1 * expected,
+            "Velocity rms along axis {} is {} m/s, expected {} m/s for {} photons per atom.",
+            axis, rms, expected, mean_photons
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Tests of the run parameters written as attributes of the output file.
+
+use std::path::PathBuf;
+
+use hdf5::types::VarLenUnicode;
+use imaging_diffusion::config::SimulationConfig;
+
+/// A uniquely named path in the temporary directory.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("imaging_diffusion_{}_{}.h5", name, std::process::id()))
+}
+
+/// Writes the attributes of `config` to a new file, and passes the file to `check`.
+fn with_attributes<F: Fn(&hdf5::File)>(name: &str, config: &SimulationConfig, check: F) {
+    let path = temp_path(name);
+    {
+        let file = hdf5::File::create(&path).expect("Could not create file.");
+        config.write_h5_attributes(&file).expect("Could not write attributes.");
+        check(&file);
+    }
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn the_seed_is_written_if_set() {
+    with_attributes("seeded_attributes", &SimulationConfig { seed: Some(1234), ..Default::default() }, |file| {
+        assert_eq!(file.attr("seed").unwrap().read_raw::<u64>().unwrap(), vec![1234]);
+    });
+    with_attributes("unseeded_attributes", &SimulationConfig::default(), |file| {
+        assert!(file.attr("seed").is_err(), "A seed was written for an unseeded run.");
+    });
+}
+
+#[test]
+fn parameters_which_change_the_photons_are_written() {
+    let config: SimulationConfig = toml::from_str(
+        r#"
+        photon_precision = "Single"
+        detection_efficiency = 0.25
+        region_of_interest = { min = [-1e-3, -2e-3, -3e-3], max = [1e-3, 2e-3, 3e-3] }
+        [reabsorption]
+        cell_size = 5e-6
+        cross_section = 1e-13
+        max_scatterings = 3
+        "#,
+    ).expect("Could not parse config.");
+    with_attributes("photon_attributes", &config, |file| {
+        let precision = file.attr("photon_precision").unwrap().read_raw::<VarLenUnicode>().unwrap();
+        assert_eq!(precision[0].as_str(), "Single");
+        assert_eq!(file.attr("detection_efficiency").unwrap().read_raw::<f64>().unwrap(), vec![0.25]);
+        assert_eq!(file.attr("region_of_interest_min").unwrap().read_raw::<f64>().unwrap(), vec![-1e-3, -2e-3, -3e-3]);
+        assert_eq!(file.attr("region_of_interest_max").unwrap().read_raw::<f64>().unwrap(), vec![1e-3, 2e-3, 3e-3]);
+        assert_eq!(file.attr("reabsorption_cell_size").unwrap().read_raw::<f64>().unwrap(), vec![5e-6]);
+        assert_eq!(file.attr("reabsorption_cross_section").unwrap().read_raw::<f64>().unwrap(), vec![1e-13]);
+        assert_eq!(file.attr("reabsorption_max_scatterings").unwrap().read_raw::<u32>().unwrap(), vec![3]);
+    });
+}
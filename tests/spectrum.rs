@@ -0,0 +1,65 @@
+//! Tests of the emission spectrum accumulated by a [SpectrumHistogram].
+
+extern crate atomecs as lib;
+
+mod common;
+
+use std::sync::Arc;
+
+use imaging_diffusion::photons::list::{MemoryPhotonSink, PhotonBroadcast, PhotonBroadcastSystem, PhotonSink};
+use imaging_diffusion::photons::spectrum::SpectrumHistogram;
+use lib::atom::AtomicTransition;
+use lib::integrator::Timestep;
+use lib::laser_cooling::force::EmissionForceOption;
+use lib::laser_cooling::photons_scattered::ScatteringFluctuationsOption;
+use nalgebra::Vector3;
+
+#[test]
+fn frequencies_are_binned_about_the_center() {
+    let center = 3.8e14;
+    // Five bins of 1 MHz, covering center ± 2.5 MHz.
+    let spectrum = SpectrumHistogram::new(center, 5.0e6, 5);
+    assert_eq!(spectrum.bin_width, 1.0e6);
+    let frequencies = spectrum.frequencies();
+    assert_eq!(frequencies.len(), 5);
+    assert!((frequencies[2] - center).abs() < 1.0);
+
+    spectrum.count(center);
+    spectrum.count(center + 1.2e6);
+    spectrum.count(center - 2.4e6);
+    spectrum.count(center + 2.6e6);
+    spectrum.count(f64::NAN);
+
+    assert_eq!(spectrum.counts(), vec![1, 0, 1, 1, 0]);
+    assert_eq!(spectrum.outside(), 2);
+}
+
+#[test]
+fn broadcast_spectrum_counts_the_frequencies_of_the_registered_photons() {
+    let (mut world, mut dispatcher) = common::create_simulation(|builder| {
+        builder.add(PhotonBroadcastSystem::default(), "", &[]);
+    });
+    let photons = Arc::new(MemoryPhotonSink::default());
+    common::create_atom(&mut world, Vector3::zeros(), Vector3::new(0.0, 0.0, 1.0));
+    // A 200 MHz span covers the natural line and the Doppler shift of the atom.
+    let center = AtomicTransition::rubidium().frequency;
+    let spectrum = Arc::new(SpectrumHistogram::new(center, 2.0e8, 200));
+    world.insert(PhotonBroadcast::new().with_sink(photons.clone()).with_sink(spectrum.clone()));
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(EmissionForceOption::Off);
+    world.insert(Timestep { delta: 1.0e-6 });
+    common::create_beam(&mut world, Vector3::x(), 0.0, 1);
+
+    common::step(&mut world, &mut dispatcher, 5);
+
+    let photons = photons.photons();
+    assert!(!photons.is_empty(), "No photons were emitted.");
+    assert_eq!(spectrum.photons_written(), photons.len() as u64);
+    // The spectrum bins the frequencies of the photons written to the other sinks, rather than its own sample.
+    let expected = SpectrumHistogram::new(center, 2.0e8, 200);
+    for photon in photons.iter() {
+        expected.count(photon.frequency.expect("The photons should have a frequency."));
+    }
+    assert_eq!(spectrum.counts(), expected.counts());
+    assert_eq!(spectrum.outside(), expected.outside());
+}
@@ -0,0 +1,58 @@
+//! Tests that a magnetic field Zeeman-shifts the scattering of a polarized imaging beam.
+
+extern crate atomecs as lib;
+
+mod common;
+
+use lib::integrator::Timestep;
+use lib::laser_cooling::photons_scattered::{ActualPhotonsScatteredVector, ScatteringFluctuationsOption};
+use lib::magnetic::uniform::UniformMagneticField;
+use nalgebra::Vector3;
+use specs::prelude::*;
+
+/// Zeeman shift of the σ⁺ transition, in units of MHz/G.
+const ZEEMAN_SHIFT: f64 = 1.4;
+
+/// Number of photons scattered in a frame by a stationary rubidium atom, from a σ⁺ beam propagating along
+/// the magnetic field, for the given beam detuning (MHz) and field (G).
+fn photons_scattered(detuning: f64, field: f64) -> f64 {
+    let (mut world, mut dispatcher) = common::create_simulation(|_| {});
+    common::create_beam(&mut world, Vector3::z(), detuning, 1);
+    world
+        .create_entity()
+        .with(UniformMagneticField::gauss(Vector3::new(0.0, 0.0, field)))
+        .build();
+    let atom = common::create_atom(&mut world, Vector3::zeros(), Vector3::zeros());
+
+    // Without fluctuations, the actual number of photons scattered equals the expected number.
+    world.insert(ScatteringFluctuationsOption::Off);
+    world.insert(Timestep { delta: 1.0e-7 });
+    common::step(&mut world, &mut dispatcher, 2);
+
+    let scattered = world.read_storage::<ActualPhotonsScatteredVector>();
+    scattered.get(atom).expect("Atom has no scattered photons.").contents.iter().map(|a| a.scattered).sum()
+}
+
+#[test]
+fn field_shifts_resonant_beam_out_of_resonance() {
+    let zero_field = photons_scattered(0.0, 0.0);
+    let high_field = photons_scattered(0.0, 10.0);
+    assert!(zero_field > 0.0);
+    assert!(
+        high_field < 0.5 * zero_field,
+        "A 10 G field should shift a resonant beam out of resonance, but scattering only fell from {} to {}.",
+        zero_field, high_field
+    );
+}
+
+#[test]
+fn detuned_beam_is_brought_into_resonance_by_field() {
+    let detuning = 10.0 * ZEEMAN_SHIFT;
+    let zero_field = photons_scattered(detuning, 0.0);
+    let high_field = photons_scattered(detuning, 10.0);
+    assert!(
+        high_field > 2.0 * zero_field,
+        "A beam detuned by the Zeeman shift should scatter more in the field, but scattering only rose from {} to {}.",
+        zero_field, high_field
+    );
+}